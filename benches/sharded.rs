@@ -0,0 +1,102 @@
+//! Compares sequential `AccountManager` with `ShardedAccountManager`, run with `cargo bench`
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use rust_decimal::Decimal;
+use transaction_processor::{
+    AccountManager, ClientId, InputRecord, InputRecordType, OutputOrder, ShardedAccountManager,
+};
+
+const RECORDS: u32 = 2_000_000;
+const CLIENTS: u64 = 10_000;
+const RUNS: u32 = 3;
+
+fn generate_records() -> Vec<InputRecord> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next = move |modulo: u64| {
+        state = state
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (state >> 33) % modulo
+    };
+
+    // Last transaction of each client, disputes reference transactions of the same client
+    let mut last_transactions = vec![None; CLIENTS as usize];
+    (0..RECORDS)
+        .map(|transaction_id| {
+            let client_id = next(CLIENTS) as ClientId;
+            let last_transaction = &mut last_transactions[usize::from(client_id)];
+            let (r#type, transaction_id) = match (next(20), *last_transaction) {
+                (18, Some(last)) => (InputRecordType::Dispute, last),
+                (19, Some(last)) => (InputRecordType::Resolve, last),
+                (0..=11, _) => (InputRecordType::Deposit, transaction_id),
+                _ => (InputRecordType::Withdrawal, transaction_id),
+            };
+            if matches!(
+                r#type,
+                InputRecordType::Deposit | InputRecordType::Withdrawal
+            ) {
+                *last_transaction = Some(transaction_id);
+            }
+            let amount = matches!(
+                r#type,
+                InputRecordType::Deposit | InputRecordType::Withdrawal
+            )
+            .then(|| Decimal::new(next(100_000) as i64 + 1, 2));
+
+            InputRecord::new(r#type, client_id, transaction_id, amount)
+        })
+        .collect()
+}
+
+fn measure(name: &str, mut run: impl FnMut() -> AccountManager) -> (Duration, AccountManager) {
+    let mut best = Duration::MAX;
+    let mut result = None;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let account_manager = black_box(run());
+        best = best.min(start.elapsed());
+        result = Some(account_manager);
+    }
+
+    let records_per_second = f64::from(RECORDS) / best.as_secs_f64();
+    println!("{name:<16} {best:>10.2?} {records_per_second:>14.0} records/s");
+    (best, result.unwrap_or_default())
+}
+
+fn main() {
+    let records = generate_records();
+
+    let (sequential_time, sequential) = measure("sequential", || {
+        let mut account_manager = AccountManager::new();
+        for record in &records {
+            let _ = account_manager.process_record(record);
+        }
+        account_manager
+    });
+    let expected = sequential.gather_output_ordered(OutputOrder::Insertion);
+
+    for workers in [1, 2, 4, 8] {
+        let (time, account_manager) = measure(&format!("sharded x{workers}"), || {
+            let mut sharded = ShardedAccountManager::new(AccountManager::new(), workers).unwrap();
+            for record in &records {
+                sharded.process_record(record.clone(), ()).unwrap();
+            }
+            sharded.finish().unwrap().0
+        });
+
+        assert_eq!(
+            expected,
+            account_manager.gather_output_ordered(OutputOrder::Insertion),
+            "sharded output differs from sequential"
+        );
+        println!(
+            "{:<16} {:>10.2}x",
+            "  speedup",
+            sequential_time.as_secs_f64() / time.as_secs_f64()
+        );
+    }
+}
@@ -1,41 +1,190 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use rust_decimal::Decimal;
-use strum::Display;
+use serde::{Deserialize, Serialize};
+use strum::{Display, IntoStaticStr};
 use thiserror::Error;
 
-use crate::model::{ClientId, InputRecord, InputRecordType, OutputRecord, TransactionId};
-
-#[derive(Debug)]
+use crate::{
+    account_manager::dispute_policy::{
+        DisputePolicy, DisputeRules, DisputedFunds, MAX_DISPUTE_CYCLES,
+    },
+    credit::CreditLimits,
+    events::{Balances, Event, EventKind},
+    fees::{FeeSchedule, FeeTrigger},
+    model::{
+        ClientId, Currency, InputRecord, InputRecordType, OutputRecord, Timestamp, TransactionId,
+        TransactionRecord,
+    },
+    rates::ExchangeRates,
+};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Account {
     client_id: ClientId,
     transactions: HashMap<TransactionId, Transaction>,
 
+    /// Funds of records without currency
     available: Decimal,
     held: Decimal,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    currencies: BTreeMap<Currency, Funds>,
+    /// Fees charged by records, in order of charging
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    fees: Vec<FeeCharge>,
 
     locked: bool,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+struct Funds {
+    available: Decimal,
+    held: Decimal,
+}
+
+/// Fees aren't transactions, so they can't be disputed and disputes don't refund them
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct FeeCharge {
+    /// Transaction of record which charged the fee
+    transaction_id: TransactionId,
+    trigger: FeeTrigger,
+    amount: Decimal,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<Timestamp>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 struct Transaction {
     pub state: TransactionState,
     pub amount: Decimal,
     pub r#type: TransactionType,
+    /// States transaction went through before the current one, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previous_states: Vec<TransactionState>,
+    /// Set for administrative transactions, boxed since regular transactions are far more common
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin: Option<Box<AdminNote>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    /// Time current dispute cycle started at, if it's known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disputed_at: Option<Timestamp>,
+    /// Funds moved by the first dispute, every cycle moves and reverts the same ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disputed_funds: Option<DisputedFunds>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    /// Other client of a transfer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counterparty: Option<ClientId>,
+}
+
+impl Transaction {
+    /// Number of dispute cycles transaction went through, including the current one
+    fn dispute_cycles(&self) -> u8 {
+        let cycles = self
+            .previous_states
+            .iter()
+            .chain([&self.state])
+            .filter(|state| state.is_disputed())
+            .count();
+        match (cycles, self.state) {
+            // Snapshots from before state history was kept have resolved transactions without it
+            (0, TransactionState::Resolved) => 1,
+            _ => u8::try_from(cycles).unwrap_or(u8::MAX),
+        }
+    }
+
+    /// Funds moved by dispute of this transaction. Snapshots from before they were kept
+    /// don't have them, then they are computed with current policy.
+    fn disputed_funds(
+        &self,
+        available: Decimal,
+        held: Decimal,
+        dispute_policy: &dyn DisputePolicy,
+    ) -> Result<DisputedFunds, ProcessingError> {
+        match self.disputed_funds {
+            Some(funds) => Ok(funds),
+            None => DisputedFunds::of(self.r#type, self.amount, available, held, dispute_policy),
+        }
+    }
+}
+
+/// Who made an administrative transaction and why
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct AdminNote {
+    operator: String,
+    reason: Option<String>,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Display)]
+/// Effect of a record on account, computed without mutating the account
+#[derive(Clone, Debug)]
+pub(crate) struct AccountChange {
+    transaction_id: TransactionId,
+    transaction: TransactionChange,
+
+    /// Currency of funds below
+    currency: Option<Currency>,
+    available: Decimal,
+    held: Decimal,
+    /// Second leg of conversion, new available funds in target currency
+    credit: Option<(Currency, Decimal)>,
+    /// Fee charged by the record, it's already debited from `available`
+    fee: Option<FeeCharge>,
+
+    locked: bool,
+}
+
+#[derive(Clone, Debug)]
+enum TransactionChange {
+    Insert(Transaction),
+    /// New state along with time dispute cycle started at, if it's a dispute,
+    /// and funds the dispute moved
+    SetState(TransactionState, Option<Timestamp>, DisputedFunds),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Display, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
 pub enum TransactionState {
     Valid,
     Dispute,
+    /// Resolved transaction disputed again, second presentment
+    SecondDispute,
+    /// Third and final dispute cycle
+    Arbitration,
     Resolved,
     ChargedBack,
 }
 
-#[derive(Debug, Eq, PartialEq)]
-enum TransactionType {
+impl TransactionState {
+    /// Transaction is in one of dispute cycles, it can be resolved or charged back
+    pub fn is_disputed(self) -> bool {
+        matches!(
+            self,
+            Self::Dispute | Self::SecondDispute | Self::Arbitration
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Display, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum TransactionType {
     Deposit,
     Withdrawal,
+    Adjustment,
+    Freeze,
+    Unlock,
+    Conversion,
+    /// Transfer to other client
+    TransferOut,
+    /// Transfer from other client, its id belongs to the sending client
+    TransferIn,
+    /// Fee charged by other transaction, listed only in history
+    Fee,
 }
 
 impl Account {
@@ -46,15 +195,151 @@ impl Account {
 
             available: Decimal::ZERO,
             held: Decimal::ZERO,
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
 
             locked: false,
         }
     }
 
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    /// Ids of transactions created by records of this client, incoming transfers are left out
+    pub fn transaction_ids(&self) -> impl Iterator<Item = TransactionId> + '_ {
+        self.transactions
+            .iter()
+            .filter(|(_, transaction)| transaction.r#type != TransactionType::TransferIn)
+            .map(|(&transaction_id, _)| transaction_id)
+    }
+
+    /// Disputes with known start time, as `(disputed_at, transaction_id)`
+    pub(crate) fn open_disputes(&self) -> impl Iterator<Item = (Timestamp, TransactionId)> + '_ {
+        self.transactions
+            .iter()
+            .filter(|(_, transaction)| transaction.state.is_disputed())
+            .filter_map(|(&transaction_id, transaction)| {
+                transaction
+                    .disputed_at
+                    .map(|disputed_at| (disputed_at, transaction_id))
+            })
+    }
+
+    /// Start time of dispute of given transaction, if it's disputed
+    pub(crate) fn disputed_at(&self, transaction_id: TransactionId) -> Option<Timestamp> {
+        self.transactions
+            .get(&transaction_id)
+            .filter(|transaction| transaction.state.is_disputed())
+            .and_then(|transaction| transaction.disputed_at)
+    }
+
+    /// Funds moved by dispute of given transaction, if it was ever disputed
+    pub(crate) fn disputed_funds(&self, transaction_id: TransactionId) -> Option<DisputedFunds> {
+        self.transactions
+            .get(&transaction_id)
+            .and_then(|transaction| transaction.disputed_funds)
+    }
+
+    /// Processes record with default [`DisputeRules`]. Transfers are rejected, they need account
+    /// of the receiving client too, so only [`crate::AccountManager`] can process them.
+    /// Operators of administrative records are checked by [`crate::AccountManager`] as well.
     pub fn process_record(&mut self, record: &InputRecord) -> Result<(), ProcessingError> {
-        if self.locked {
+        if record.r#type == InputRecordType::Transfer {
+            let destination = record
+                .to_client_id
+                .ok_or(ProcessingError::DestinationMissing)?;
+            return Err(ProcessingError::DestinationAccountMissing(destination));
+        }
+        let change = self.plan_record(record, &DisputeRules::default(), None, None, None)?;
+        self.apply_change(change);
+
+        Ok(())
+    }
+
+    /// Computes effect of record on account without mutating it
+    pub(crate) fn plan_record(
+        &self,
+        record: &InputRecord,
+        dispute_rules: &DisputeRules,
+        exchange_rates: Option<&ExchangeRates>,
+        fee_schedule: Option<&FeeSchedule>,
+        credit_limits: Option<&CreditLimits>,
+    ) -> Result<AccountChange, ProcessingError> {
+        let credit_limit = |currency| {
+            credit_limits.map_or(Decimal::ZERO, |credit_limits| {
+                credit_limits.limit(self.client_id, currency)
+            })
+        };
+        let change = self.plan_transaction(record, dispute_rules, exchange_rates, credit_limit)?;
+        match fee_schedule {
+            Some(fee_schedule) => self.charge_fee(record, change, fee_schedule, credit_limit),
+            None => Ok(change),
+        }
+    }
+
+    /// Debits fee of record on top of its change, withdrawal with its fee has to fit
+    /// in available funds.
+    fn charge_fee(
+        &self,
+        record: &InputRecord,
+        mut change: AccountChange,
+        fee_schedule: &FeeSchedule,
+        credit_limit: impl Fn(Option<Currency>) -> Decimal,
+    ) -> Result<AccountChange, ProcessingError> {
+        let Some(trigger) = FeeTrigger::of(record.r#type) else {
+            return Ok(change);
+        };
+        let amount = match trigger {
+            FeeTrigger::Chargeback => self
+                .transactions
+                .get(&record.transaction_id)
+                .map_or(Decimal::ZERO, |transaction| transaction.amount.abs()),
+            FeeTrigger::Withdrawal => record.amount.unwrap_or_default(),
+        };
+        let fee = fee_schedule.fee(trigger, amount)?;
+        if fee.is_zero() {
+            return Ok(change);
+        }
+
+        let new_available = change
+            .available
+            .checked_sub(fee)
+            .ok_or(ProcessingError::DecimalOverflow)?;
+        let credit_limit = credit_limit(change.currency);
+        if trigger == FeeTrigger::Withdrawal && new_available < -credit_limit {
+            return Err(ProcessingError::WithdrawalNotEnoughMoneyAvailable(
+                self.funds(change.currency).available + credit_limit,
+                amount + fee,
+            ));
+        }
+
+        change.available = new_available;
+        change.fee = Some(FeeCharge {
+            transaction_id: record.transaction_id,
+            trigger,
+            amount: fee,
+            currency: change.currency,
+            timestamp: record.timestamp,
+        });
+        Ok(change)
+    }
+
+    fn plan_transaction(
+        &self,
+        record: &InputRecord,
+        dispute_rules: &DisputeRules,
+        exchange_rates: Option<&ExchangeRates>,
+        credit_limit: impl Fn(Option<Currency>) -> Decimal,
+    ) -> Result<AccountChange, ProcessingError> {
+        // Administrative records are the only way to remediate locked account
+        if self.locked && !record.r#type.is_admin() {
             return Err(ProcessingError::AccountIsLocked);
         }
+        let currency = self.record_currency(record)?;
+        let Funds { available, held } = self.funds(currency);
+        // Debits can take available funds below zero, down to minus the limit
+        let credit_limit = credit_limit(currency);
 
         match record.r#type {
             InputRecordType::Deposit => {
@@ -66,16 +351,31 @@ impl Account {
 
                 let amount = record.amount.ok_or(ProcessingError::AmountMissing)?;
 
-                self.transactions.insert(
-                    record.transaction_id,
-                    Transaction {
+                let new_available = available
+                    .checked_add(amount)
+                    .ok_or(ProcessingError::DecimalOverflow)?;
+
+                Ok(AccountChange {
+                    transaction_id: record.transaction_id,
+                    transaction: TransactionChange::Insert(Transaction {
                         state: TransactionState::Valid,
                         amount,
                         r#type: TransactionType::Deposit,
-                    },
-                );
-
-                self.available += amount;
+                        previous_states: Vec::new(),
+                        admin: None,
+                        timestamp: record.timestamp,
+                        disputed_at: None,
+                        disputed_funds: None,
+                        currency,
+                        counterparty: None,
+                    }),
+                    currency,
+                    available: new_available,
+                    held,
+                    credit: None,
+                    fee: None,
+                    locked: self.locked,
+                })
             }
             InputRecordType::Withdrawal => {
                 if self.transactions.contains_key(&record.transaction_id) {
@@ -86,175 +386,808 @@ impl Account {
 
                 let amount = record.amount.ok_or(ProcessingError::AmountMissing)?;
 
-                let new_available = self
-                    .available
+                let new_available = available
                     .checked_sub(amount)
                     .ok_or(ProcessingError::DecimalOverflow)?;
-                if new_available < Decimal::ZERO {
+                if new_available < -credit_limit {
                     return Err(ProcessingError::WithdrawalNotEnoughMoneyAvailable(
-                        self.available,
+                        available + credit_limit,
                         amount,
                     ));
                 }
 
-                self.transactions.insert(
-                    record.transaction_id,
-                    Transaction {
+                Ok(AccountChange {
+                    transaction_id: record.transaction_id,
+                    transaction: TransactionChange::Insert(Transaction {
                         state: TransactionState::Valid,
                         amount: -amount,
                         r#type: TransactionType::Withdrawal,
-                    },
-                );
+                        previous_states: Vec::new(),
+                        admin: None,
+                        timestamp: record.timestamp,
+                        disputed_at: None,
+                        disputed_funds: None,
+                        currency,
+                        counterparty: None,
+                    }),
+                    currency,
+                    available: new_available,
+                    held,
+                    credit: None,
+                    fee: None,
+                    locked: self.locked,
+                })
+            }
+            InputRecordType::Convert => {
+                if self.transactions.contains_key(&record.transaction_id) {
+                    return Err(ProcessingError::TransactionAlreadyExists(
+                        record.transaction_id,
+                    ));
+                }
+
+                let amount = record.amount.ok_or(ProcessingError::AmountMissing)?;
+                let (Some(from), Some(to)) = (currency, record.to_currency) else {
+                    return Err(ProcessingError::CurrencyMissing);
+                };
+                if from == to {
+                    return Err(ProcessingError::ConversionToSameCurrency(to));
+                }
+                let converted = exchange_rates
+                    .ok_or(ProcessingError::RateMissing(from))?
+                    .convert(amount, from, to)?;
+
+                let new_available = available
+                    .checked_sub(amount)
+                    .ok_or(ProcessingError::DecimalOverflow)?;
+                if new_available < -credit_limit {
+                    return Err(ProcessingError::WithdrawalNotEnoughMoneyAvailable(
+                        available + credit_limit,
+                        amount,
+                    ));
+                }
+                let credited = self
+                    .funds(Some(to))
+                    .available
+                    .checked_add(converted)
+                    .ok_or(ProcessingError::DecimalOverflow)?;
 
-                self.available = new_available;
+                // Both legs are a single change, so they are applied together or not at all
+                Ok(AccountChange {
+                    transaction_id: record.transaction_id,
+                    transaction: TransactionChange::Insert(Transaction {
+                        state: TransactionState::Valid,
+                        amount: -amount,
+                        r#type: TransactionType::Conversion,
+                        previous_states: Vec::new(),
+                        admin: None,
+                        timestamp: record.timestamp,
+                        disputed_at: None,
+                        disputed_funds: None,
+                        currency,
+                        counterparty: None,
+                    }),
+                    currency,
+                    available: new_available,
+                    held,
+                    credit: Some((to, credited)),
+                    fee: None,
+                    locked: self.locked,
+                })
             }
-            InputRecordType::Dispute => {
-                let transaction = self
-                    .transactions
-                    .get_mut(&record.transaction_id)
-                    .ok_or(ProcessingError::TransactionMissing(record.transaction_id))?;
-                check_if_state_eq(transaction, TransactionState::Valid)?;
+            InputRecordType::Transfer => {
+                if self.transactions.contains_key(&record.transaction_id) {
+                    return Err(ProcessingError::TransactionAlreadyExists(
+                        record.transaction_id,
+                    ));
+                }
 
-                let (new_available, new_held) =
-                    calculate_transaction_dispute(transaction, self.available, self.held)?;
-                self.available = new_available;
-                self.held = new_held;
-                transaction.state = TransactionState::Dispute;
+                let destination = record
+                    .to_client_id
+                    .ok_or(ProcessingError::DestinationMissing)?;
+                if destination == self.client_id {
+                    return Err(ProcessingError::TransferToSameClient);
+                }
+                let amount = record.amount.ok_or(ProcessingError::AmountMissing)?;
+
+                let new_available = available
+                    .checked_sub(amount)
+                    .ok_or(ProcessingError::DecimalOverflow)?;
+                if new_available < -credit_limit {
+                    return Err(ProcessingError::WithdrawalNotEnoughMoneyAvailable(
+                        available + credit_limit,
+                        amount,
+                    ));
+                }
+
+                // Credit of the destination account is planned by `plan_transfer_in`
+                Ok(AccountChange {
+                    transaction_id: record.transaction_id,
+                    transaction: TransactionChange::Insert(Transaction {
+                        state: TransactionState::Valid,
+                        amount: -amount,
+                        r#type: TransactionType::TransferOut,
+                        previous_states: Vec::new(),
+                        admin: None,
+                        timestamp: record.timestamp,
+                        disputed_at: None,
+                        disputed_funds: None,
+                        currency,
+                        counterparty: Some(destination),
+                    }),
+                    currency,
+                    available: new_available,
+                    held,
+                    credit: None,
+                    fee: None,
+                    locked: self.locked,
+                })
+            }
+            InputRecordType::Adjust => {
+                if self.transactions.contains_key(&record.transaction_id) {
+                    return Err(ProcessingError::TransactionAlreadyExists(
+                        record.transaction_id,
+                    ));
+                }
+
+                let amount = record.amount.ok_or(ProcessingError::AmountMissing)?;
+
+                let new_available = available
+                    .checked_add(amount)
+                    .ok_or(ProcessingError::DecimalOverflow)?;
+                if amount < Decimal::ZERO && new_available < -credit_limit {
+                    return Err(ProcessingError::WithdrawalNotEnoughMoneyAvailable(
+                        available + credit_limit,
+                        -amount,
+                    ));
+                }
+
+                Ok(AccountChange {
+                    transaction_id: record.transaction_id,
+                    transaction: TransactionChange::Insert(admin_transaction(
+                        record,
+                        amount,
+                        TransactionType::Adjustment,
+                        currency,
+                    )),
+                    currency,
+                    available: new_available,
+                    held,
+                    credit: None,
+                    fee: None,
+                    locked: self.locked,
+                })
+            }
+            InputRecordType::Freeze | InputRecordType::Unlock => {
+                if self.transactions.contains_key(&record.transaction_id) {
+                    return Err(ProcessingError::TransactionAlreadyExists(
+                        record.transaction_id,
+                    ));
+                }
+
+                let (r#type, locked) = match record.r#type {
+                    InputRecordType::Freeze if self.locked => {
+                        return Err(ProcessingError::AccountIsLocked)
+                    }
+                    InputRecordType::Unlock if !self.locked => {
+                        return Err(ProcessingError::AccountNotLocked)
+                    }
+                    InputRecordType::Freeze => (TransactionType::Freeze, true),
+                    _ => (TransactionType::Unlock, false),
+                };
+
+                Ok(AccountChange {
+                    transaction_id: record.transaction_id,
+                    transaction: TransactionChange::Insert(admin_transaction(
+                        record,
+                        Decimal::ZERO,
+                        r#type,
+                        currency,
+                    )),
+                    currency,
+                    available,
+                    held,
+                    credit: None,
+                    fee: None,
+                    locked,
+                })
+            }
+            InputRecordType::Dispute => {
+                let transaction = self.get_disputable_transaction(record.transaction_id)?;
+                if exceeds(dispute_rules.dispute_window, transaction.timestamp, record) {
+                    return Err(ProcessingError::DisputeWindowExpired(record.transaction_id));
+                }
+                let funds =
+                    transaction.disputed_funds(available, held, dispute_rules.policy.as_ref())?;
+
+                let (state, (new_available, new_held)) = match transaction.state {
+                    TransactionState::Valid => {
+                        (TransactionState::Dispute, funds.dispute(available, held)?)
+                    }
+                    // Reverted transaction is contested, so it goes through another dispute cycle
+                    TransactionState::Resolved => {
+                        let cycles = transaction.dispute_cycles();
+                        if cycles >= dispute_rules.max_cycles.min(MAX_DISPUTE_CYCLES) {
+                            return Err(ProcessingError::DisputeCycleLimitReached(cycles));
+                        }
+                        let state = match cycles {
+                            1 => TransactionState::SecondDispute,
+                            _ => TransactionState::Arbitration,
+                        };
+                        (state, funds.redispute(transaction.amount, available, held)?)
+                    }
+                    state => {
+                        return Err(ProcessingError::TransactionWrongState(
+                            TransactionState::Valid,
+                            state,
+                        ))
+                    }
+                };
+                Ok(AccountChange {
+                    transaction_id: record.transaction_id,
+                    transaction: TransactionChange::SetState(state, record.timestamp, funds),
+                    currency,
+                    available: new_available,
+                    held: new_held,
+                    credit: None,
+                    fee: None,
+                    locked: self.locked,
+                })
             }
             InputRecordType::Resolve => {
-                let transaction = self
-                    .transactions
-                    .get_mut(&record.transaction_id)
-                    .ok_or(ProcessingError::TransactionMissing(record.transaction_id))?;
-                check_if_state_eq(transaction, TransactionState::Dispute)?;
+                let transaction = self.get_disputable_transaction(record.transaction_id)?;
+                // Late resolves go through, otherwise overdue dispute would hold funds forever
+                check_if_disputed(transaction)?;
 
+                let funds =
+                    transaction.disputed_funds(available, held, dispute_rules.policy.as_ref())?;
                 let (new_available, new_held) =
-                    calculate_transaction_revert(transaction, self.available, self.held)?;
-                self.available = new_available;
-                self.held = new_held;
-                transaction.state = TransactionState::Resolved;
+                    funds.revert(transaction.amount, available, held)?;
+                Ok(AccountChange {
+                    transaction_id: record.transaction_id,
+                    transaction: TransactionChange::SetState(
+                        TransactionState::Resolved,
+                        None,
+                        funds,
+                    ),
+                    currency,
+                    available: new_available,
+                    held: new_held,
+                    credit: None,
+                    fee: None,
+                    locked: self.locked,
+                })
             }
             InputRecordType::Chargeback => {
-                let transaction = self
-                    .transactions
-                    .get_mut(&record.transaction_id)
-                    .ok_or(ProcessingError::TransactionMissing(record.transaction_id))?;
-                check_if_state_eq(transaction, TransactionState::Dispute)?;
+                let transaction = self.get_disputable_transaction(record.transaction_id)?;
+                check_if_disputed(transaction)?;
+                if exceeds(
+                    dispute_rules.resolution_deadline,
+                    transaction.disputed_at,
+                    record,
+                ) {
+                    return Err(ProcessingError::ResolutionDeadlineExceeded(
+                        record.transaction_id,
+                    ));
+                }
 
+                let funds =
+                    transaction.disputed_funds(available, held, dispute_rules.policy.as_ref())?;
                 let (new_available, new_held) =
-                    calculate_transaction_revert(transaction, self.available, self.held)?;
-                self.available = new_available;
-                self.held = new_held;
-                self.locked = true;
-                transaction.state = TransactionState::ChargedBack;
+                    funds.revert(transaction.amount, available, held)?;
+                Ok(AccountChange {
+                    transaction_id: record.transaction_id,
+                    transaction: TransactionChange::SetState(
+                        TransactionState::ChargedBack,
+                        None,
+                        funds,
+                    ),
+                    currency,
+                    available: new_available,
+                    held: new_held,
+                    credit: None,
+                    fee: None,
+                    locked: true,
+                })
             }
         }
+    }
 
-        Ok(())
+    /// Computes credit of this account by transfer `record` of other client
+    pub(crate) fn plan_transfer_in(
+        &self,
+        record: &InputRecord,
+    ) -> Result<AccountChange, ProcessingError> {
+        if self.locked {
+            return Err(ProcessingError::DestinationAccountLocked(self.client_id));
+        }
+        if self.transactions.contains_key(&record.transaction_id) {
+            return Err(ProcessingError::TransactionAlreadyExists(
+                record.transaction_id,
+            ));
+        }
+
+        let amount = record.amount.ok_or(ProcessingError::AmountMissing)?;
+        let Funds { available, held } = self.funds(record.currency);
+        let new_available = available
+            .checked_add(amount)
+            .ok_or(ProcessingError::DecimalOverflow)?;
+
+        Ok(AccountChange {
+            transaction_id: record.transaction_id,
+            transaction: TransactionChange::Insert(Transaction {
+                state: TransactionState::Valid,
+                amount,
+                r#type: TransactionType::TransferIn,
+                previous_states: Vec::new(),
+                admin: None,
+                timestamp: record.timestamp,
+                disputed_at: None,
+                disputed_funds: None,
+                currency: record.currency,
+                counterparty: Some(record.client_id),
+            }),
+            currency: record.currency,
+            available: new_available,
+            held,
+            credit: None,
+            fee: None,
+            locked: self.locked,
+        })
     }
 
-    pub fn to_output(&self) -> OutputRecord {
-        OutputRecord {
+    /// Applies change computed by [`Account::plan_record`] of this account,
+    /// no other change can be applied in between
+    pub(crate) fn apply_change(&mut self, change: AccountChange) {
+        match change.transaction {
+            TransactionChange::Insert(transaction) => {
+                self.transactions.insert(change.transaction_id, transaction);
+            }
+            TransactionChange::SetState(state, disputed_at, funds) => {
+                if let Some(transaction) = self.transactions.get_mut(&change.transaction_id) {
+                    let previous_state = std::mem::replace(&mut transaction.state, state);
+                    transaction.previous_states.push(previous_state);
+                    transaction.disputed_at = disputed_at;
+                    transaction.disputed_funds = Some(funds);
+                }
+            }
+        }
+
+        let funds = Funds {
+            available: change.available,
+            held: change.held,
+        };
+        match change.currency {
+            None => {
+                self.available = funds.available;
+                self.held = funds.held;
+            }
+            Some(currency) => {
+                self.currencies.insert(currency, funds);
+            }
+        }
+        if let Some((currency, available)) = change.credit {
+            self.currencies.entry(currency).or_default().available = available;
+        }
+        self.fees.extend(change.fee);
+        self.locked = change.locked;
+    }
+
+    /// Currency of funds record works on, disputes can only use currency of disputed transaction
+    fn record_currency(&self, record: &InputRecord) -> Result<Option<Currency>, ProcessingError> {
+        match record.r#type {
+            InputRecordType::Dispute | InputRecordType::Resolve | InputRecordType::Chargeback => {
+                match self.transactions.get(&record.transaction_id) {
+                    Some(transaction)
+                        if record
+                            .currency
+                            .is_some_and(|currency| transaction.currency != Some(currency)) =>
+                    {
+                        Err(ProcessingError::CurrencyMismatch(record.transaction_id))
+                    }
+                    Some(transaction) => Ok(transaction.currency),
+                    None => Ok(record.currency),
+                }
+            }
+            // Lock status is shared by all currencies
+            InputRecordType::Freeze | InputRecordType::Unlock => Ok(None),
+            _ => Ok(record.currency),
+        }
+    }
+
+    fn funds(&self, currency: Option<Currency>) -> Funds {
+        match currency {
+            None => Funds {
+                available: self.available,
+                held: self.held,
+            },
+            Some(currency) => self.currencies.get(&currency).copied().unwrap_or_default(),
+        }
+    }
+
+    /// Events describing change computed by [`Account::plan_record`] of this account
+    pub(crate) fn change_events(&self, record: &InputRecord, change: &AccountChange) -> Vec<Event> {
+        let kind = match record.r#type {
+            InputRecordType::Deposit => EventKind::DepositApplied,
+            InputRecordType::Withdrawal => EventKind::WithdrawalApplied,
+            InputRecordType::Dispute => EventKind::FundsHeld,
+            InputRecordType::Resolve => EventKind::FundsReleased,
+            InputRecordType::Chargeback => EventKind::ChargebackApplied,
+            InputRecordType::Adjust => EventKind::AdjustmentApplied,
+            InputRecordType::Freeze => EventKind::AccountFrozen,
+            InputRecordType::Unlock => EventKind::AccountUnlocked,
+            InputRecordType::Convert => EventKind::ConversionDebited,
+            InputRecordType::Transfer if record.client_id == self.client_id => {
+                EventKind::TransferSent
+            }
+            InputRecordType::Transfer => EventKind::TransferReceived,
+        };
+        let amount = match &change.transaction {
+            TransactionChange::Insert(transaction) => match transaction.r#type {
+                TransactionType::Withdrawal
+                | TransactionType::Conversion
+                | TransactionType::TransferOut => -transaction.amount,
+                _ => transaction.amount,
+            },
+            TransactionChange::SetState(..) => self
+                .transactions
+                .get(&change.transaction_id)
+                .map_or(Decimal::ZERO, |transaction| transaction.amount.abs()),
+        };
+
+        let before = self.currency_balances(change.currency);
+        // Locking caused by other records and fees are reported as separate events
+        let fee = change.fee.as_ref().map_or(Decimal::ZERO, |fee| fee.amount);
+        let mut after = Balances {
+            available: change.available + fee,
+            held: change.held,
+            locked: match kind {
+                EventKind::AccountFrozen | EventKind::AccountUnlocked => change.locked,
+                _ => self.locked,
+            },
+        };
+        let mut events = vec![Event {
+            kind,
             client_id: self.client_id,
-            available: self.available,
-            held: self.held,
-            total: self.available + self.held,
+            transaction_id: change.transaction_id,
+            amount,
+            before,
+            after,
+            currency: change.currency,
+            operator: record.operator.clone().filter(|_| record.r#type.is_admin()),
+            reason: record.reason.clone().filter(|_| record.r#type.is_admin()),
+        }];
+        if !fee.is_zero() {
+            let charged = Balances {
+                available: change.available,
+                ..after
+            };
+            events.push(Event {
+                kind: EventKind::FeeCharged,
+                client_id: self.client_id,
+                transaction_id: change.transaction_id,
+                amount: fee,
+                before: after,
+                after: charged,
+                currency: change.currency,
+                operator: None,
+                reason: None,
+            });
+            after = charged;
+        }
+        if change.locked && !after.locked {
+            events.push(Event {
+                kind: EventKind::AccountLocked,
+                client_id: self.client_id,
+                transaction_id: change.transaction_id,
+                amount: Decimal::ZERO,
+                before: after,
+                after: Balances {
+                    locked: true,
+                    ..after
+                },
+                currency: change.currency,
+                operator: None,
+                reason: None,
+            });
+        }
+        if let Some((currency, available)) = change.credit {
+            let before = self.currency_balances(Some(currency));
+            events.push(Event {
+                kind: EventKind::ConversionCredited,
+                client_id: self.client_id,
+                transaction_id: change.transaction_id,
+                amount: available - before.available,
+                before,
+                after: Balances {
+                    available,
+                    ..before
+                },
+                currency: Some(currency),
+                operator: None,
+                reason: None,
+            });
+        }
+
+        events
+    }
+
+    /// Balances of records without currency
+    pub fn balances(&self) -> Balances {
+        self.currency_balances(None)
+    }
+
+    pub fn currency_balances(&self, currency: Option<Currency>) -> Balances {
+        let Funds { available, held } = self.funds(currency);
+        Balances {
+            available,
+            held,
             locked: self.locked,
         }
     }
+
+    /// One row per currency, ordered by currency. Row without currency is skipped if account
+    /// only has funds in other currencies.
+    pub fn to_outputs(&self) -> Vec<OutputRecord> {
+        let unit_less =
+            self.currencies.is_empty() || !self.available.is_zero() || !self.held.is_zero();
+        unit_less
+            .then_some(None)
+            .into_iter()
+            .chain(self.currencies.keys().copied().map(Some))
+            .map(|currency| {
+                let Funds { available, held } = self.funds(currency);
+                OutputRecord {
+                    client_id: self.client_id,
+                    currency,
+                    available,
+                    held,
+                    total: available + held,
+                    locked: self.locked,
+                    credit_limit: None,
+                    available_to_spend: None,
+                }
+            })
+            .collect()
+    }
+
+    /// Single row with funds of all currencies valued in base currency of `exchange_rates`
+    pub fn valuation(
+        &self,
+        exchange_rates: &ExchangeRates,
+    ) -> Result<OutputRecord, ProcessingError> {
+        let mut available = Decimal::ZERO;
+        let mut held = Decimal::ZERO;
+        for output in self.to_outputs() {
+            available = available
+                .checked_add(exchange_rates.value(output.available, output.currency)?)
+                .ok_or(ProcessingError::DecimalOverflow)?;
+            held = held
+                .checked_add(exchange_rates.value(output.held, output.currency)?)
+                .ok_or(ProcessingError::DecimalOverflow)?;
+        }
+
+        Ok(OutputRecord {
+            client_id: self.client_id,
+            currency: Some(exchange_rates.base()),
+            available: available.normalize(),
+            held: held.normalize(),
+            total: (available + held).normalize(),
+            locked: self.locked,
+            credit_limit: None,
+            available_to_spend: None,
+        })
+    }
+
+    /// Fees charged from this account, as `(trigger, currency, amount)`
+    pub(crate) fn fee_charges(
+        &self,
+    ) -> impl Iterator<Item = (FeeTrigger, Option<Currency>, Decimal)> + '_ {
+        self.fees
+            .iter()
+            .map(|fee| (fee.trigger, fee.currency, fee.amount))
+    }
+
+    /// Transactions of this account and fees they charged, ordered by id
+    pub fn transactions(&self) -> Vec<TransactionRecord> {
+        let mut transactions: Vec<_> = self
+            .transactions
+            .iter()
+            .map(|(&transaction_id, transaction)| TransactionRecord {
+                transaction_id,
+                r#type: transaction.r#type,
+                amount: match transaction.r#type {
+                    TransactionType::Withdrawal
+                    | TransactionType::Conversion
+                    | TransactionType::TransferOut => -transaction.amount,
+                    _ => transaction.amount,
+                },
+                state: transaction.state,
+                transitions: transaction
+                    .previous_states
+                    .iter()
+                    .copied()
+                    .chain([transaction.state])
+                    .collect(),
+                operator: transaction
+                    .admin
+                    .as_ref()
+                    .map(|admin| admin.operator.clone()),
+                reason: transaction
+                    .admin
+                    .as_ref()
+                    .and_then(|admin| admin.reason.clone()),
+                timestamp: transaction.timestamp,
+                currency: transaction.currency,
+                counterparty: transaction.counterparty,
+            })
+            .chain(self.fees.iter().map(|fee| TransactionRecord {
+                transaction_id: fee.transaction_id,
+                r#type: TransactionType::Fee,
+                amount: fee.amount,
+                state: TransactionState::Valid,
+                transitions: vec![TransactionState::Valid],
+                operator: None,
+                reason: None,
+                timestamp: fee.timestamp,
+                currency: fee.currency,
+                counterparty: None,
+            }))
+            .collect();
+        // Sort is stable, so fees follow the transaction which charged them
+        transactions.sort_by_key(|transaction| transaction.transaction_id);
+        transactions
+    }
+
+    fn get_disputable_transaction(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<&Transaction, ProcessingError> {
+        let transaction = self
+            .transactions
+            .get(&transaction_id)
+            .ok_or(ProcessingError::TransactionMissing(transaction_id))?;
+        match transaction.r#type {
+            TransactionType::Deposit | TransactionType::Withdrawal => Ok(transaction),
+            r#type => Err(ProcessingError::TransactionNotDisputable(r#type)),
+        }
+    }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Error, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
 pub enum ProcessingError {
     #[error("Account is locked")]
     AccountIsLocked,
+    #[error("Account is not locked")]
+    AccountNotLocked,
     #[error("Amount missing")]
     AmountMissing,
+    #[error("Amount has to be positive: `{0}`")]
+    AmountNotPositive(Decimal),
+    #[error("Amount has too many decimal places: `{0}`")]
+    AmountTooPrecise(Decimal),
+    #[error("Amount not expected for this record type: `{0}`")]
+    AmountUnexpected(Decimal),
+    #[error("Amount can't be zero")]
+    AmountZero,
+    #[error("Client mismatch, transaction: `{0}` belongs to client: `{1}`, requested by: `{2}`")]
+    ClientMismatch(TransactionId, ClientId, ClientId),
+    #[error("Currency of record doesn't match currency of transaction: `{0}`")]
+    CurrencyMismatch(TransactionId),
+    #[error("Conversion needs different source and target currency, got: `{0}`")]
+    ConversionToSameCurrency(Currency),
+    #[error("Currency missing")]
+    CurrencyMissing,
     #[error("Decimal overflow")]
     DecimalOverflow,
+    #[error("Destination account is locked: `{0}`")]
+    DestinationAccountLocked(ClientId),
+    #[error("Destination account missing: `{0}`")]
+    DestinationAccountMissing(ClientId),
+    #[error("Destination client missing")]
+    DestinationMissing,
+    #[error("Dispute cycle limit reached, transaction was disputed: `{0}` times")]
+    DisputeCycleLimitReached(u8),
+    #[error("Dispute window expired for transaction: `{0}`")]
+    DisputeWindowExpired(TransactionId),
+    #[error("Operator missing")]
+    OperatorMissing,
+    #[error("Operator not authorized: `{0}`")]
+    OperatorNotAuthorized(String),
+    #[error("Exchange rate missing for currency: `{0}`")]
+    RateMissing(Currency),
+    #[error("Reason missing")]
+    ReasonMissing,
+    #[error("Resolution deadline exceeded for dispute of transaction: `{0}`")]
+    ResolutionDeadlineExceeded(TransactionId),
 
     #[error("Transaction already exists: `{0}`")]
     TransactionAlreadyExists(TransactionId),
     #[error("Transaction missing: `{0}`")]
     TransactionMissing(TransactionId),
+    #[error("Transaction of type `{0}` can't be disputed")]
+    TransactionNotDisputable(TransactionType),
     #[error("Transaction wrong state, expected: `{0}`, actual: `{0}`")]
     TransactionWrongState(TransactionState, TransactionState),
+    #[error("Transfer needs different source and destination client")]
+    TransferToSameClient,
 
     #[error("Withdrawal: not enough money available, available: `{0}`, requested: `{1}`")]
     WithdrawalNotEnoughMoneyAvailable(Decimal, Decimal),
 }
 
-fn calculate_transaction_dispute(
-    transaction: &Transaction,
-    available: Decimal,
-    held: Decimal,
-) -> Result<(Decimal, Decimal), ProcessingError> {
-    match transaction.r#type {
-        TransactionType::Deposit => {
-            let new_available = available
-                .checked_sub(transaction.amount)
-                .ok_or(ProcessingError::DecimalOverflow)?;
-            let new_held = held
-                .checked_add(transaction.amount)
-                .ok_or(ProcessingError::DecimalOverflow)?;
-            Ok((new_available, new_held))
-        }
-        TransactionType::Withdrawal => {
-            let new_held = held
-                .checked_add(-transaction.amount)
-                .ok_or(ProcessingError::DecimalOverflow)?;
-            Ok((available, new_held))
-        }
+impl ProcessingError {
+    /// Stable, machine-readable name of error, e.g. `account_is_locked`
+    pub fn code(&self) -> &'static str {
+        self.into()
     }
 }
 
-fn calculate_transaction_revert(
-    transaction: &Transaction,
-    available: Decimal,
-    held: Decimal,
-) -> Result<(Decimal, Decimal), ProcessingError> {
-    match transaction.r#type {
-        TransactionType::Deposit => {
-            let new_held = held
-                .checked_sub(transaction.amount)
-                .ok_or(ProcessingError::DecimalOverflow)?;
-            Ok((available, new_held))
-        }
-        TransactionType::Withdrawal => {
-            let new_held = held
-                .checked_sub(-transaction.amount)
-                .ok_or(ProcessingError::DecimalOverflow)?;
-            let new_available = available
-                .checked_add(-transaction.amount)
-                .ok_or(ProcessingError::DecimalOverflow)?;
-            Ok((new_available, new_held))
-        }
+/// Administrative transaction, its operator is checked by [`crate::AccountManager`]
+fn admin_transaction(
+    record: &InputRecord,
+    amount: Decimal,
+    r#type: TransactionType,
+    currency: Option<Currency>,
+) -> Transaction {
+    Transaction {
+        state: TransactionState::Valid,
+        amount,
+        r#type,
+        previous_states: Vec::new(),
+        admin: Some(Box::new(AdminNote {
+            operator: record.operator.clone().unwrap_or_default(),
+            reason: record.reason.clone(),
+        })),
+        timestamp: record.timestamp,
+        disputed_at: None,
+        disputed_funds: None,
+        currency,
+        counterparty: None,
     }
 }
 
-fn check_if_state_eq(
-    transaction: &Transaction,
-    expected: TransactionState,
-) -> Result<(), ProcessingError> {
-    if transaction.state != expected {
+fn check_if_disputed(transaction: &Transaction) -> Result<(), ProcessingError> {
+    if !transaction.state.is_disputed() {
         return Err(ProcessingError::TransactionWrongState(
-            expected,
-            transaction.state.clone(),
+            TransactionState::Dispute,
+            transaction.state,
         ));
     }
 
     Ok(())
 }
 
+/// Record came more than `limit` seconds after `since`, never true when either time is unknown
+fn exceeds(limit: Option<u64>, since: Option<Timestamp>, record: &InputRecord) -> bool {
+    match (limit, since, record.timestamp) {
+        (Some(limit), Some(since), Some(timestamp)) => timestamp.saturating_sub(since) > limit,
+        _ => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, HashMap};
 
     use rust_decimal::Decimal;
     use rust_decimal_macros::dec;
 
     use super::{Account, ProcessingError, Transaction, TransactionState, TransactionType};
-    use crate::model::{InputRecord, InputRecordType};
+    use crate::{
+        account_manager::dispute_policy::DisputeRules,
+        model::{InputRecord, InputRecordType, TransactionId, TransactionRecord},
+        rates::ExchangeRates,
+    };
+
+    #[test]
+    fn test_error_code() {
+        assert_eq!("account_is_locked", ProcessingError::AccountIsLocked.code());
+        assert_eq!(
+            "withdrawal_not_enough_money_available",
+            ProcessingError::WithdrawalNotEnoughMoneyAvailable(Decimal::ZERO, Decimal::ONE).code()
+        );
+    }
 
     #[test]
     fn test_to_output() {
@@ -263,10 +1196,12 @@ mod tests {
             transactions: HashMap::default(),
             available: dec!(10.0),
             held: dec!(15.0),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
             locked: true,
         };
 
-        let output = account.to_output();
+        let output = account.to_outputs().remove(0);
         assert_eq!(1234, output.client_id);
         assert_eq!(dec!(10.0), output.available);
         assert_eq!(dec!(15.0), output.held);
@@ -274,16 +1209,66 @@ mod tests {
         assert!(output.locked);
     }
 
+    #[test]
+    fn test_transactions() {
+        let mut account = Account::new(1);
+        for (r#type, transaction_id, amount) in [
+            (InputRecordType::Deposit, 2, Some(dec!(10.0))),
+            (InputRecordType::Withdrawal, 1, Some(dec!(4.0))),
+            (InputRecordType::Dispute, 2, None),
+            (InputRecordType::Resolve, 2, None),
+            (InputRecordType::Dispute, 1, None),
+        ] {
+            account
+                .process_record(&InputRecord::new(r#type, 1, transaction_id, amount))
+                .unwrap();
+        }
+
+        assert_eq!(
+            vec![
+                TransactionRecord {
+                    transaction_id: 1,
+                    r#type: TransactionType::Withdrawal,
+                    amount: dec!(4.0),
+                    state: TransactionState::Dispute,
+                    transitions: vec![TransactionState::Valid, TransactionState::Dispute],
+                    operator: None,
+                    reason: None,
+                    timestamp: None,
+                    currency: None,
+                    counterparty: None,
+                },
+                TransactionRecord {
+                    transaction_id: 2,
+                    r#type: TransactionType::Deposit,
+                    amount: dec!(10.0),
+                    state: TransactionState::Resolved,
+                    transitions: vec![
+                        TransactionState::Valid,
+                        TransactionState::Dispute,
+                        TransactionState::Resolved
+                    ],
+                    operator: None,
+                    reason: None,
+                    timestamp: None,
+                    currency: None,
+                    counterparty: None,
+                },
+            ],
+            account.transactions()
+        );
+    }
+
     #[test]
     fn test_process_deposit_success() {
         let mut account = Account::new(0);
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Deposit,
-                client_id: 0,
-                transaction_id: 0,
-                amount: Some(dec!(1.0)),
-            })
+            .process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                0,
+                0,
+                Some(dec!(1.0)),
+            ))
             .unwrap();
 
         assert_eq!(dec!(1.0), account.available);
@@ -292,12 +1277,8 @@ mod tests {
     #[test]
     fn test_process_deposit_fail_missing_amount() {
         let mut account = Account::new(0);
-        let result = account.process_record(&InputRecord {
-            r#type: InputRecordType::Deposit,
-            client_id: 0,
-            transaction_id: 0,
-            amount: None,
-        });
+        let result =
+            account.process_record(&InputRecord::new(InputRecordType::Deposit, 0, 0, None));
 
         assert!(matches!(result, Err(ProcessingError::AmountMissing)));
     }
@@ -309,15 +1290,17 @@ mod tests {
             transactions: HashMap::default(),
             available: dec!(10.0),
             held: Decimal::default(),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
             locked: false,
         };
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Withdrawal,
-                client_id: 0,
-                transaction_id: 0,
-                amount: Some(dec!(1.0)),
-            })
+            .process_record(&InputRecord::new(
+                InputRecordType::Withdrawal,
+                0,
+                0,
+                Some(dec!(1.0)),
+            ))
             .unwrap();
 
         assert_eq!(dec!(9.0), account.available);
@@ -327,12 +1310,8 @@ mod tests {
     #[test]
     fn test_process_withdraw_fail_missing_amount() {
         let mut account = Account::new(0);
-        let result = account.process_record(&InputRecord {
-            r#type: InputRecordType::Withdrawal,
-            client_id: 0,
-            transaction_id: 0,
-            amount: None,
-        });
+        let result =
+            account.process_record(&InputRecord::new(InputRecordType::Withdrawal, 0, 0, None));
 
         assert!(matches!(result, Err(ProcessingError::AmountMissing)));
     }
@@ -344,14 +1323,16 @@ mod tests {
             transactions: HashMap::default(),
             available: dec!(10.0),
             held: Decimal::default(),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
             locked: false,
         };
-        let result = account.process_record(&InputRecord {
-            r#type: InputRecordType::Withdrawal,
-            client_id: 0,
-            transaction_id: 0,
-            amount: Some(dec!(11.0)),
-        });
+        let result = account.process_record(&InputRecord::new(
+            InputRecordType::Withdrawal,
+            0,
+            0,
+            Some(dec!(11.0)),
+        ));
 
         assert!(matches!(
             result,
@@ -369,19 +1350,23 @@ mod tests {
                     state: TransactionState::Valid,
                     amount: dec!(10.0),
                     r#type: TransactionType::Deposit,
+                    previous_states: Vec::new(),
+                    admin: None,
+                    timestamp: None,
+                    disputed_at: None,
+                    disputed_funds: None,
+                    currency: None,
+                    counterparty: None,
                 },
             )]),
             available: dec!(10.0),
             held: dec!(0.0),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
             locked: false,
         };
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Dispute,
-                client_id: 0,
-                transaction_id: 0,
-                amount: None,
-            })
+            .process_record(&InputRecord::new(InputRecordType::Dispute, 0, 0, None))
             .unwrap();
 
         assert_eq!(dec!(0.0), account.available);
@@ -398,19 +1383,23 @@ mod tests {
                     state: TransactionState::Valid,
                     amount: dec!(-10.0),
                     r#type: TransactionType::Withdrawal,
+                    previous_states: Vec::new(),
+                    admin: None,
+                    timestamp: None,
+                    disputed_at: None,
+                    disputed_funds: None,
+                    currency: None,
+                    counterparty: None,
                 },
             )]),
             available: dec!(0.0),
             held: dec!(0.0),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
             locked: false,
         };
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Dispute,
-                client_id: 0,
-                transaction_id: 0,
-                amount: None,
-            })
+            .process_record(&InputRecord::new(InputRecordType::Dispute, 0, 0, None))
             .unwrap();
 
         assert_eq!(dec!(0.0), account.available);
@@ -428,18 +1417,23 @@ mod tests {
                     state: TransactionState::ChargedBack,
                     amount: dec!(10.0),
                     r#type: TransactionType::Deposit,
+                    previous_states: Vec::new(),
+                    admin: None,
+                    timestamp: None,
+                    disputed_at: None,
+                    disputed_funds: None,
+                    currency: None,
+                    counterparty: None,
                 },
             )]),
             available: dec!(10.0),
             held: dec!(0.0),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
             locked: false,
         };
-        let result = account.process_record(&InputRecord {
-            r#type: InputRecordType::Dispute,
-            client_id: 0,
-            transaction_id: 0,
-            amount: None,
-        });
+        let result =
+            account.process_record(&InputRecord::new(InputRecordType::Dispute, 0, 0, None));
         assert!(matches!(
             result,
             Err(ProcessingError::TransactionWrongState(_, _))
@@ -456,18 +1450,23 @@ mod tests {
                     state: TransactionState::ChargedBack,
                     amount: dec!(10.0),
                     r#type: TransactionType::Deposit,
+                    previous_states: Vec::new(),
+                    admin: None,
+                    timestamp: None,
+                    disputed_at: None,
+                    disputed_funds: None,
+                    currency: None,
+                    counterparty: None,
                 },
             )]),
             available: dec!(10.0),
             held: dec!(0.0),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
             locked: false,
         };
-        let result = account.process_record(&InputRecord {
-            r#type: InputRecordType::Dispute,
-            client_id: 0,
-            transaction_id: 0,
-            amount: None,
-        });
+        let result =
+            account.process_record(&InputRecord::new(InputRecordType::Dispute, 0, 0, None));
         assert!(matches!(
             result,
             Err(ProcessingError::TransactionWrongState(_, _))
@@ -484,19 +1483,23 @@ mod tests {
                     state: TransactionState::Dispute,
                     amount: dec!(10.0),
                     r#type: TransactionType::Deposit,
+                    previous_states: Vec::new(),
+                    admin: None,
+                    timestamp: None,
+                    disputed_at: None,
+                    disputed_funds: None,
+                    currency: None,
+                    counterparty: None,
                 },
             )]),
             available: dec!(0.0),
             held: dec!(10.0),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
             locked: false,
         };
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Resolve,
-                client_id: 0,
-                transaction_id: 0,
-                amount: None,
-            })
+            .process_record(&InputRecord::new(InputRecordType::Resolve, 0, 0, None))
             .unwrap();
 
         assert_eq!(dec!(0.0), account.available);
@@ -514,19 +1517,23 @@ mod tests {
                     state: TransactionState::Dispute,
                     amount: dec!(-10.0),
                     r#type: TransactionType::Withdrawal,
+                    previous_states: Vec::new(),
+                    admin: None,
+                    timestamp: None,
+                    disputed_at: None,
+                    disputed_funds: None,
+                    currency: None,
+                    counterparty: None,
                 },
             )]),
             available: dec!(0.0),
             held: dec!(10.0),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
             locked: false,
         };
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Resolve,
-                client_id: 0,
-                transaction_id: 0,
-                amount: None,
-            })
+            .process_record(&InputRecord::new(InputRecordType::Resolve, 0, 0, None))
             .unwrap();
 
         assert_eq!(dec!(10.0), account.available);
@@ -544,19 +1551,23 @@ mod tests {
                     state: TransactionState::Dispute,
                     amount: dec!(10.0),
                     r#type: TransactionType::Deposit,
+                    previous_states: Vec::new(),
+                    admin: None,
+                    timestamp: None,
+                    disputed_at: None,
+                    disputed_funds: None,
+                    currency: None,
+                    counterparty: None,
                 },
             )]),
             available: dec!(0.0),
             held: dec!(10.0),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
             locked: false,
         };
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Chargeback,
-                client_id: 0,
-                transaction_id: 0,
-                amount: None,
-            })
+            .process_record(&InputRecord::new(InputRecordType::Chargeback, 0, 0, None))
             .unwrap();
 
         assert_eq!(dec!(0.0), account.available);
@@ -575,51 +1586,28 @@ mod tests {
             transactions: HashMap::default(),
             available: dec!(0.0),
             held: dec!(10.0),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
             locked: true,
         };
         assert!(matches!(
-            account.process_record(&InputRecord {
-                r#type: InputRecordType::Deposit,
-                client_id: 0,
-                transaction_id: 0,
-                amount: None,
-            }),
+            account.process_record(&InputRecord::new(InputRecordType::Deposit, 0, 0, None)),
             Err(ProcessingError::AccountIsLocked)
         ));
         assert!(matches!(
-            account.process_record(&InputRecord {
-                r#type: InputRecordType::Withdrawal,
-                client_id: 0,
-                transaction_id: 0,
-                amount: None,
-            }),
+            account.process_record(&InputRecord::new(InputRecordType::Withdrawal, 0, 0, None)),
             Err(ProcessingError::AccountIsLocked)
         ));
         assert!(matches!(
-            account.process_record(&InputRecord {
-                r#type: InputRecordType::Dispute,
-                client_id: 0,
-                transaction_id: 0,
-                amount: None,
-            }),
+            account.process_record(&InputRecord::new(InputRecordType::Dispute, 0, 0, None)),
             Err(ProcessingError::AccountIsLocked)
         ));
         assert!(matches!(
-            account.process_record(&InputRecord {
-                r#type: InputRecordType::Resolve,
-                client_id: 0,
-                transaction_id: 0,
-                amount: None,
-            }),
+            account.process_record(&InputRecord::new(InputRecordType::Resolve, 0, 0, None)),
             Err(ProcessingError::AccountIsLocked)
         ));
         assert!(matches!(
-            account.process_record(&InputRecord {
-                r#type: InputRecordType::Chargeback,
-                client_id: 0,
-                transaction_id: 0,
-                amount: None,
-            }),
+            account.process_record(&InputRecord::new(InputRecordType::Chargeback, 0, 0, None)),
             Err(ProcessingError::AccountIsLocked)
         ));
     }
@@ -634,29 +1622,38 @@ mod tests {
                     state: TransactionState::Valid,
                     amount: Decimal::default(),
                     r#type: TransactionType::Deposit,
+                    previous_states: Vec::new(),
+                    admin: None,
+                    timestamp: None,
+                    disputed_at: None,
+                    disputed_funds: None,
+                    currency: None,
+                    counterparty: None,
                 },
             )]),
             available: dec!(0.0),
             held: dec!(0.0),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
             locked: false,
         };
 
         assert!(matches!(
-            account.process_record(&InputRecord {
-                r#type: InputRecordType::Deposit,
-                client_id: 0,
-                transaction_id: 0,
-                amount: Some(dec!(50.0)),
-            }),
+            account.process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                0,
+                0,
+                Some(dec!(50.0))
+            )),
             Err(ProcessingError::TransactionAlreadyExists(_))
         ));
         assert!(matches!(
-            account.process_record(&InputRecord {
-                r#type: InputRecordType::Withdrawal,
-                client_id: 0,
-                transaction_id: 0,
-                amount: Some(dec!(50.0)),
-            }),
+            account.process_record(&InputRecord::new(
+                InputRecordType::Withdrawal,
+                0,
+                0,
+                Some(dec!(50.0))
+            )),
             Err(ProcessingError::TransactionAlreadyExists(_))
         ));
     }
@@ -668,127 +1665,786 @@ mod tests {
             transactions: HashMap::default(),
             available: dec!(0.0),
             held: dec!(0.0),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
             locked: false,
         };
 
         // Deposit 100 and withdraw 50
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Deposit,
-                client_id: 0,
-                transaction_id: 0,
-                amount: Some(dec!(100.0)),
-            })
+            .process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                0,
+                0,
+                Some(dec!(100.0)),
+            ))
             .unwrap();
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Withdrawal,
-                client_id: 0,
-                transaction_id: 1,
-                amount: Some(dec!(50.0)),
-            })
+            .process_record(&InputRecord::new(
+                InputRecordType::Withdrawal,
+                0,
+                1,
+                Some(dec!(50.0)),
+            ))
             .unwrap();
         assert_eq!(dec!(50.0), account.available);
         assert_eq!(dec!(0.0), account.held);
 
         // Dispute the withdrawal
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Dispute,
-                client_id: 0,
-                transaction_id: 1,
-                amount: None,
-            })
+            .process_record(&InputRecord::new(InputRecordType::Dispute, 0, 1, None))
             .unwrap();
         assert_eq!(dec!(50.0), account.available);
         assert_eq!(dec!(50.0), account.held);
 
         // Resolve the withdrawal
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Resolve,
-                client_id: 0,
-                transaction_id: 1,
-                amount: None,
-            })
+            .process_record(&InputRecord::new(InputRecordType::Resolve, 0, 1, None))
             .unwrap();
         assert_eq!(dec!(100.0), account.available);
         assert_eq!(dec!(0.0), account.held);
 
         // Dispute the deposit
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Dispute,
-                client_id: 0,
-                transaction_id: 0,
-                amount: None,
-            })
+            .process_record(&InputRecord::new(InputRecordType::Dispute, 0, 0, None))
             .unwrap();
         assert_eq!(dec!(0.0), account.available);
         assert_eq!(dec!(100.0), account.held);
 
         // Resolve the deposit
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Resolve,
-                client_id: 0,
-                transaction_id: 0,
-                amount: None,
-            })
+            .process_record(&InputRecord::new(InputRecordType::Resolve, 0, 0, None))
             .unwrap();
         assert_eq!(dec!(0.0), account.available);
         assert_eq!(dec!(0.0), account.held);
 
         // Deposit 100 and withdraw 100
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Deposit,
-                client_id: 0,
-                transaction_id: 2,
-                amount: Some(dec!(100.0)),
-            })
+            .process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                0,
+                2,
+                Some(dec!(100.0)),
+            ))
             .unwrap();
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Withdrawal,
-                client_id: 0,
-                transaction_id: 3,
-                amount: Some(dec!(100.0)),
-            })
+            .process_record(&InputRecord::new(
+                InputRecordType::Withdrawal,
+                0,
+                3,
+                Some(dec!(100.0)),
+            ))
             .unwrap();
         assert_eq!(dec!(0.0), account.available);
         assert_eq!(dec!(0.0), account.held);
 
         // Dispute deposit and chargeback deposit
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Dispute,
-                client_id: 0,
-                transaction_id: 2,
-                amount: None,
-            })
+            .process_record(&InputRecord::new(InputRecordType::Dispute, 0, 2, None))
             .unwrap();
         account
-            .process_record(&InputRecord {
-                r#type: InputRecordType::Chargeback,
-                client_id: 0,
-                transaction_id: 2,
-                amount: None,
-            })
+            .process_record(&InputRecord::new(InputRecordType::Chargeback, 0, 2, None))
             .unwrap();
         assert_eq!(dec!(-100.0), account.available);
         assert_eq!(dec!(0.0), account.held);
         assert!(account.locked);
 
         // No further operations are allowed
+        assert!(matches!(
+            account.process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                0,
+                4,
+                Some(dec!(99999.0))
+            )),
+            Err(ProcessingError::AccountIsLocked)
+        ));
+    }
+
+    #[test]
+    fn test_process_unlock_after_chargeback() {
+        let mut account = Account {
+            client_id: 1,
+            transactions: HashMap::default(),
+            available: dec!(5.0),
+            held: dec!(0.0),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
+            locked: true,
+        };
+
+        account
+            .process_record(&InputRecord::admin(
+                InputRecordType::Unlock,
+                1,
+                1,
+                None,
+                "alice",
+            ))
+            .unwrap();
+        assert!(!account.locked);
+        assert!(matches!(
+            account.process_record(&InputRecord::admin(
+                InputRecordType::Unlock,
+                1,
+                2,
+                None,
+                "alice"
+            )),
+            Err(ProcessingError::AccountNotLocked)
+        ));
+
+        // Regular records work again
+        account
+            .process_record(&InputRecord::new(
+                InputRecordType::Withdrawal,
+                1,
+                3,
+                Some(dec!(5.0)),
+            ))
+            .unwrap();
+        assert_eq!(dec!(0.0), account.available);
+    }
+
+    #[test]
+    fn test_process_freeze() {
+        let mut account = Account::new(1);
+        account
+            .process_record(&InputRecord::admin(
+                InputRecordType::Freeze,
+                1,
+                1,
+                None,
+                "alice",
+            ))
+            .unwrap();
+        assert!(account.locked);
+        assert!(matches!(
+            account.process_record(&InputRecord::admin(
+                InputRecordType::Freeze,
+                1,
+                2,
+                None,
+                "alice"
+            )),
+            Err(ProcessingError::AccountIsLocked)
+        ));
+        assert!(matches!(
+            account.process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                1,
+                3,
+                Some(dec!(5.0))
+            )),
+            Err(ProcessingError::AccountIsLocked)
+        ));
+    }
+
+    #[test]
+    fn test_process_adjust() {
+        let mut account = Account {
+            client_id: 1,
+            transactions: HashMap::default(),
+            available: dec!(5.0),
+            held: dec!(1.0),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
+            locked: true,
+        };
+
+        // Works on locked account
+        account
+            .process_record(&InputRecord::admin(
+                InputRecordType::Adjust,
+                1,
+                1,
+                Some(dec!(2.5)),
+                "alice",
+            ))
+            .unwrap();
+        account
+            .process_record(&InputRecord::admin(
+                InputRecordType::Adjust,
+                1,
+                2,
+                Some(dec!(-7.5)),
+                "alice",
+            ))
+            .unwrap();
+        assert_eq!(dec!(0.0), account.available);
+        assert_eq!(dec!(1.0), account.held);
+        assert!(account.locked);
+
+        assert!(matches!(
+            account.process_record(&InputRecord::admin(
+                InputRecordType::Adjust,
+                1,
+                3,
+                Some(dec!(-0.1)),
+                "alice"
+            )),
+            Err(ProcessingError::WithdrawalNotEnoughMoneyAvailable(_, _))
+        ));
+        assert!(matches!(
+            account.process_record(&InputRecord::admin(
+                InputRecordType::Adjust,
+                1,
+                1,
+                Some(dec!(1.0)),
+                "alice"
+            )),
+            Err(ProcessingError::TransactionAlreadyExists(1))
+        ));
+
+        account
+            .process_record(&InputRecord::admin(
+                InputRecordType::Unlock,
+                1,
+                5,
+                None,
+                "alice",
+            ))
+            .unwrap();
+        assert!(matches!(
+            account.process_record(&InputRecord::new(InputRecordType::Dispute, 1, 1, None)),
+            Err(ProcessingError::TransactionNotDisputable(
+                TransactionType::Adjustment
+            ))
+        ));
+
+        let transactions = account.transactions();
+        assert_eq!(dec!(-7.5), transactions[1].amount);
+        assert_eq!(Some("alice"), transactions[1].operator.as_deref());
+        assert_eq!(Some("remediation"), transactions[1].reason.as_deref());
+        assert_eq!(TransactionType::Unlock, transactions[2].r#type);
+    }
+
+    fn dispute_cycle_account(
+        r#type: TransactionType,
+        amount: Decimal,
+        state: TransactionState,
+        previous_states: Vec<TransactionState>,
+    ) -> Account {
+        Account {
+            client_id: 0,
+            transactions: HashMap::from([(
+                0,
+                Transaction {
+                    state,
+                    amount,
+                    r#type,
+                    previous_states,
+                    admin: None,
+                    timestamp: None,
+                    disputed_at: None,
+                    disputed_funds: None,
+                    currency: None,
+                    counterparty: None,
+                },
+            )]),
+            available: dec!(0.0),
+            held: dec!(0.0),
+            currencies: BTreeMap::new(),
+            fees: Vec::new(),
+            locked: false,
+        }
+    }
+
+    fn process_cycle_record(
+        account: &mut Account,
+        r#type: InputRecordType,
+        max_cycles: u8,
+    ) -> Result<(), ProcessingError> {
+        let rules = DisputeRules {
+            max_cycles,
+            ..DisputeRules::default()
+        };
+        let record = InputRecord::new(r#type, 0, 0, None);
+        let change = account.plan_record(&record, &rules, None, None, None)?;
+        account.apply_change(change);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_redispute_limit_reached() {
+        for previous_states in [
+            vec![TransactionState::Valid, TransactionState::Dispute],
+            // Snapshot from before state history was kept
+            Vec::new(),
+        ] {
+            let mut account = dispute_cycle_account(
+                TransactionType::Deposit,
+                dec!(10.0),
+                TransactionState::Resolved,
+                previous_states,
+            );
+            assert!(matches!(
+                process_cycle_record(&mut account, InputRecordType::Dispute, 1),
+                Err(ProcessingError::DisputeCycleLimitReached(1))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_process_second_dispute_deposit() {
+        let mut account = dispute_cycle_account(
+            TransactionType::Deposit,
+            dec!(10.0),
+            TransactionState::Resolved,
+            vec![TransactionState::Valid, TransactionState::Dispute],
+        );
+
+        process_cycle_record(&mut account, InputRecordType::Dispute, 2).unwrap();
+        assert_eq!(
+            TransactionState::SecondDispute,
+            account.transactions[&0].state
+        );
+        assert_eq!(dec!(0.0), account.available);
+        assert_eq!(dec!(10.0), account.held);
+
+        process_cycle_record(&mut account, InputRecordType::Resolve, 2).unwrap();
+        assert_eq!(dec!(0.0), account.available);
+        assert_eq!(dec!(0.0), account.held);
+        assert!(matches!(
+            process_cycle_record(&mut account, InputRecordType::Dispute, 2),
+            Err(ProcessingError::DisputeCycleLimitReached(2))
+        ));
+        assert_eq!(
+            vec![
+                TransactionState::Valid,
+                TransactionState::Dispute,
+                TransactionState::Resolved,
+                TransactionState::SecondDispute,
+                TransactionState::Resolved,
+            ],
+            account.transactions()[0].transitions
+        );
+    }
+
+    #[test]
+    fn test_process_arbitration_withdrawal() {
+        let mut account = dispute_cycle_account(
+            TransactionType::Withdrawal,
+            dec!(-10.0),
+            TransactionState::Valid,
+            Vec::new(),
+        );
+
+        for (r#type, state, available, held) in [
+            (
+                InputRecordType::Dispute,
+                TransactionState::Dispute,
+                dec!(0.0),
+                dec!(10.0),
+            ),
+            (
+                InputRecordType::Resolve,
+                TransactionState::Resolved,
+                dec!(10.0),
+                dec!(0.0),
+            ),
+            (
+                InputRecordType::Dispute,
+                TransactionState::SecondDispute,
+                dec!(0.0),
+                dec!(10.0),
+            ),
+            (
+                InputRecordType::Resolve,
+                TransactionState::Resolved,
+                dec!(10.0),
+                dec!(0.0),
+            ),
+            (
+                InputRecordType::Dispute,
+                TransactionState::Arbitration,
+                dec!(0.0),
+                dec!(10.0),
+            ),
+            (
+                InputRecordType::Resolve,
+                TransactionState::Resolved,
+                dec!(10.0),
+                dec!(0.0),
+            ),
+        ] {
+            // Limit above maximum still ends with arbitration
+            process_cycle_record(&mut account, r#type, u8::MAX).unwrap();
+            assert_eq!(state, account.transactions[&0].state);
+            assert_eq!(available, account.available);
+            assert_eq!(held, account.held);
+        }
+
+        assert!(matches!(
+            process_cycle_record(&mut account, InputRecordType::Dispute, u8::MAX),
+            Err(ProcessingError::DisputeCycleLimitReached(3))
+        ));
+    }
+
+    #[test]
+    fn test_process_chargeback_later_cycles() {
+        for (state, previous_states) in [
+            (
+                TransactionState::SecondDispute,
+                vec![
+                    TransactionState::Valid,
+                    TransactionState::Dispute,
+                    TransactionState::Resolved,
+                ],
+            ),
+            (
+                TransactionState::Arbitration,
+                vec![
+                    TransactionState::Valid,
+                    TransactionState::Dispute,
+                    TransactionState::Resolved,
+                    TransactionState::SecondDispute,
+                    TransactionState::Resolved,
+                ],
+            ),
+        ] {
+            let mut account =
+                dispute_cycle_account(TransactionType::Deposit, dec!(10.0), state, previous_states);
+            account.held = dec!(10.0);
+
+            process_cycle_record(&mut account, InputRecordType::Chargeback, 3).unwrap();
+            assert_eq!(
+                TransactionState::ChargedBack,
+                account.transactions[&0].state
+            );
+            assert_eq!(dec!(0.0), account.held);
+            assert!(account.locked);
+            assert!(matches!(
+                account.process_record(&InputRecord::new(InputRecordType::Dispute, 0, 0, None)),
+                Err(ProcessingError::AccountIsLocked)
+            ));
+        }
+    }
+
+    fn process_timed_record(
+        account: &mut Account,
+        r#type: InputRecordType,
+        timestamp: Option<u64>,
+        rules: &DisputeRules,
+    ) -> Result<(), ProcessingError> {
+        let record = InputRecord {
+            timestamp,
+            ..InputRecord::new(r#type, 0, 0, None)
+        };
+        let change = account.plan_record(&record, rules, None, None, None)?;
+        account.apply_change(change);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_dispute_window() {
+        let rules = DisputeRules {
+            dispute_window: Some(100),
+            ..DisputeRules::default()
+        };
+        let timed_account = || {
+            let mut account = dispute_cycle_account(
+                TransactionType::Deposit,
+                dec!(10.0),
+                TransactionState::Valid,
+                Vec::new(),
+            );
+            account.transactions.get_mut(&0).unwrap().timestamp = Some(1_000);
+            account
+        };
+
+        let mut account = timed_account();
+        assert!(matches!(
+            process_timed_record(&mut account, InputRecordType::Dispute, Some(1_101), &rules),
+            Err(ProcessingError::DisputeWindowExpired(0))
+        ));
+        process_timed_record(&mut account, InputRecordType::Dispute, Some(1_100), &rules).unwrap();
+        assert_eq!(Some(1_100), account.transactions[&0].disputed_at);
+
+        // Window can't be enforced without time of dispute
+        let mut account = timed_account();
+        process_timed_record(&mut account, InputRecordType::Dispute, None, &rules).unwrap();
+    }
+
+    #[test]
+    fn test_process_resolution_deadline() {
+        let rules = DisputeRules {
+            resolution_deadline: Some(10),
+            ..DisputeRules::default()
+        };
+        let disputed_account = || {
+            let mut account = dispute_cycle_account(
+                TransactionType::Deposit,
+                dec!(10.0),
+                TransactionState::Valid,
+                Vec::new(),
+            );
+            account.available = dec!(10.0);
+            process_timed_record(&mut account, InputRecordType::Dispute, Some(50), &rules).unwrap();
+            account
+        };
+
+        let mut account = disputed_account();
+        assert!(matches!(
+            process_timed_record(&mut account, InputRecordType::Chargeback, Some(61), &rules),
+            Err(ProcessingError::ResolutionDeadlineExceeded(0))
+        ));
+        process_timed_record(&mut account, InputRecordType::Chargeback, Some(60), &rules).unwrap();
+        assert_eq!(None, account.transactions[&0].disputed_at);
+        assert_eq!(dec!(0.0), account.held);
+
+        // Overdue dispute can still be resolved, funds aren't held forever
+        let mut account = disputed_account();
+        process_timed_record(
+            &mut account,
+            InputRecordType::Resolve,
+            Some(99_999_999),
+            &rules,
+        )
+        .unwrap();
+        assert_eq!(TransactionState::Resolved, account.transactions[&0].state);
+        assert_eq!(dec!(0.0), account.held);
+        assert_eq!(dec!(0.0), account.available);
+    }
+
+    fn currency_record(
+        r#type: InputRecordType,
+        transaction_id: TransactionId,
+        amount: Option<Decimal>,
+        currency: Option<&str>,
+    ) -> InputRecord {
+        InputRecord {
+            currency: currency.map(|currency| currency.parse().unwrap()),
+            ..InputRecord::new(r#type, 1, transaction_id, amount)
+        }
+    }
+
+    #[test]
+    fn test_process_currencies() {
+        let mut account = Account::new(1);
+        for record in [
+            currency_record(InputRecordType::Deposit, 1, Some(dec!(10.0)), None),
+            currency_record(InputRecordType::Deposit, 2, Some(dec!(20.0)), Some("EUR")),
+            currency_record(InputRecordType::Deposit, 3, Some(dec!(30.0)), Some("USD")),
+            currency_record(InputRecordType::Withdrawal, 4, Some(dec!(5.0)), Some("EUR")),
+            // Dispute inherits currency of transaction
+            currency_record(InputRecordType::Dispute, 3, None, None),
+        ] {
+            account.process_record(&record).unwrap();
+        }
+
+        assert!(matches!(
+            account.process_record(&currency_record(
+                InputRecordType::Withdrawal,
+                5,
+                Some(dec!(16.0)),
+                Some("EUR")
+            )),
+            Err(ProcessingError::WithdrawalNotEnoughMoneyAvailable(_, _))
+        ));
+        assert!(matches!(
+            account.process_record(&currency_record(
+                InputRecordType::Resolve,
+                3,
+                None,
+                Some("EUR")
+            )),
+            Err(ProcessingError::CurrencyMismatch(3))
+        ));
+        assert!(matches!(
+            account.process_record(&currency_record(InputRecordType::Dispute, 2, None, None)),
+            Ok(())
+        ));
+
+        let outputs = account.to_outputs();
+        assert_eq!(3, outputs.len());
+        assert_eq!(
+            (None, dec!(10.0), dec!(0.0)),
+            (outputs[0].currency, outputs[0].available, outputs[0].held)
+        );
+        assert_eq!(
+            (Some("EUR".parse().unwrap()), dec!(-5.0), dec!(20.0)),
+            (outputs[1].currency, outputs[1].available, outputs[1].held)
+        );
+        assert_eq!(
+            (Some("USD".parse().unwrap()), dec!(0.0), dec!(30.0)),
+            (outputs[2].currency, outputs[2].available, outputs[2].held)
+        );
+    }
+
+    #[test]
+    fn test_to_outputs_currencies_only() {
+        let mut account = Account::new(1);
+        account
+            .process_record(&currency_record(
+                InputRecordType::Deposit,
+                1,
+                Some(dec!(1.0)),
+                Some("GBP"),
+            ))
+            .unwrap();
+
+        let outputs = account.to_outputs();
+        assert_eq!(1, outputs.len());
+        assert_eq!(Some("GBP".parse().unwrap()), outputs[0].currency);
+    }
+
+    fn process_conversion(
+        account: &mut Account,
+        transaction_id: TransactionId,
+        amount: Decimal,
+        to_currency: &str,
+        exchange_rates: Option<&ExchangeRates>,
+    ) -> Result<(), ProcessingError> {
+        let record = InputRecord {
+            to_currency: Some(to_currency.parse().unwrap()),
+            ..currency_record(
+                InputRecordType::Convert,
+                transaction_id,
+                Some(amount),
+                Some("EUR"),
+            )
+        };
+        let change = account.plan_record(
+            &record,
+            &DisputeRules::default(),
+            exchange_rates,
+            None,
+            None,
+        )?;
+        account.apply_change(change);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_convert() {
+        let mut rates = ExchangeRates::new("USD".parse().unwrap());
+        rates.insert("EUR".parse().unwrap(), dec!(1.08)).unwrap();
+        let mut account = Account::new(1);
+        account
+            .process_record(&currency_record(
+                InputRecordType::Deposit,
+                1,
+                Some(dec!(10.0)),
+                Some("EUR"),
+            ))
+            .unwrap();
+
+        process_conversion(&mut account, 2, dec!(4.0), "USD", Some(&rates)).unwrap();
+        let balances = |account: &Account, currency: &str| {
+            account
+                .currency_balances(Some(currency.parse().unwrap()))
+                .available
+        };
+        assert_eq!(dec!(6.0), balances(&account, "EUR"));
+        assert_eq!(dec!(4.32), balances(&account, "USD"));
+        assert_eq!(TransactionType::Conversion, account.transactions[&2].r#type);
+
+        // Neither leg is applied if conversion fails
+        for (transaction_id, amount, to_currency, exchange_rates, expected) in [
+            (
+                3,
+                dec!(6.1),
+                "USD",
+                Some(&rates),
+                "withdrawal_not_enough_money_available",
+            ),
+            (
+                3,
+                dec!(1.0),
+                "EUR",
+                Some(&rates),
+                "conversion_to_same_currency",
+            ),
+            (3, dec!(1.0), "GBP", Some(&rates), "rate_missing"),
+            (3, dec!(1.0), "USD", None, "rate_missing"),
+            (
+                2,
+                dec!(1.0),
+                "USD",
+                Some(&rates),
+                "transaction_already_exists",
+            ),
+        ] {
+            let error = process_conversion(
+                &mut account,
+                transaction_id,
+                amount,
+                to_currency,
+                exchange_rates,
+            )
+            .unwrap_err();
+            assert_eq!(expected, error.code());
+        }
+        assert_eq!(dec!(6.0), balances(&account, "EUR"));
+        assert_eq!(dec!(4.32), balances(&account, "USD"));
+
+        assert!(matches!(
+            account.process_record(&currency_record(InputRecordType::Dispute, 2, None, None)),
+            Err(ProcessingError::TransactionNotDisputable(
+                TransactionType::Conversion
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_process_transfer() {
+        let mut account = Account::new(1);
+        account
+            .process_record(&currency_record(
+                InputRecordType::Deposit,
+                1,
+                Some(dec!(10.0)),
+                None,
+            ))
+            .unwrap();
+
+        // Money would leave without reaching anyone
         assert!(matches!(
             account.process_record(&InputRecord {
-                r#type: InputRecordType::Deposit,
-                client_id: 0,
-                transaction_id: 4,
-                amount: Some(dec!(99999.0)),
+                to_client_id: Some(2),
+                ..currency_record(InputRecordType::Transfer, 2, Some(dec!(4.0)), None)
             }),
-            Err(ProcessingError::AccountIsLocked)
+            Err(ProcessingError::DestinationAccountMissing(2))
+        ));
+        assert_eq!(dec!(10.0), account.available);
+        assert!(!account.transactions.contains_key(&2));
+    }
+
+    #[test]
+    fn test_valuation() {
+        let mut rates = ExchangeRates::new("USD".parse().unwrap());
+        rates.insert("EUR".parse().unwrap(), dec!(1.5)).unwrap();
+        let mut account = Account::new(1);
+        for record in [
+            currency_record(InputRecordType::Deposit, 1, Some(dec!(1.0)), None),
+            currency_record(InputRecordType::Deposit, 2, Some(dec!(2.0)), Some("EUR")),
+            currency_record(InputRecordType::Deposit, 3, Some(dec!(3.0)), Some("USD")),
+            currency_record(InputRecordType::Dispute, 2, None, None),
+        ] {
+            account.process_record(&record).unwrap();
+        }
+
+        let valuation = account.valuation(&rates).unwrap();
+        assert_eq!(Some("USD".parse().unwrap()), valuation.currency);
+        assert_eq!(dec!(4.0), valuation.available);
+        assert_eq!(dec!(3.0), valuation.held);
+        assert_eq!(dec!(7.0), valuation.total);
+
+        account
+            .process_record(&currency_record(
+                InputRecordType::Deposit,
+                4,
+                Some(dec!(1.0)),
+                Some("GBP"),
+            ))
+            .unwrap();
+        assert!(matches!(
+            account.valuation(&rates),
+            Err(ProcessingError::RateMissing(_))
         ));
     }
 }
@@ -0,0 +1,208 @@
+use std::{fmt::Debug, sync::Arc};
+
+use clap::ValueEnum;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::account_manager::account::{ProcessingError, TransactionType};
+
+/// Decides how disputes of withdrawals move funds. Withdrawn money already left the account,
+/// so partners differ in whether and when client gets it back during a dispute.
+/// Funds moved by the dispute are kept as [`DisputedFunds`], resolve and chargeback revert them
+/// without asking the policy again.
+pub trait DisputePolicy: Debug + Send + Sync {
+    /// Withdrawal of positive `amount` gets disputed, returns new `(available, held)` balances
+    fn dispute_withdrawal(
+        &self,
+        amount: Decimal,
+        available: Decimal,
+        held: Decimal,
+    ) -> Result<(Decimal, Decimal), ProcessingError>;
+}
+
+/// Funds dispute of a transaction moved to available and held balances. They are kept with the
+/// transaction, so reverting it doesn't depend on policy configured later.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DisputedFunds {
+    pub available: Decimal,
+    pub held: Decimal,
+}
+
+impl DisputedFunds {
+    /// Funds moved by dispute of transaction of signed `amount` (negative for withdrawals),
+    /// how withdrawals are disputed is up to `policy`
+    pub fn of(
+        r#type: TransactionType,
+        amount: Decimal,
+        available: Decimal,
+        held: Decimal,
+        policy: &dyn DisputePolicy,
+    ) -> Result<Self, ProcessingError> {
+        let (new_available, new_held) = match r#type {
+            TransactionType::Deposit => shift(available, held, -amount, amount)?,
+            TransactionType::Withdrawal => policy.dispute_withdrawal(-amount, available, held)?,
+            r#type => return Err(ProcessingError::TransactionNotDisputable(r#type)),
+        };
+
+        Ok(Self {
+            available: new_available
+                .checked_sub(available)
+                .ok_or(ProcessingError::DecimalOverflow)?,
+            held: new_held
+                .checked_sub(held)
+                .ok_or(ProcessingError::DecimalOverflow)?,
+        })
+    }
+
+    /// Balances once dispute is opened
+    pub fn dispute(
+        self,
+        available: Decimal,
+        held: Decimal,
+    ) -> Result<(Decimal, Decimal), ProcessingError> {
+        shift(available, held, self.available, self.held)
+    }
+
+    /// Balances once transaction of signed `amount` is reverted by resolve or chargeback,
+    /// dispute is undone and the amount is taken back
+    pub fn revert(
+        self,
+        amount: Decimal,
+        available: Decimal,
+        held: Decimal,
+    ) -> Result<(Decimal, Decimal), ProcessingError> {
+        let (available, held) = shift(available, held, -self.available, -self.held)?;
+        shift(available, held, -amount, Decimal::ZERO)
+    }
+
+    /// Balances once reverted transaction is disputed again, undoes [`DisputedFunds::revert`]
+    pub fn redispute(
+        self,
+        amount: Decimal,
+        available: Decimal,
+        held: Decimal,
+    ) -> Result<(Decimal, Decimal), ProcessingError> {
+        let (available, held) = shift(available, held, amount, Decimal::ZERO)?;
+        self.dispute(available, held)
+    }
+}
+
+fn shift(
+    available: Decimal,
+    held: Decimal,
+    by_available: Decimal,
+    by_held: Decimal,
+) -> Result<(Decimal, Decimal), ProcessingError> {
+    Ok((
+        available
+            .checked_add(by_available)
+            .ok_or(ProcessingError::DecimalOverflow)?,
+        held.checked_add(by_held)
+            .ok_or(ProcessingError::DecimalOverflow)?,
+    ))
+}
+
+/// Disputes go through at most three cycles: dispute, second presentment and arbitration
+pub const MAX_DISPUTE_CYCLES: u8 = 3;
+
+/// Dispute configuration shared by all accounts
+#[derive(Clone, Debug)]
+pub struct DisputeRules {
+    pub policy: Arc<dyn DisputePolicy>,
+    /// How many times transaction can be disputed, resolved transaction can be disputed again
+    /// until the limit is reached. Between `1` and [`MAX_DISPUTE_CYCLES`].
+    pub max_cycles: u8,
+    /// Seconds after transaction it can be disputed in
+    pub dispute_window: Option<u64>,
+    /// Seconds after start of dispute cycle it can be charged back in, late resolves are accepted
+    pub resolution_deadline: Option<u64>,
+    /// Resolve disputes past resolution deadline, once a record with later timestamp arrives
+    pub auto_resolve: bool,
+}
+
+impl Default for DisputeRules {
+    fn default() -> Self {
+        Self {
+            policy: Arc::new(HoldWithdrawals),
+            max_cycles: 1,
+            dispute_window: None,
+            resolution_deadline: None,
+            auto_resolve: false,
+        }
+    }
+}
+
+/// Disputed amount is held, it becomes available once withdrawal is reverted
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HoldWithdrawals;
+
+impl DisputePolicy for HoldWithdrawals {
+    fn dispute_withdrawal(
+        &self,
+        amount: Decimal,
+        available: Decimal,
+        held: Decimal,
+    ) -> Result<(Decimal, Decimal), ProcessingError> {
+        let new_held = held
+            .checked_add(amount)
+            .ok_or(ProcessingError::DecimalOverflow)?;
+        Ok((available, new_held))
+    }
+}
+
+/// Withdrawals can't be disputed at all
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DisallowWithdrawalDisputes;
+
+impl DisputePolicy for DisallowWithdrawalDisputes {
+    fn dispute_withdrawal(
+        &self,
+        _amount: Decimal,
+        _available: Decimal,
+        _held: Decimal,
+    ) -> Result<(Decimal, Decimal), ProcessingError> {
+        Err(ProcessingError::TransactionNotDisputable(
+            TransactionType::Withdrawal,
+        ))
+    }
+}
+
+/// Disputed amount is credited back to available right away, revert moves no more funds
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProvisionalCredit;
+
+impl DisputePolicy for ProvisionalCredit {
+    fn dispute_withdrawal(
+        &self,
+        amount: Decimal,
+        available: Decimal,
+        held: Decimal,
+    ) -> Result<(Decimal, Decimal), ProcessingError> {
+        let new_available = available
+            .checked_add(amount)
+            .ok_or(ProcessingError::DecimalOverflow)?;
+        Ok((new_available, held))
+    }
+}
+
+/// Built-in policies, selectable from configuration
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum DisputePolicyKind {
+    /// Hold disputed amount until withdrawal is reverted
+    #[default]
+    Hold,
+    /// Reject disputes of withdrawals
+    Disallow,
+    /// Credit disputed amount back to available funds right away
+    ProvisionalCredit,
+}
+
+impl DisputePolicyKind {
+    pub fn policy(self) -> Arc<dyn DisputePolicy> {
+        match self {
+            Self::Hold => Arc::new(HoldWithdrawals),
+            Self::Disallow => Arc::new(DisallowWithdrawalDisputes),
+            Self::ProvisionalCredit => Arc::new(ProvisionalCredit),
+        }
+    }
+}
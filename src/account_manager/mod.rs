@@ -1,34 +1,1405 @@
-use std::collections::HashMap;
+use std::{
+    cmp::Reverse,
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
+    convert::Infallible,
+    sync::Arc,
+};
+
+use clap::ValueEnum;
+use rust_decimal::Decimal;
 
 use crate::{
-    account_manager::account::Account,
-    model::{ClientId, InputRecord, OutputRecord},
+    account_manager::{
+        account::{Account, AccountChange, ProcessingError},
+        dispute_policy::{DisputePolicy, DisputeRules},
+    },
+    credit::CreditLimits,
+    events::EventSink,
+    fees::{FeeRevenue, FeeSchedule},
+    model::{
+        ClientId, InputRecord, InputRecordType, OutputRecord, Timestamp, TransactionId,
+        TransactionRecord,
+    },
+    rates::ExchangeRates,
+    snapshot::{Snapshot, SnapshotError},
 };
 
 pub mod account;
+pub mod dispute_policy;
+pub mod sharded;
+
+/// Order of records returned by [`AccountManager::gather_output_ordered`]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum OutputOrder {
+    /// Ascending by client id
+    #[default]
+    Client,
+    /// Descending by total funds, ties ordered by client id
+    TotalDesc,
+    /// In order of first appearance of client
+    Insertion,
+}
 
+#[derive(Debug)]
 pub struct AccountManager {
-    accounts: HashMap<ClientId, Account>,
+    // BTreeMap keeps output deterministic, ordered by client id
+    accounts: BTreeMap<ClientId, Account>,
+    // Clients in order of first appearance
+    insertion_order: Vec<ClientId>,
+    // Global index of transactions, ids have to be unique across all clients
+    transaction_owners: HashMap<TransactionId, ClientId>,
+    // Disputes with known start time, ordered by it, so overdue ones can be auto-resolved
+    open_disputes: BTreeSet<(Timestamp, TransactionId)>,
+    dispute_rules: DisputeRules,
+    // Needed only by conversions
+    exchange_rates: Option<Arc<ExchangeRates>>,
+    fee_schedule: Option<Arc<FeeSchedule>>,
+    credit_limits: Option<Arc<CreditLimits>>,
+    // Allowed to submit administrative records, nobody by default
+    operators: Arc<HashSet<String>>,
+}
+
+impl Default for AccountManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AccountManager {
+    /// Accounts with default [`DisputeRules`], see [`AccountManager::with_dispute_rules`]
     pub fn new() -> Self {
         Self {
-            accounts: HashMap::new(),
+            accounts: BTreeMap::new(),
+            insertion_order: Vec::new(),
+            transaction_owners: HashMap::new(),
+            open_disputes: BTreeSet::new(),
+            dispute_rules: DisputeRules::default(),
+            exchange_rates: None,
+            fee_schedule: None,
+            credit_limits: None,
+            operators: Arc::default(),
+        }
+    }
+
+    /// Rules aren't a part of snapshot, funds moved by disputes are, so open disputes are reverted
+    /// the same way under any policy
+    pub fn with_dispute_rules(mut self, dispute_rules: DisputeRules) -> Self {
+        self.dispute_rules = dispute_rules;
+        self
+    }
+
+    /// Replaces just the policy of current rules
+    pub fn with_dispute_policy(mut self, dispute_policy: Arc<dyn DisputePolicy>) -> Self {
+        self.dispute_rules.policy = dispute_policy;
+        self
+    }
+
+    pub fn dispute_rules(&self) -> &DisputeRules {
+        &self.dispute_rules
+    }
+
+    /// Rates used by conversions, without them every conversion is rejected
+    pub fn with_exchange_rates(mut self, exchange_rates: Arc<ExchangeRates>) -> Self {
+        self.exchange_rates = Some(exchange_rates);
+        self
+    }
+
+    pub fn exchange_rates(&self) -> Option<&Arc<ExchangeRates>> {
+        self.exchange_rates.as_ref()
+    }
+
+    /// Fees charged by records, without schedule nothing is charged.
+    /// Fees already charged are kept in snapshot, schedule itself isn't.
+    pub fn with_fee_schedule(mut self, fee_schedule: Arc<FeeSchedule>) -> Self {
+        self.fee_schedule = Some(fee_schedule);
+        self
+    }
+
+    pub fn fee_schedule(&self) -> Option<&Arc<FeeSchedule>> {
+        self.fee_schedule.as_ref()
+    }
+
+    /// Credit lines debits can use, with them output shows credit limit and funds available to spend
+    pub fn with_credit_limits(mut self, credit_limits: Arc<CreditLimits>) -> Self {
+        self.credit_limits = Some(credit_limits);
+        self
+    }
+
+    pub fn credit_limits(&self) -> Option<&Arc<CreditLimits>> {
+        self.credit_limits.as_ref()
+    }
+
+    /// Operators allowed to submit administrative records, without them every such record is rejected
+    pub fn with_operators(mut self, operators: Arc<HashSet<String>>) -> Self {
+        self.operators = operators;
+        self
+    }
+
+    pub fn operators(&self) -> &Arc<HashSet<String>> {
+        &self.operators
+    }
+
+    /// Restores state saved with [`AccountManager::snapshot`]
+    pub fn from_snapshot(snapshot: Snapshot) -> Result<Self, SnapshotError> {
+        let mut account_manager = Self::new();
+        for account in snapshot.accounts {
+            let client_id = account.client_id();
+            for transaction_id in account.transaction_ids() {
+                if account_manager
+                    .transaction_owners
+                    .insert(transaction_id, client_id)
+                    .is_some()
+                {
+                    return Err(SnapshotError::DuplicateTransaction(transaction_id));
+                }
+            }
+
+            account_manager
+                .open_disputes
+                .extend(account.open_disputes());
+
+            match account_manager.accounts.entry(client_id) {
+                Entry::Occupied(_) => return Err(SnapshotError::DuplicateClient(client_id)),
+                Entry::Vacant(entry) => {
+                    entry.insert(account);
+                }
+            }
+            account_manager.insertion_order.push(client_id);
         }
+
+        Ok(account_manager)
+    }
+
+    /// Like [`AccountManager::snapshot`], but without cloning accounts
+    pub fn into_snapshot(mut self) -> Snapshot {
+        Snapshot::new(
+            self.insertion_order
+                .iter()
+                .filter_map(|client_id| self.accounts.remove(client_id))
+                .collect(),
+        )
+    }
+
+    /// Complete state of all accounts, including their transactions
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(
+            self.insertion_order
+                .iter()
+                .filter_map(|client_id| self.accounts.get(client_id))
+                .cloned()
+                .collect(),
+        )
     }
 
-    pub fn process_record(&mut self, record: &InputRecord) -> Result<(), account::ProcessingError> {
+    /// All accounts, ordered by client id
+    pub fn accounts(&self) -> impl Iterator<Item = &Account> + '_ {
+        self.accounts.values()
+    }
+
+    pub fn account(&self, client_id: ClientId) -> Option<&Account> {
+        self.accounts.get(&client_id)
+    }
+
+    /// Output of given client, one record per currency, `None` if client is unknown
+    pub fn account_output(&self, client_id: ClientId) -> Option<Vec<OutputRecord>> {
+        self.account(client_id)
+            .map(|account| self.with_credit(account.to_outputs()))
+    }
+
+    /// Transactions of given client with their state transitions, `None` if client is unknown
+    pub fn transactions(&self, client_id: ClientId) -> Option<Vec<TransactionRecord>> {
+        self.account(client_id).map(Account::transactions)
+    }
+
+    /// Client which owns given transaction
+    pub fn transaction_owner(&self, transaction_id: TransactionId) -> Option<ClientId> {
+        self.transaction_owners.get(&transaction_id).copied()
+    }
+
+    pub fn process_record(&mut self, record: &InputRecord) -> Result<(), ProcessingError> {
+        self.resolve_overdue(record, |_, _, _| Ok::<_, Infallible>(()))
+            .unwrap_or_else(|never| match never {});
+        let plan = self.plan_record(record)?;
+        self.apply_plan(record, plan);
+
+        Ok(())
+    }
+
+    /// Processes record, `before_apply` is called with its outcome before any account is mutated.
+    /// Error returned from `before_apply` cancels the record and leaves accounts untouched.
+    pub fn process_record_with<E>(
+        &mut self,
+        record: &InputRecord,
+        before_apply: impl FnOnce(Result<(), &ProcessingError>) -> Result<(), E>,
+    ) -> Result<Result<(), ProcessingError>, E> {
+        // Replaying the same records auto-resolves the same disputes, so they aren't passed along
+        self.resolve_overdue(record, |_, _, _| Ok::<_, Infallible>(()))
+            .unwrap_or_else(|never| match never {});
+        let planned = self.plan_record(record);
+        before_apply(planned.as_ref().map(|_| ()))?;
+
+        Ok(planned.map(|plan| self.apply_plan(record, plan)))
+    }
+
+    /// Processes record, events describing its effect are emitted to `sink` before any account is mutated.
+    /// Rejected records emit no events, error returned from `sink` cancels the record.
+    /// Disputes auto-resolved by the record emit their events first.
+    pub fn process_record_events<S: EventSink>(
+        &mut self,
+        record: &InputRecord,
+        sink: &mut S,
+    ) -> Result<Result<(), ProcessingError>, S::Error> {
+        self.resolve_overdue(record, |account, resolve, change| {
+            account
+                .change_events(resolve, change)
+                .iter()
+                .try_for_each(|event| sink.emit(event))
+        })?;
+
+        let plan = match self.plan_record(record) {
+            Ok(plan) => plan,
+            Err(error) => return Ok(Err(error)),
+        };
+
+        let mut events = match self.accounts.get(&record.client_id) {
+            Some(account) => account.change_events(record, &plan.change),
+            None => Account::new(record.client_id).change_events(record, &plan.change),
+        };
+        if let Some((client_id, change)) = &plan.destination {
+            if let Some(account) = self.accounts.get(client_id) {
+                events.extend(account.change_events(record, change));
+            }
+        }
+        events.iter().try_for_each(|event| sink.emit(event))?;
+        self.apply_plan(record, plan);
+
+        Ok(Ok(()))
+    }
+
+    /// Processes transfer to account kept by other manager, both accounts change or neither does.
+    /// Used by sharding, when transfer crosses shards.
+    pub(crate) fn process_transfer_to(
+        &mut self,
+        destination: &mut AccountManager,
+        record: &InputRecord,
+    ) -> Result<(), ProcessingError> {
+        for account_manager in [&mut *self, &mut *destination] {
+            account_manager
+                .resolve_overdue(record, |_, _, _| Ok::<_, Infallible>(()))
+                .unwrap_or_else(|never| match never {});
+        }
+        let change = self.plan_source(record)?;
+        let (client_id, credit) = destination.plan_destination(record)?;
+        self.apply_change(record, change);
+        destination.apply_destination(client_id, credit);
+
+        Ok(())
+    }
+
+    fn plan_record(&self, record: &InputRecord) -> Result<Plan, ProcessingError> {
+        let change = self.plan_source(record)?;
+        let destination = match record.r#type {
+            InputRecordType::Transfer => Some(self.plan_destination(record)?),
+            _ => None,
+        };
+
+        Ok(Plan {
+            change,
+            destination,
+        })
+    }
+
+    /// Change of account of the client which sent `record`
+    fn plan_source(&self, record: &InputRecord) -> Result<AccountChange, ProcessingError> {
+        self.check_admin_record(record)?;
+        self.check_transaction_owner(record)?;
+
+        let exchange_rates = self.exchange_rates.as_deref();
+        let fee_schedule = self.fee_schedule.as_deref();
+        let credit_limits = self.credit_limits.as_deref();
+        match self.accounts.get(&record.client_id) {
+            Some(account) => account.plan_record(
+                record,
+                &self.dispute_rules,
+                exchange_rates,
+                fee_schedule,
+                credit_limits,
+            ),
+            None => Account::new(record.client_id).plan_record(
+                record,
+                &self.dispute_rules,
+                exchange_rates,
+                fee_schedule,
+                credit_limits,
+            ),
+        }
+    }
+
+    /// Credit of transfer destination, it has to be an existing account
+    fn plan_destination(
+        &self,
+        record: &InputRecord,
+    ) -> Result<(ClientId, AccountChange), ProcessingError> {
+        let client_id = record
+            .to_client_id
+            .ok_or(ProcessingError::DestinationMissing)?;
         let account = self
             .accounts
-            .entry(record.client_id)
-            .or_insert_with(|| Account::new(record.client_id));
-        account.process_record(record)?;
+            .get(&client_id)
+            .ok_or(ProcessingError::DestinationAccountMissing(client_id))?;
+
+        Ok((client_id, account.plan_transfer_in(record)?))
+    }
+
+    /// Resolves disputes which are past resolution deadline at time of `record`,
+    /// `before_apply` is called with each of them before it's applied
+    fn resolve_overdue<E>(
+        &mut self,
+        record: &InputRecord,
+        mut before_apply: impl FnMut(&Account, &InputRecord, &AccountChange) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let (true, Some(deadline), Some(now)) = (
+            self.dispute_rules.auto_resolve,
+            self.dispute_rules.resolution_deadline,
+            record.timestamp,
+        ) else {
+            return Ok(());
+        };
+
+        while let Some(&(disputed_at, transaction_id)) = self.open_disputes.first() {
+            let resolve_at = disputed_at.saturating_add(deadline);
+            if now <= resolve_at {
+                break;
+            }
+
+            let Some(account) = self
+                .transaction_owner(transaction_id)
+                .and_then(|client_id| self.accounts.get(&client_id))
+            else {
+                self.open_disputes.pop_first();
+                continue;
+            };
+            let resolve = InputRecord {
+                timestamp: Some(resolve_at),
+                ..InputRecord::new(
+                    InputRecordType::Resolve,
+                    account.client_id(),
+                    transaction_id,
+                    None,
+                )
+            };
+            match account.plan_record(&resolve, &self.dispute_rules, None, None, None) {
+                Ok(change) => {
+                    before_apply(account, &resolve, &change)?;
+                    self.apply_change(&resolve, change);
+                }
+                // Disputes of locked accounts wait, they are queued again once account is unlocked
+                Err(_) => {
+                    self.open_disputes.pop_first();
+                }
+            }
+        }
 
         Ok(())
     }
 
+    fn apply_plan(&mut self, record: &InputRecord, plan: Plan) {
+        self.apply_change(record, plan.change);
+        if let Some((client_id, change)) = plan.destination {
+            self.apply_destination(client_id, change);
+        }
+    }
+
+    /// Credits transfer destination, its transaction id is owned by the sending client
+    fn apply_destination(&mut self, client_id: ClientId, change: AccountChange) {
+        if let Some(account) = self.accounts.get_mut(&client_id) {
+            account.apply_change(change);
+        }
+    }
+
+    fn apply_change(&mut self, record: &InputRecord, change: AccountChange) {
+        let insertion_order = &mut self.insertion_order;
+        let account = self.accounts.entry(record.client_id).or_insert_with(|| {
+            insertion_order.push(record.client_id);
+            Account::new(record.client_id)
+        });
+        if let Some(disputed_at) = account.disputed_at(record.transaction_id) {
+            self.open_disputes
+                .remove(&(disputed_at, record.transaction_id));
+        }
+        account.apply_change(change);
+        if let Some(disputed_at) = account.disputed_at(record.transaction_id) {
+            self.open_disputes
+                .insert((disputed_at, record.transaction_id));
+        }
+        if record.r#type == InputRecordType::Unlock {
+            self.open_disputes.extend(account.open_disputes());
+        }
+
+        if record.r#type.creates_transaction() {
+            self.transaction_owners
+                .insert(record.transaction_id, record.client_id);
+        }
+    }
+
+    /// Output of all accounts, ordered by client id and currency
     pub fn gather_output(&self) -> Vec<OutputRecord> {
-        self.accounts.values().map(Account::to_output).collect()
+        self.with_credit(
+            self.accounts
+                .values()
+                .flat_map(Account::to_outputs)
+                .collect(),
+        )
+    }
+
+    /// Fills in credit view of `output`, if credit limits are configured
+    fn with_credit(&self, mut output: Vec<OutputRecord>) -> Vec<OutputRecord> {
+        if let Some(credit_limits) = &self.credit_limits {
+            for record in &mut output {
+                let credit_limit = credit_limits.limit(record.client_id, record.currency);
+                record.credit_limit = Some(credit_limit);
+                record.available_to_spend = Some(record.available + credit_limit);
+            }
+        }
+        output
+    }
+
+    /// Every account valued in base currency of `exchange_rates`, ordered by client id
+    pub fn gather_valuation(
+        &self,
+        exchange_rates: &ExchangeRates,
+    ) -> Result<Vec<OutputRecord>, ProcessingError> {
+        self.accounts
+            .values()
+            .map(|account| account.valuation(exchange_rates))
+            .collect()
+    }
+
+    /// Fees charged from all accounts, ordered by type and currency
+    pub fn gather_fee_revenue(&self) -> Vec<FeeRevenue> {
+        let mut revenue = BTreeMap::new();
+        for (trigger, currency, amount) in self.accounts.values().flat_map(Account::fee_charges) {
+            let entry = revenue
+                .entry((trigger, currency))
+                .or_insert_with(|| FeeRevenue {
+                    r#type: trigger,
+                    currency,
+                    count: 0,
+                    amount: Decimal::ZERO,
+                });
+            entry.count += 1;
+            entry.amount += amount;
+        }
+
+        revenue.into_values().collect()
+    }
+
+    pub fn gather_output_ordered(&self, order: OutputOrder) -> Vec<OutputRecord> {
+        match order {
+            OutputOrder::Client => self.gather_output(),
+            OutputOrder::TotalDesc => {
+                let mut output = self.gather_output();
+                // Sort is stable, so equal totals stay ordered by client id
+                output.sort_by_key(|record| Reverse(record.total));
+                output
+            }
+            OutputOrder::Insertion => self.with_credit(
+                self.insertion_order
+                    .iter()
+                    .filter_map(|client_id| self.accounts.get(client_id))
+                    .flat_map(Account::to_outputs)
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Administrative records need an allowed operator, adjustments need a reason too
+    fn check_admin_record(&self, record: &InputRecord) -> Result<(), ProcessingError> {
+        if !record.r#type.is_admin() {
+            return Ok(());
+        }
+
+        match record.operator.as_deref() {
+            None | Some("") => return Err(ProcessingError::OperatorMissing),
+            Some(operator) if !self.operators.contains(operator) => {
+                return Err(ProcessingError::OperatorNotAuthorized(operator.to_string()))
+            }
+            Some(_) => {}
+        }
+        if record.r#type == InputRecordType::Adjust
+            && record.reason.as_deref().is_none_or(str::is_empty)
+        {
+            return Err(ProcessingError::ReasonMissing);
+        }
+
+        Ok(())
+    }
+
+    fn check_transaction_owner(&self, record: &InputRecord) -> Result<(), ProcessingError> {
+        match self.transaction_owners.get(&record.transaction_id) {
+            Some(&owner) => check_owner(record, owner),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Changes caused by a single record, transfers change two accounts
+struct Plan {
+    change: AccountChange,
+    destination: Option<(ClientId, AccountChange)>,
+}
+
+/// Checks record against owner of transaction it references, transaction ids are global
+pub(crate) fn check_owner(record: &InputRecord, owner: ClientId) -> Result<(), ProcessingError> {
+    match record.r#type {
+        r#type if r#type.creates_transaction() => Err(ProcessingError::TransactionAlreadyExists(
+            record.transaction_id,
+        )),
+        _ if owner != record.client_id => Err(ProcessingError::ClientMismatch(
+            record.transaction_id,
+            owner,
+            record.client_id,
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Arc};
+
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    use super::{AccountManager, OutputOrder};
+    use crate::{
+        account_manager::{
+            account::{ProcessingError, TransactionState, TransactionType},
+            dispute_policy::{DisputePolicyKind, DisputeRules},
+        },
+        credit::CreditLimits,
+        events::{Balances, EventKind},
+        fees::{FeeSchedule, FeeTrigger},
+        model::{ClientId, InputRecord, InputRecordType, OutputRecord, TransactionId},
+    };
+
+    #[test]
+    fn test_process_transaction_already_exists_other_client() {
+        let mut account_manager = AccountManager::new();
+        account_manager
+            .process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                1,
+                0,
+                Some(dec!(10.0)),
+            ))
+            .unwrap();
+
+        assert!(matches!(
+            account_manager.process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                2,
+                0,
+                Some(dec!(10.0))
+            )),
+            Err(ProcessingError::TransactionAlreadyExists(0))
+        ));
+        assert!(matches!(
+            account_manager.process_record(&InputRecord::new(
+                InputRecordType::Withdrawal,
+                2,
+                0,
+                Some(dec!(10.0))
+            )),
+            Err(ProcessingError::TransactionAlreadyExists(0))
+        ));
+    }
+
+    #[test]
+    fn test_process_failed_transaction_is_not_indexed() {
+        let mut account_manager = AccountManager::new();
+        assert!(matches!(
+            account_manager.process_record(&InputRecord::new(
+                InputRecordType::Withdrawal,
+                1,
+                0,
+                Some(dec!(10.0))
+            )),
+            Err(ProcessingError::WithdrawalNotEnoughMoneyAvailable(_, _))
+        ));
+
+        account_manager
+            .process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                2,
+                0,
+                Some(dec!(10.0)),
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_process_rejected_record_does_not_create_account() {
+        let mut account_manager = AccountManager::new();
+        assert!(matches!(
+            account_manager.process_record(&InputRecord::new(InputRecordType::Dispute, 1, 0, None)),
+            Err(ProcessingError::TransactionMissing(0))
+        ));
+
+        assert!(account_manager.gather_output().is_empty());
+    }
+
+    #[test]
+    fn test_process_record_with() {
+        let mut account_manager = AccountManager::new();
+        let deposit = InputRecord::new(InputRecordType::Deposit, 1, 0, Some(dec!(10.0)));
+
+        // Error from hook cancels the record
+        assert!(matches!(
+            account_manager.process_record_with(&deposit, |outcome| {
+                assert!(outcome.is_ok());
+                Err("cancelled")
+            }),
+            Err("cancelled")
+        ));
+        assert!(account_manager.gather_output().is_empty());
+
+        assert!(matches!(
+            account_manager.process_record_with(&deposit, |_| Ok::<(), ()>(())),
+            Ok(Ok(()))
+        ));
+        assert!(matches!(
+            account_manager.process_record_with(&deposit, |outcome| {
+                assert!(matches!(
+                    outcome,
+                    Err(ProcessingError::TransactionAlreadyExists(0))
+                ));
+                Ok::<(), ()>(())
+            }),
+            Ok(Err(ProcessingError::TransactionAlreadyExists(0)))
+        ));
+        assert_eq!(dec!(10.0), account_manager.gather_output()[0].available);
+    }
+
+    #[test]
+    fn test_process_dispute_client_mismatch() {
+        let mut account_manager = AccountManager::new();
+        account_manager
+            .process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                1,
+                0,
+                Some(dec!(10.0)),
+            ))
+            .unwrap();
+
+        for r#type in [
+            InputRecordType::Dispute,
+            InputRecordType::Resolve,
+            InputRecordType::Chargeback,
+        ] {
+            assert!(matches!(
+                account_manager.process_record(&InputRecord::new(r#type, 2, 0, None)),
+                Err(ProcessingError::ClientMismatch(0, 1, 2))
+            ));
+        }
+
+        account_manager
+            .process_record(&InputRecord::new(InputRecordType::Dispute, 1, 0, None))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_process_record_events() {
+        let mut account_manager = AccountManager::new();
+        let mut events = Vec::new();
+        for (r#type, transaction_id, amount) in [
+            (InputRecordType::Deposit, 0, Some(dec!(10.0))),
+            (InputRecordType::Withdrawal, 1, Some(dec!(20.0))),
+            (InputRecordType::Withdrawal, 1, Some(dec!(4.0))),
+            (InputRecordType::Dispute, 0, None),
+            (InputRecordType::Chargeback, 0, None),
+        ] {
+            let _ = account_manager
+                .process_record_events(
+                    &InputRecord::new(r#type, 1, transaction_id, amount),
+                    &mut events,
+                )
+                .unwrap();
+        }
+
+        assert_eq!(
+            vec![
+                EventKind::DepositApplied,
+                EventKind::WithdrawalApplied,
+                EventKind::FundsHeld,
+                EventKind::ChargebackApplied,
+                EventKind::AccountLocked,
+            ],
+            events.iter().map(|event| event.kind).collect::<Vec<_>>()
+        );
+        assert_eq!(dec!(10.0), events[3].amount);
+        assert_eq!(
+            Balances {
+                available: dec!(-4.0),
+                held: dec!(0.0),
+                locked: true,
+            },
+            events[4].after
+        );
+        // Events of a client are chained
+        for pair in events.windows(2) {
+            assert_eq!(pair[0].after, pair[1].before);
+        }
+        assert_eq!(
+            account_manager.account(1).unwrap().balances(),
+            events.last().unwrap().after
+        );
+    }
+
+    #[test]
+    fn test_process_withdrawal_dispute_policy() {
+        let balances = |account_manager: &AccountManager| {
+            let output = &account_manager.gather_output()[0];
+            (output.available, output.held)
+        };
+
+        for (kind, disputed) in [
+            (DisputePolicyKind::Hold, (dec!(6.0), dec!(4.0))),
+            (
+                DisputePolicyKind::ProvisionalCredit,
+                (dec!(10.0), dec!(0.0)),
+            ),
+        ] {
+            for revert in [InputRecordType::Resolve, InputRecordType::Chargeback] {
+                let mut account_manager = AccountManager::new().with_dispute_policy(kind.policy());
+                account_manager
+                    .process_record(&InputRecord::new(
+                        InputRecordType::Deposit,
+                        1,
+                        0,
+                        Some(dec!(10.0)),
+                    ))
+                    .unwrap();
+                account_manager
+                    .process_record(&InputRecord::new(
+                        InputRecordType::Withdrawal,
+                        1,
+                        1,
+                        Some(dec!(4.0)),
+                    ))
+                    .unwrap();
+
+                account_manager
+                    .process_record(&InputRecord::new(InputRecordType::Dispute, 1, 1, None))
+                    .unwrap();
+                assert_eq!(disputed, balances(&account_manager), "{kind:?}");
+                // Either way reverted withdrawal ends up back in available funds
+                account_manager
+                    .process_record(&InputRecord::new(revert, 1, 1, None))
+                    .unwrap();
+                assert_eq!((dec!(10.0), dec!(0.0)), balances(&account_manager));
+            }
+        }
+
+        let mut account_manager =
+            AccountManager::new().with_dispute_policy(DisputePolicyKind::Disallow.policy());
+        account_manager
+            .process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                1,
+                0,
+                Some(dec!(10.0)),
+            ))
+            .unwrap();
+        account_manager
+            .process_record(&InputRecord::new(
+                InputRecordType::Withdrawal,
+                1,
+                1,
+                Some(dec!(4.0)),
+            ))
+            .unwrap();
+        assert!(matches!(
+            account_manager.process_record(&InputRecord::new(InputRecordType::Dispute, 1, 1, None)),
+            Err(ProcessingError::TransactionNotDisputable(
+                TransactionType::Withdrawal
+            ))
+        ));
+        // Deposits are still disputable
+        account_manager
+            .process_record(&InputRecord::new(InputRecordType::Dispute, 1, 0, None))
+            .unwrap();
+        assert_eq!((dec!(-4.0), dec!(10.0)), balances(&account_manager));
+    }
+
+    #[test]
+    fn test_dispute_policy_changed_on_restore() {
+        let mut disputed = AccountManager::new()
+            .with_dispute_policy(DisputePolicyKind::ProvisionalCredit.policy());
+        disputed
+            .process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                1,
+                0,
+                Some(dec!(10.0)),
+            ))
+            .unwrap();
+        disputed
+            .process_record(&InputRecord::new(
+                InputRecordType::Withdrawal,
+                1,
+                1,
+                Some(dec!(4.0)),
+            ))
+            .unwrap();
+        disputed
+            .process_record(&InputRecord::new(InputRecordType::Dispute, 1, 1, None))
+            .unwrap();
+
+        let snapshot = serde_json::to_string(&disputed.snapshot()).unwrap();
+        // Funds moved by the dispute are reverted, whatever policy is configured now
+        for kind in [DisputePolicyKind::Hold, DisputePolicyKind::Disallow] {
+            for revert in [InputRecordType::Resolve, InputRecordType::Chargeback] {
+                let mut account_manager =
+                    AccountManager::from_snapshot(serde_json::from_str(&snapshot).unwrap())
+                        .unwrap()
+                        .with_dispute_rules(DisputeRules {
+                            policy: kind.policy(),
+                            max_cycles: 2,
+                            ..DisputeRules::default()
+                        });
+                account_manager
+                    .process_record(&InputRecord::new(revert, 1, 1, None))
+                    .unwrap();
+                let output = &account_manager.gather_output()[0];
+                assert_eq!(
+                    (dec!(10.0), dec!(0.0)),
+                    (output.available, output.held),
+                    "{kind:?}"
+                );
+
+                // Another cycle moves the same funds again
+                if revert == InputRecordType::Resolve {
+                    account_manager
+                        .process_record(&InputRecord::new(InputRecordType::Dispute, 1, 1, None))
+                        .unwrap();
+                    let output = &account_manager.gather_output()[0];
+                    assert_eq!((dec!(10.0), dec!(0.0)), (output.available, output.held));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_auto_resolve() {
+        let mut account_manager = AccountManager::new().with_dispute_rules(DisputeRules {
+            resolution_deadline: Some(10),
+            auto_resolve: true,
+            ..DisputeRules::default()
+        });
+        account_manager
+            .process_record(&InputRecord {
+                timestamp: Some(0),
+                ..InputRecord::new(InputRecordType::Deposit, 1, 0, Some(dec!(10.0)))
+            })
+            .unwrap();
+        account_manager
+            .process_record(&InputRecord {
+                timestamp: Some(5),
+                ..InputRecord::new(InputRecordType::Dispute, 1, 0, None)
+            })
+            .unwrap();
+        // Deadline is not exceeded yet
+        account_manager
+            .process_record(&InputRecord {
+                timestamp: Some(15),
+                ..InputRecord::new(InputRecordType::Deposit, 1, 1, Some(dec!(1.0)))
+            })
+            .unwrap();
+        assert_eq!(dec!(10.0), account_manager.gather_output()[0].held);
+
+        // Open disputes survive snapshot
+        let mut account_manager = AccountManager::from_snapshot(account_manager.snapshot())
+            .unwrap()
+            .with_dispute_rules(account_manager.dispute_rules().clone());
+        let mut events = Vec::new();
+        account_manager
+            .process_record_events(
+                &InputRecord {
+                    timestamp: Some(16),
+                    ..InputRecord::new(InputRecordType::Deposit, 1, 2, Some(dec!(2.0)))
+                },
+                &mut events,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            vec![EventKind::FundsReleased, EventKind::DepositApplied],
+            events.iter().map(|event| event.kind).collect::<Vec<_>>()
+        );
+        assert_eq!(0, events[0].transaction_id);
+        assert_eq!(
+            TransactionState::Resolved,
+            account_manager.transactions(1).unwrap()[0].state
+        );
+        assert_eq!(dec!(0.0), account_manager.gather_output()[0].held);
+
+        // Too late for manual chargeback
+        assert!(matches!(
+            account_manager.process_record(&InputRecord {
+                timestamp: Some(17),
+                ..InputRecord::new(InputRecordType::Chargeback, 1, 0, None)
+            }),
+            Err(ProcessingError::TransactionWrongState(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_auto_resolve_after_unlock() {
+        let mut account_manager = AccountManager::new()
+            .with_dispute_rules(DisputeRules {
+                resolution_deadline: Some(10),
+                auto_resolve: true,
+                ..DisputeRules::default()
+            })
+            .with_operators(operators(&["alice"]));
+        for (r#type, transaction_id, amount, timestamp) in [
+            (InputRecordType::Deposit, 0, Some(dec!(10.0)), 0),
+            (InputRecordType::Dispute, 0, None, 5),
+            (InputRecordType::Freeze, 1, None, 6),
+        ] {
+            account_manager
+                .process_record(&InputRecord {
+                    operator: Some("alice".to_string()),
+                    timestamp: Some(timestamp),
+                    ..InputRecord::new(r#type, 1, transaction_id, amount)
+                })
+                .unwrap();
+        }
+
+        // Overdue dispute of locked account is skipped
+        account_manager
+            .process_record(&InputRecord {
+                operator: Some("alice".to_string()),
+                timestamp: Some(20),
+                ..InputRecord::new(InputRecordType::Unlock, 1, 2, None)
+            })
+            .unwrap();
+        assert_eq!(dec!(10.0), account_manager.gather_output()[0].held);
+
+        // Unlock queued it again, next record resolves it
+        account_manager
+            .process_record(&InputRecord {
+                operator: Some("alice".to_string()),
+                timestamp: Some(21),
+                ..InputRecord::new(InputRecordType::Deposit, 1, 3, Some(dec!(1.0)))
+            })
+            .unwrap();
+        assert_eq!(
+            TransactionState::Resolved,
+            account_manager.transactions(1).unwrap()[0].state
+        );
+        assert_eq!(dec!(0.0), account_manager.gather_output()[0].held);
+    }
+
+    #[test]
+    fn test_operators() {
+        let mut account_manager =
+            manager_with_deposits(&[(1, dec!(10.0))]).with_operators(operators(&["alice"]));
+
+        for record in [
+            InputRecord::admin(InputRecordType::Adjust, 1, 1, Some(dec!(5.0)), "mallory"),
+            InputRecord::admin(InputRecordType::Freeze, 1, 1, None, "mallory"),
+        ] {
+            assert!(matches!(
+                account_manager.process_record(&record),
+                Err(ProcessingError::OperatorNotAuthorized(operator)) if operator == "mallory"
+            ));
+        }
+        for operator in [None, Some(String::new())] {
+            assert!(matches!(
+                account_manager.process_record(&InputRecord {
+                    operator,
+                    ..InputRecord::admin(InputRecordType::Unlock, 1, 1, None, "alice")
+                }),
+                Err(ProcessingError::OperatorMissing)
+            ));
+        }
+        for reason in [None, Some(String::new())] {
+            assert!(matches!(
+                account_manager.process_record(&InputRecord {
+                    reason,
+                    ..InputRecord::admin(InputRecordType::Adjust, 1, 1, Some(dec!(5.0)), "alice")
+                }),
+                Err(ProcessingError::ReasonMissing)
+            ));
+        }
+        assert_eq!(dec!(10.0), account_manager.gather_output()[0].available);
+
+        account_manager
+            .process_record(&InputRecord::admin(
+                InputRecordType::Adjust,
+                1,
+                1,
+                Some(dec!(5.0)),
+                "alice",
+            ))
+            .unwrap();
+        assert_eq!(dec!(15.0), account_manager.gather_output()[0].available);
+
+        // Operator of regular records is not checked
+        account_manager
+            .process_record(&InputRecord {
+                operator: Some("mallory".to_string()),
+                ..InputRecord::new(InputRecordType::Deposit, 1, 2, Some(dec!(1.0)))
+            })
+            .unwrap();
+
+        // Nobody is authorized by default
+        assert!(matches!(
+            AccountManager::new().process_record(&InputRecord::admin(
+                InputRecordType::Freeze,
+                1,
+                1,
+                None,
+                "alice"
+            )),
+            Err(ProcessingError::OperatorNotAuthorized(_))
+        ));
+    }
+
+    #[test]
+    fn test_process_transfer() {
+        let mut account_manager = manager_with_deposits(&[(1, dec!(10.0)), (2, dec!(5.0))])
+            .with_operators(operators(&["alice"]));
+        let available = |account_manager: &AccountManager, client_id| {
+            account_manager
+                .account(client_id)
+                .unwrap()
+                .balances()
+                .available
+        };
+
+        let mut events = Vec::new();
+        account_manager
+            .process_record_events(
+                &InputRecord {
+                    to_client_id: Some(2),
+                    ..InputRecord::new(InputRecordType::Transfer, 1, 2, Some(dec!(4.0)))
+                },
+                &mut events,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            vec![
+                (EventKind::TransferSent, 1),
+                (EventKind::TransferReceived, 2)
+            ],
+            events
+                .iter()
+                .map(|event| (event.kind, event.client_id))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(dec!(6.0), available(&account_manager, 1));
+        assert_eq!(dec!(9.0), available(&account_manager, 2));
+        assert_eq!(Some(1), account_manager.transaction_owner(2));
+        let received = &account_manager.transactions(2).unwrap()[1];
+        assert_eq!(TransactionType::TransferIn, received.r#type);
+        assert_eq!(Some(1), received.counterparty);
+
+        // Transfers can't be disputed, neither by sending nor by receiving client
+        assert!(matches!(
+            account_manager.process_record(&InputRecord::new(InputRecordType::Dispute, 1, 2, None)),
+            Err(ProcessingError::TransactionNotDisputable(
+                TransactionType::TransferOut
+            ))
+        ));
+        assert!(matches!(
+            account_manager.process_record(&InputRecord::new(InputRecordType::Dispute, 2, 2, None)),
+            Err(ProcessingError::ClientMismatch(2, 1, 2))
+        ));
+
+        for (transfer, expected) in [
+            (
+                InputRecord {
+                    to_client_id: Some(3),
+                    ..InputRecord::new(InputRecordType::Transfer, 1, 3, Some(dec!(1.0)))
+                },
+                ProcessingError::DestinationAccountMissing(3),
+            ),
+            (
+                InputRecord {
+                    to_client_id: Some(2),
+                    ..InputRecord::new(InputRecordType::Transfer, 1, 3, Some(dec!(7.0)))
+                },
+                ProcessingError::WithdrawalNotEnoughMoneyAvailable(dec!(6.0), dec!(7.0)),
+            ),
+            (
+                InputRecord {
+                    to_client_id: Some(1),
+                    ..InputRecord::new(InputRecordType::Transfer, 1, 3, Some(dec!(1.0)))
+                },
+                ProcessingError::TransferToSameClient,
+            ),
+            (
+                InputRecord::new(InputRecordType::Transfer, 1, 3, Some(dec!(1.0))),
+                ProcessingError::DestinationMissing,
+            ),
+            (
+                InputRecord {
+                    to_client_id: Some(1),
+                    ..InputRecord::new(InputRecordType::Transfer, 2, 2, Some(dec!(1.0)))
+                },
+                ProcessingError::TransactionAlreadyExists(2),
+            ),
+        ] {
+            assert_eq!(
+                expected.code(),
+                account_manager
+                    .process_record(&transfer)
+                    .unwrap_err()
+                    .code()
+            );
+        }
+
+        // Locked destination rejects the transfer, sending account stays untouched
+        account_manager
+            .process_record(&InputRecord::admin(
+                InputRecordType::Freeze,
+                2,
+                4,
+                None,
+                "alice",
+            ))
+            .unwrap();
+        assert!(matches!(
+            account_manager.process_record(&InputRecord {
+                to_client_id: Some(2),
+                ..InputRecord::new(InputRecordType::Transfer, 1, 5, Some(dec!(1.0)))
+            }),
+            Err(ProcessingError::DestinationAccountLocked(2))
+        ));
+        assert_eq!(dec!(6.0), available(&account_manager, 1));
+        assert_eq!(None, account_manager.transaction_owner(5));
+
+        // Incoming transfer doesn't make receiving client an owner of its id
+        let restored = AccountManager::from_snapshot(account_manager.snapshot()).unwrap();
+        assert_eq!(Some(1), restored.transaction_owner(2));
+    }
+
+    #[test]
+    fn test_fees() {
+        let fee_schedule = FeeSchedule::read(
+            "type,from,flat,percent\n\
+             withdrawal,0,1,\n\
+             withdrawal,1000,,1\n\
+             chargeback,0,15,\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let mut account_manager = AccountManager::new().with_fee_schedule(Arc::new(fee_schedule));
+        let mut events = Vec::new();
+        let mut process = |account_manager: &mut AccountManager, record| {
+            account_manager
+                .process_record_events(&record, &mut events)
+                .unwrap()
+        };
+
+        process(
+            &mut account_manager,
+            InputRecord::new(InputRecordType::Deposit, 1, 1, Some(dec!(100.0))),
+        )
+        .unwrap();
+        // Withdrawal has to cover its fee too
+        assert!(matches!(
+            process(
+                &mut account_manager,
+                InputRecord::new(InputRecordType::Withdrawal, 1, 3, Some(dec!(99.5)))
+            ),
+            Err(ProcessingError::WithdrawalNotEnoughMoneyAvailable(_, _))
+        ));
+        process(
+            &mut account_manager,
+            InputRecord::new(InputRecordType::Withdrawal, 1, 3, Some(dec!(50.0))),
+        )
+        .unwrap();
+        assert_eq!(
+            dec!(49.0),
+            account_manager.account(1).unwrap().balances().available
+        );
+
+        // Deposits are free, dispute holds exactly the deposited amount
+        process(
+            &mut account_manager,
+            InputRecord::new(InputRecordType::Dispute, 1, 1, None),
+        )
+        .unwrap();
+        assert_eq!(
+            Balances {
+                available: dec!(-51.0),
+                held: dec!(100.0),
+                locked: false,
+            },
+            account_manager.account(1).unwrap().balances()
+        );
+        process(
+            &mut account_manager,
+            InputRecord::new(InputRecordType::Chargeback, 1, 1, None),
+        )
+        .unwrap();
+        assert_eq!(
+            Balances {
+                available: dec!(-66.0),
+                held: dec!(0.0),
+                locked: true,
+            },
+            account_manager.account(1).unwrap().balances()
+        );
+
+        for pair in events.windows(2) {
+            assert_eq!(pair[0].after, pair[1].before);
+        }
+        assert_eq!(
+            2,
+            events
+                .iter()
+                .filter(|event| event.kind == EventKind::FeeCharged)
+                .count()
+        );
+
+        assert_eq!(
+            vec![
+                (1, TransactionType::Deposit, dec!(100.0)),
+                (1, TransactionType::Fee, dec!(15)),
+                (3, TransactionType::Withdrawal, dec!(50.0)),
+                (3, TransactionType::Fee, dec!(1)),
+            ],
+            account_manager
+                .transactions(1)
+                .unwrap()
+                .iter()
+                .map(|transaction| (
+                    transaction.transaction_id,
+                    transaction.r#type,
+                    transaction.amount
+                ))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![
+                (FeeTrigger::Withdrawal, 1, dec!(1)),
+                (FeeTrigger::Chargeback, 1, dec!(15)),
+            ],
+            account_manager
+                .gather_fee_revenue()
+                .iter()
+                .map(|revenue| (revenue.r#type, revenue.count, revenue.amount))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_credit_limits() {
+        let credit_limits = CreditLimits::read("client,limit\n1,100\n".as_bytes()).unwrap();
+        let mut account_manager = manager_with_deposits(&[(1, dec!(50)), (2, dec!(50))])
+            .with_credit_limits(Arc::new(credit_limits));
+        let mut withdraw = |client_id, transaction_id, amount| {
+            account_manager.process_record(&InputRecord::new(
+                InputRecordType::Withdrawal,
+                client_id,
+                transaction_id,
+                Some(amount),
+            ))
+        };
+
+        withdraw(1, 2, dec!(120)).unwrap();
+        assert!(matches!(
+            withdraw(1, 3, dec!(40)),
+            Err(ProcessingError::WithdrawalNotEnoughMoneyAvailable(available, _))
+                if available == dec!(30)
+        ));
+        withdraw(1, 4, dec!(30)).unwrap();
+        // Clients without credit line can't go below zero
+        assert!(matches!(
+            withdraw(2, 5, dec!(50.01)),
+            Err(ProcessingError::WithdrawalNotEnoughMoneyAvailable(_, _))
+        ));
+
+        assert_eq!(
+            vec![
+                (dec!(-100), Some(dec!(100)), Some(dec!(0))),
+                (dec!(50), Some(dec!(0)), Some(dec!(50))),
+            ],
+            account_manager
+                .gather_output()
+                .iter()
+                .map(|record| (
+                    record.available,
+                    record.credit_limit,
+                    record.available_to_spend
+                ))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(dec!(0)),
+            account_manager.account_output(1).unwrap()[0].available_to_spend
+        );
+    }
+
+    fn manager_with_deposits(deposits: &[(ClientId, Decimal)]) -> AccountManager {
+        let mut account_manager = AccountManager::new();
+        for (transaction_id, &(client_id, amount)) in deposits.iter().enumerate() {
+            account_manager
+                .process_record(&InputRecord::new(
+                    InputRecordType::Deposit,
+                    client_id,
+                    transaction_id as TransactionId,
+                    Some(amount),
+                ))
+                .unwrap();
+        }
+        account_manager
+    }
+
+    fn operators(operators: &[&str]) -> Arc<HashSet<String>> {
+        Arc::new(
+            operators
+                .iter()
+                .map(|operator| operator.to_string())
+                .collect(),
+        )
+    }
+
+    fn client_ids(output: &[OutputRecord]) -> Vec<ClientId> {
+        output.iter().map(|record| record.client_id).collect()
+    }
+
+    #[test]
+    fn test_gather_output_ordered() {
+        let account_manager = manager_with_deposits(&[
+            (3, dec!(5.0)),
+            (1, dec!(10.0)),
+            (2, dec!(20.0)),
+            (4, dec!(10.0)),
+        ]);
+
+        assert_eq!(
+            vec![1, 2, 3, 4],
+            client_ids(&account_manager.gather_output())
+        );
+        assert_eq!(
+            vec![1, 2, 3, 4],
+            client_ids(&account_manager.gather_output_ordered(OutputOrder::Client))
+        );
+        assert_eq!(
+            vec![2, 1, 4, 3],
+            client_ids(&account_manager.gather_output_ordered(OutputOrder::TotalDesc))
+        );
+        assert_eq!(
+            vec![3, 1, 2, 4],
+            client_ids(&account_manager.gather_output_ordered(OutputOrder::Insertion))
+        );
     }
 }
@@ -0,0 +1,595 @@
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Mutex, PoisonError,
+    },
+    thread::JoinHandle,
+};
+
+use thiserror::Error;
+
+use crate::{
+    account_manager::{
+        account::ProcessingError, check_owner, dispute_policy::DisputeRules, AccountManager,
+    },
+    credit::CreditLimits,
+    fees::FeeSchedule,
+    model::{ClientId, InputRecord, InputRecordType, TransactionId},
+    rates::ExchangeRates,
+    snapshot::{Snapshot, SnapshotError},
+};
+
+/// Shards are tracked in a `u64` bitmask
+pub const MAX_WORKERS: usize = 64;
+
+/// Records are sent to workers in batches, sending them one by one costs more than processing
+const BATCH_SIZE: usize = 1024;
+/// Number of batches which can wait for a worker, before dispatching blocks
+const CHANNEL_CAPACITY: usize = 16;
+
+/// Record rejected by [`ShardedAccountManager`], along with context it was submitted with
+#[derive(Debug)]
+pub struct Rejected<C> {
+    pub context: C,
+    pub error: ProcessingError,
+}
+
+#[derive(Debug, Error)]
+pub enum ShardedError {
+    #[error("Number of workers has to be between 1 and {MAX_WORKERS}, got: `{0}`")]
+    InvalidWorkers(usize),
+    /// Workers see only timestamps of their own records, so disputes would be resolved at different times
+    #[error("Auto-resolve of overdue disputes is not supported with multiple workers")]
+    AutoResolveUnsupported,
+    #[error("IO error: `{0}`")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Snapshot(#[from] SnapshotError),
+    #[error("Worker thread exited unexpectedly")]
+    WorkerExited,
+}
+
+/// Processes records on multiple threads, accounts are partitioned by client id,
+/// each worker owns a shard of them. Records of a client are always processed in input order,
+/// outcome is the same as of sequential [`AccountManager`].
+///
+/// Transaction ids are global, every worker only knows transactions of its own shard.
+/// Dispatcher remembers which shards tried to create a transaction, when a record references
+/// a transaction touched by other shard, it waits for that shard to catch up and checks its
+/// owner. With unique transaction ids this never happens.
+///
+/// Transfers between clients of different shards are processed by dispatcher itself,
+/// once both shards catch up.
+pub struct ShardedAccountManager<C> {
+    shards: Vec<Shard<C>>,
+    position: u64,
+    /// Bitmask of shards which tried to create given transaction
+    transaction_shards: HashMap<TransactionId, u64>,
+    /// Clients in order of first appearance, before processing started
+    initial_order: Vec<ClientId>,
+    rejected: Vec<(u64, Rejected<C>)>,
+    /// Positions of records processed by dispatcher which created accounts
+    created: Vec<(u64, ClientId)>,
+    settings: Settings,
+}
+
+/// Settings aren't a part of snapshot, so they are carried over to shards separately
+struct Settings {
+    dispute_rules: DisputeRules,
+    exchange_rates: Option<Arc<ExchangeRates>>,
+    fee_schedule: Option<Arc<FeeSchedule>>,
+    credit_limits: Option<Arc<CreditLimits>>,
+    operators: Arc<HashSet<String>>,
+}
+
+struct Shard<C> {
+    sender: SyncSender<Message<C>>,
+    batch: Vec<Job<C>>,
+    account_manager: Arc<Mutex<AccountManager>>,
+    handle: JoinHandle<WorkerResult<C>>,
+}
+
+struct Job<C> {
+    position: u64,
+    record: InputRecord,
+    context: C,
+}
+
+enum Message<C> {
+    Batch(Vec<Job<C>>),
+    /// Acknowledged once all previously sent records are processed
+    Sync(SyncSender<()>),
+}
+
+struct WorkerResult<C> {
+    /// Positions of records which created accounts
+    created: Vec<(u64, ClientId)>,
+    rejected: Vec<(u64, Rejected<C>)>,
+}
+
+impl<C: Send + 'static> ShardedAccountManager<C> {
+    /// Splits state of `account_manager` between `workers` threads, its rules can't auto-resolve disputes
+    pub fn new(account_manager: AccountManager, workers: usize) -> Result<Self, ShardedError> {
+        if !(1..=MAX_WORKERS).contains(&workers) {
+            return Err(ShardedError::InvalidWorkers(workers));
+        }
+        if account_manager.dispute_rules().auto_resolve {
+            return Err(ShardedError::AutoResolveUnsupported);
+        }
+
+        let settings = Settings::of(&account_manager);
+        let snapshot = account_manager.into_snapshot();
+        let initial_order = snapshot
+            .accounts
+            .iter()
+            .map(|account| account.client_id())
+            .collect();
+
+        let mut transaction_shards = HashMap::new();
+        let mut partitions = (0..workers).map(|_| Vec::new()).collect::<Vec<_>>();
+        for account in snapshot.accounts {
+            let index = shard_index(account.client_id(), workers);
+            transaction_shards.extend(
+                account
+                    .transaction_ids()
+                    .map(|transaction_id| (transaction_id, 1 << index)),
+            );
+            partitions[index].push(account);
+        }
+
+        let shards = partitions
+            .into_iter()
+            .enumerate()
+            .map(|(index, accounts)| {
+                let account_manager = Arc::new(Mutex::new(
+                    settings.configure(AccountManager::from_snapshot(Snapshot::new(accounts))?),
+                ));
+                let (sender, receiver) = mpsc::sync_channel(CHANNEL_CAPACITY);
+                let worker_manager = Arc::clone(&account_manager);
+                let handle = std::thread::Builder::new()
+                    .name(format!("shard-{index}"))
+                    .spawn(move || run_worker(&worker_manager, &receiver))?;
+
+                Ok(Shard {
+                    sender,
+                    batch: Vec::with_capacity(BATCH_SIZE),
+                    account_manager,
+                    handle,
+                })
+            })
+            .collect::<Result<Vec<_>, ShardedError>>()?;
+
+        Ok(Self {
+            shards,
+            position: 0,
+            transaction_shards,
+            initial_order,
+            rejected: Vec::new(),
+            created: Vec::new(),
+            settings,
+        })
+    }
+
+    /// Queues record for processing, `context` is returned along with the error if it gets rejected
+    pub fn process_record(&mut self, record: InputRecord, context: C) -> Result<(), ShardedError> {
+        self.position += 1;
+        let index = shard_index(record.client_id, self.shards.len());
+        let shard_bit = 1 << index;
+
+        let other_shards = self
+            .transaction_shards
+            .get(&record.transaction_id)
+            .map_or(0, |shards| shards & !shard_bit);
+        if other_shards != 0 {
+            if let Err(error) = self.check_other_shards(other_shards, &record)? {
+                self.rejected
+                    .push((self.position, Rejected { context, error }));
+                return Ok(());
+            }
+        }
+
+        if record.r#type.creates_transaction() {
+            *self
+                .transaction_shards
+                .entry(record.transaction_id)
+                .or_default() |= shard_bit;
+        }
+
+        if let (InputRecordType::Transfer, Some(destination)) = (record.r#type, record.to_client_id)
+        {
+            let destination_index = shard_index(destination, self.shards.len());
+            if destination_index != index {
+                if let Err(error) =
+                    self.transfer_between_shards(index, destination_index, &record)?
+                {
+                    self.rejected
+                        .push((self.position, Rejected { context, error }));
+                }
+                return Ok(());
+            }
+        }
+
+        let shard = &mut self.shards[index];
+        shard.batch.push(Job {
+            position: self.position,
+            record,
+            context,
+        });
+        if shard.batch.len() >= BATCH_SIZE {
+            shard.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Waits for all workers, returns merged state and rejected records in submission order
+    pub fn finish(mut self) -> Result<(AccountManager, Vec<Rejected<C>>), ShardedError> {
+        let mut created = mem::take(&mut self.created);
+        let mut accounts = HashMap::new();
+        for mut shard in self.shards {
+            shard.flush()?;
+            drop(shard.sender);
+            let result = shard
+                .handle
+                .join()
+                .map_err(|_| ShardedError::WorkerExited)?;
+            created.extend(result.created);
+            self.rejected.extend(result.rejected);
+
+            let account_manager = Arc::try_unwrap(shard.account_manager)
+                .map_err(|_| ShardedError::WorkerExited)?
+                .into_inner()
+                .unwrap_or_else(PoisonError::into_inner);
+            accounts.extend(
+                account_manager
+                    .into_snapshot()
+                    .accounts
+                    .into_iter()
+                    .map(|account| (account.client_id(), account)),
+            );
+        }
+
+        created.sort_unstable();
+        let account_manager =
+            self.settings
+                .configure(AccountManager::from_snapshot(Snapshot::new(
+                    self.initial_order
+                        .into_iter()
+                        .chain(created.into_iter().map(|(_, client_id)| client_id))
+                        .filter_map(|client_id| accounts.remove(&client_id))
+                        .collect(),
+                ))?);
+
+        self.rejected
+            .sort_unstable_by_key(|(position, _)| *position);
+        Ok((
+            account_manager,
+            self.rejected
+                .into_iter()
+                .map(|(_, rejected)| rejected)
+                .collect(),
+        ))
+    }
+
+    /// Waits for given shards to catch up, then checks who owns record's transaction
+    fn check_other_shards(
+        &mut self,
+        shards: u64,
+        record: &InputRecord,
+    ) -> Result<Result<(), ProcessingError>, ShardedError> {
+        self.sync(shards)?;
+
+        let selected = |index: &usize| shards & (1 << index) != 0;
+        for (_, shard) in self.shards.iter().enumerate().filter(|(i, _)| selected(i)) {
+            let owner = shard
+                .account_manager
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .transaction_owner(record.transaction_id);
+            if let Some(owner) = owner {
+                return Ok(check_owner(record, owner));
+            }
+        }
+
+        Ok(Ok(()))
+    }
+
+    /// Processes transfer from client of `source` shard to client of `destination` shard,
+    /// after both of them process all records dispatched so far
+    fn transfer_between_shards(
+        &mut self,
+        source: usize,
+        destination: usize,
+        record: &InputRecord,
+    ) -> Result<Result<(), ProcessingError>, ShardedError> {
+        self.sync(1 << source | 1 << destination)?;
+
+        let mut source_manager = self.shards[source]
+            .account_manager
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut destination_manager = self.shards[destination]
+            .account_manager
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let is_new = source_manager.account(record.client_id).is_none();
+        let result = source_manager.process_transfer_to(&mut destination_manager, record);
+        if result.is_ok() && is_new {
+            self.created.push((self.position, record.client_id));
+        }
+
+        Ok(result)
+    }
+
+    /// Waits until given shards process all records dispatched so far, so their state is the same
+    /// as of sequential processing at this point
+    fn sync(&mut self, shards: u64) -> Result<(), ShardedError> {
+        let selected = |index: &usize| shards & (1 << index) != 0;
+
+        let mut acknowledgements = Vec::new();
+        for (_, shard) in self
+            .shards
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, _)| selected(i))
+        {
+            shard.flush()?;
+            let (sender, receiver) = mpsc::sync_channel(1);
+            shard
+                .sender
+                .send(Message::Sync(sender))
+                .map_err(|_| ShardedError::WorkerExited)?;
+            acknowledgements.push(receiver);
+        }
+        for receiver in acknowledgements {
+            receiver.recv().map_err(|_| ShardedError::WorkerExited)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<C> Shard<C> {
+    fn flush(&mut self) -> Result<(), ShardedError> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        let batch = mem::replace(&mut self.batch, Vec::with_capacity(BATCH_SIZE));
+        self.sender
+            .send(Message::Batch(batch))
+            .map_err(|_| ShardedError::WorkerExited)
+    }
+}
+
+impl Settings {
+    fn of(account_manager: &AccountManager) -> Self {
+        Self {
+            dispute_rules: account_manager.dispute_rules().clone(),
+            exchange_rates: account_manager.exchange_rates().cloned(),
+            fee_schedule: account_manager.fee_schedule().cloned(),
+            credit_limits: account_manager.credit_limits().cloned(),
+            operators: Arc::clone(account_manager.operators()),
+        }
+    }
+
+    fn configure(&self, account_manager: AccountManager) -> AccountManager {
+        let mut account_manager = account_manager
+            .with_dispute_rules(self.dispute_rules.clone())
+            .with_operators(Arc::clone(&self.operators));
+        if let Some(exchange_rates) = &self.exchange_rates {
+            account_manager = account_manager.with_exchange_rates(Arc::clone(exchange_rates));
+        }
+        if let Some(fee_schedule) = &self.fee_schedule {
+            account_manager = account_manager.with_fee_schedule(Arc::clone(fee_schedule));
+        }
+        if let Some(credit_limits) = &self.credit_limits {
+            account_manager = account_manager.with_credit_limits(Arc::clone(credit_limits));
+        }
+        account_manager
+    }
+}
+
+fn shard_index(client_id: ClientId, shards: usize) -> usize {
+    usize::from(client_id) % shards
+}
+
+fn run_worker<C>(
+    account_manager: &Mutex<AccountManager>,
+    receiver: &Receiver<Message<C>>,
+) -> WorkerResult<C> {
+    let mut result = WorkerResult {
+        created: Vec::new(),
+        rejected: Vec::new(),
+    };
+
+    for message in receiver {
+        match message {
+            Message::Batch(batch) => {
+                let mut account_manager = account_manager
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                for job in batch {
+                    let is_new = account_manager.account(job.record.client_id).is_none();
+                    match account_manager.process_record(&job.record) {
+                        Ok(()) if is_new => {
+                            result.created.push((job.position, job.record.client_id))
+                        }
+                        Ok(()) => {}
+                        Err(error) => result.rejected.push((
+                            job.position,
+                            Rejected {
+                                context: job.context,
+                                error,
+                            },
+                        )),
+                    }
+                }
+            }
+            Message::Sync(acknowledgement) => {
+                // Dispatcher waits for it, it can only be gone if it panicked
+                let _ = acknowledgement.send(());
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::{ShardedAccountManager, ShardedError};
+    use crate::{
+        account_manager::{
+            dispute_policy::{DisputePolicyKind, DisputeRules},
+            AccountManager, OutputOrder,
+        },
+        model::{InputRecord, InputRecordType},
+    };
+
+    /// Pseudo-random records, with transaction ids colliding between clients
+    /// and transfers crossing shards
+    fn generate_records(count: u32) -> Vec<InputRecord> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move |modulo: u64| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 33) % modulo
+        };
+
+        (0..count)
+            .map(|_| {
+                let r#type = match next(11) {
+                    0..=3 => InputRecordType::Deposit,
+                    4..=5 => InputRecordType::Withdrawal,
+                    6..=7 => InputRecordType::Dispute,
+                    8 => InputRecordType::Resolve,
+                    9 => InputRecordType::Chargeback,
+                    _ => InputRecordType::Transfer,
+                };
+                let amount = matches!(
+                    r#type,
+                    InputRecordType::Deposit
+                        | InputRecordType::Withdrawal
+                        | InputRecordType::Transfer
+                )
+                .then(|| Decimal::new(next(10_000) as i64 + 1, 2));
+                let to_client_id = (r#type == InputRecordType::Transfer).then(|| next(50) as u16);
+
+                InputRecord {
+                    to_client_id,
+                    ..InputRecord::new(
+                        r#type,
+                        next(50) as u16,
+                        next(u64::from(count) / 2) as u32,
+                        amount,
+                    )
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_same_outcome_as_sequential() {
+        let records = generate_records(20_000);
+
+        // Policy has to reach every worker
+        for kind in [
+            DisputePolicyKind::Hold,
+            DisputePolicyKind::Disallow,
+            DisputePolicyKind::ProvisionalCredit,
+        ] {
+            let mut sequential = AccountManager::new().with_dispute_policy(kind.policy());
+            let sequential_rejected = records
+                .iter()
+                .enumerate()
+                .filter_map(|(index, record)| {
+                    sequential
+                        .process_record(record)
+                        .err()
+                        .map(|error| (index, error.code()))
+                })
+                .collect::<Vec<_>>();
+
+            for workers in [1, 3, 8] {
+                let mut sharded = ShardedAccountManager::new(
+                    AccountManager::new().with_dispute_policy(kind.policy()),
+                    workers,
+                )
+                .unwrap();
+                for (index, record) in records.iter().enumerate() {
+                    sharded.process_record(record.clone(), index).unwrap();
+                }
+                let (account_manager, rejected) = sharded.finish().unwrap();
+
+                assert_eq!(
+                    sequential.gather_output_ordered(OutputOrder::Insertion),
+                    account_manager.gather_output_ordered(OutputOrder::Insertion)
+                );
+                assert_eq!(
+                    sequential_rejected,
+                    rejected
+                        .iter()
+                        .map(|rejected| (rejected.context, rejected.error.code()))
+                        .collect::<Vec<_>>()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_initial_state_is_kept() {
+        let records = generate_records(2_000);
+        let (first, second) = records.split_at(1_000);
+
+        let mut sequential = AccountManager::new();
+        for record in first {
+            let _ = sequential.process_record(record);
+        }
+        let initial = AccountManager::from_snapshot(sequential.snapshot()).unwrap();
+        for record in second {
+            let _ = sequential.process_record(record);
+        }
+
+        let mut sharded = ShardedAccountManager::new(initial, 4).unwrap();
+        for record in second {
+            sharded.process_record(record.clone(), ()).unwrap();
+        }
+        let (account_manager, _) = sharded.finish().unwrap();
+
+        assert_eq!(
+            sequential.gather_output_ordered(OutputOrder::Insertion),
+            account_manager.gather_output_ordered(OutputOrder::Insertion)
+        );
+    }
+
+    #[test]
+    fn test_invalid_workers() {
+        assert!(matches!(
+            ShardedAccountManager::<()>::new(AccountManager::new(), 0),
+            Err(ShardedError::InvalidWorkers(0))
+        ));
+        assert!(matches!(
+            ShardedAccountManager::<()>::new(AccountManager::new(), 65),
+            Err(ShardedError::InvalidWorkers(65))
+        ));
+    }
+
+    #[test]
+    fn test_auto_resolve_unsupported() {
+        let account_manager = AccountManager::new().with_dispute_rules(DisputeRules {
+            resolution_deadline: Some(10),
+            auto_resolve: true,
+            ..DisputeRules::default()
+        });
+        assert!(matches!(
+            ShardedAccountManager::<()>::new(account_manager, 2),
+            Err(ShardedError::AutoResolveUnsupported)
+        ));
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::Read,
+};
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::model::{ClientId, Currency};
+
+/// Approved credit lines of clients, available funds can go below zero down to minus the limit.
+/// Every currency has its own limit, clients without one have no credit.
+#[derive(Clone, Debug, Default)]
+pub struct CreditLimits {
+    limits: HashMap<(ClientId, Option<Currency>), Decimal>,
+}
+
+#[derive(Debug, Error)]
+pub enum CreditLimitsError {
+    #[error("CSV error: `{0}`")]
+    Csv(#[from] csv::Error),
+    #[error("Duplicate credit limit of client: `{0}`")]
+    DuplicateLimit(ClientId),
+    #[error("Credit limit can't be negative, client: `{0}`, limit: `{1}`")]
+    NegativeLimit(ClientId, Decimal),
+}
+
+impl CreditLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads CSV with header, `client,limit`, optionally followed by `currency`.
+    /// Rows without currency are limits of records without currency.
+    pub fn read(reader: impl Read) -> Result<Self, CreditLimitsError> {
+        #[derive(Deserialize)]
+        struct Row {
+            client: ClientId,
+            limit: Decimal,
+            #[serde(default)]
+            currency: Option<Currency>,
+        }
+
+        let mut limits = Self::new();
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        for row in reader.deserialize() {
+            let Row {
+                client,
+                limit,
+                currency,
+            } = row?;
+            limits.insert(client, currency, limit)?;
+        }
+
+        Ok(limits)
+    }
+
+    pub fn insert(
+        &mut self,
+        client_id: ClientId,
+        currency: Option<Currency>,
+        limit: Decimal,
+    ) -> Result<(), CreditLimitsError> {
+        if limit < Decimal::ZERO {
+            return Err(CreditLimitsError::NegativeLimit(client_id, limit));
+        }
+        match self.limits.entry((client_id, currency)) {
+            Entry::Occupied(_) => Err(CreditLimitsError::DuplicateLimit(client_id)),
+            Entry::Vacant(entry) => {
+                entry.insert(limit.normalize());
+                Ok(())
+            }
+        }
+    }
+
+    /// Limit of client in given currency, zero if there is none
+    pub fn limit(&self, client_id: ClientId, currency: Option<Currency>) -> Decimal {
+        self.limits
+            .get(&(client_id, currency))
+            .copied()
+            .unwrap_or(Decimal::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{CreditLimits, CreditLimitsError};
+
+    #[test]
+    fn test_read() {
+        let eur = Some("EUR".parse().unwrap());
+        let input = "client, limit, currency\n1, 100,\n1, 50, EUR\n2, 0,\n";
+        let limits = CreditLimits::read(input.as_bytes()).unwrap();
+        assert_eq!(dec!(100), limits.limit(1, None));
+        assert_eq!(dec!(50), limits.limit(1, eur));
+        assert_eq!(dec!(0), limits.limit(2, None));
+        assert_eq!(dec!(0), limits.limit(3, None));
+
+        // Currency column is optional
+        let limits = CreditLimits::read("client,limit\n1,10\n".as_bytes()).unwrap();
+        assert_eq!(dec!(10), limits.limit(1, None));
+
+        let read = |input: &str| CreditLimits::read(input.as_bytes());
+        assert!(matches!(
+            read("client,limit\n1,10\n1,20\n"),
+            Err(CreditLimitsError::DuplicateLimit(..))
+        ));
+        assert!(matches!(
+            read("client,limit\n1,-10\n"),
+            Err(CreditLimitsError::NegativeLimit(..))
+        ));
+        assert!(matches!(
+            read("client,limit\nx,10\n"),
+            Err(CreditLimitsError::Csv(..))
+        ));
+    }
+}
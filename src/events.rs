@@ -0,0 +1,160 @@
+use std::{
+    convert::Infallible,
+    io::{BufWriter, Write},
+};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    format::FormatError,
+    model::{ClientId, Currency, TransactionId},
+};
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    DepositApplied,
+    WithdrawalApplied,
+    /// Disputed transaction moved to held funds, or credited provisionally, see [`crate::DisputePolicy`]
+    FundsHeld,
+    /// Dispute resolved, held funds are available again
+    FundsReleased,
+    /// Held funds of disputed transaction were reversed
+    ChargebackApplied,
+    AccountLocked,
+    /// Administrative credit or debit
+    AdjustmentApplied,
+    /// Administrative lock
+    AccountFrozen,
+    AccountUnlocked,
+    /// Source leg of conversion
+    ConversionDebited,
+    /// Target leg of conversion, emitted right after its source leg
+    ConversionCredited,
+    /// Transfer left the account of sending client
+    TransferSent,
+    /// Transfer reached the account of receiving client, emitted right after its sending leg
+    TransferReceived,
+    /// Fee debited by a record, emitted right after the record's own event
+    FeeCharged,
+}
+
+/// Balances of an account right before or after an event
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Balances {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub locked: bool,
+}
+
+/// Single movement of funds or account status change. Events of a client and currency are chained,
+/// `before` of an event is always equal to `after` of the previous one
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Event {
+    #[serde(rename = "event")]
+    pub kind: EventKind,
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    #[serde(rename = "tx")]
+    pub transaction_id: TransactionId,
+    /// Amount of transaction which caused the event, zero for lock status changes,
+    /// negative for administrative debits
+    pub amount: Decimal,
+    pub before: Balances,
+    pub after: Balances,
+    /// Currency of balances, unset for records without currency
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    /// Set for administrative events
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operator: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Receives events of applied records, in order of processing
+pub trait EventSink {
+    type Error;
+
+    fn emit(&mut self, event: &Event) -> Result<(), Self::Error>;
+}
+
+impl EventSink for Vec<Event> {
+    type Error = Infallible;
+
+    fn emit(&mut self, event: &Event) -> Result<(), Self::Error> {
+        self.push(event.clone());
+        Ok(())
+    }
+}
+
+/// Writes events as JSON Lines
+pub struct EventWriter<W: Write> {
+    writer: BufWriter<W>,
+}
+
+impl<W: Write> EventWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: BufWriter::new(writer),
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<(), FormatError> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+impl<W: Write> EventSink for EventWriter<W> {
+    type Error = FormatError;
+
+    fn emit(&mut self, event: &Event) -> Result<(), Self::Error> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        self.writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{Balances, Event, EventKind, EventSink, EventWriter};
+
+    #[test]
+    fn test_event_writer() {
+        let balances = Balances {
+            available: dec!(0),
+            held: dec!(0),
+            locked: false,
+        };
+        let mut output = Vec::new();
+        let mut writer = EventWriter::new(&mut output);
+        writer
+            .emit(&Event {
+                kind: EventKind::DepositApplied,
+                client_id: 1,
+                transaction_id: 2,
+                amount: dec!(1.5),
+                before: balances,
+                after: Balances {
+                    available: dec!(1.5),
+                    ..balances
+                },
+                currency: None,
+                operator: None,
+                reason: None,
+            })
+            .unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        assert_eq!(
+            "{\"event\":\"deposit_applied\",\"client\":1,\"tx\":2,\"amount\":\"1.5\",\
+             \"before\":{\"available\":\"0\",\"held\":\"0\",\"locked\":false},\
+             \"after\":{\"available\":\"1.5\",\"held\":\"0\",\"locked\":false}}\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+}
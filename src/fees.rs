@@ -0,0 +1,209 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    io::Read,
+};
+
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use strum::Display;
+use thiserror::Error;
+
+use crate::{
+    account_manager::account::ProcessingError,
+    model::{Currency, InputRecordType, AMOUNT_PRECISION},
+};
+
+/// Records fees are charged on
+#[derive(Clone, Copy, Debug, Deserialize, Display, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum FeeTrigger {
+    /// Fee is debited along with withdrawn amount, both have to be available
+    Withdrawal,
+    /// Penalty debited on top of charged back amount
+    Chargeback,
+}
+
+impl FeeTrigger {
+    pub fn of(r#type: InputRecordType) -> Option<Self> {
+        match r#type {
+            InputRecordType::Withdrawal => Some(Self::Withdrawal),
+            InputRecordType::Chargeback => Some(Self::Chargeback),
+            _ => None,
+        }
+    }
+}
+
+/// Fee of amounts starting at `from`, it's `flat + amount * percent / 100`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FeeTier {
+    pub from: Decimal,
+    pub flat: Decimal,
+    pub percent: Decimal,
+}
+
+/// Fees of every trigger, a single tier starting at zero makes a flat or percentage fee,
+/// more of them make a tiered one. Tier with the highest `from` not above the amount is used.
+#[derive(Clone, Debug, Default)]
+pub struct FeeSchedule {
+    tiers: BTreeMap<FeeTrigger, BTreeMap<Decimal, FeeTier>>,
+}
+
+#[derive(Debug, Error)]
+pub enum FeesError {
+    #[error("CSV error: `{0}`")]
+    Csv(#[from] csv::Error),
+    #[error("Duplicate fee tier, type: `{0}`, from: `{1}`")]
+    DuplicateTier(FeeTrigger, Decimal),
+    #[error("Fee tier has to be non-negative with percent at most 100, type: `{0}`, from: `{1}`")]
+    InvalidTier(FeeTrigger, Decimal),
+}
+
+/// Fees collected from all accounts, per trigger and currency
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct FeeRevenue {
+    pub r#type: FeeTrigger,
+    /// Unset for fees of records without currency
+    pub currency: Option<Currency>,
+    pub count: u64,
+    pub amount: Decimal,
+}
+
+impl FeeSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads CSV with header, `type,from,flat,percent`. Empty `flat` or `percent` is zero.
+    pub fn read(reader: impl Read) -> Result<Self, FeesError> {
+        #[derive(Deserialize)]
+        struct Row {
+            r#type: FeeTrigger,
+            from: Decimal,
+            flat: Option<Decimal>,
+            percent: Option<Decimal>,
+        }
+
+        let mut schedule = Self::new();
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        for row in reader.deserialize() {
+            let row: Row = row?;
+            schedule.insert(
+                row.r#type,
+                FeeTier {
+                    from: row.from,
+                    flat: row.flat.unwrap_or_default(),
+                    percent: row.percent.unwrap_or_default(),
+                },
+            )?;
+        }
+
+        Ok(schedule)
+    }
+
+    pub fn insert(&mut self, trigger: FeeTrigger, tier: FeeTier) -> Result<(), FeesError> {
+        if tier.from < Decimal::ZERO
+            || tier.flat < Decimal::ZERO
+            || !(Decimal::ZERO..=Decimal::ONE_HUNDRED).contains(&tier.percent)
+        {
+            return Err(FeesError::InvalidTier(trigger, tier.from));
+        }
+        match self.tiers.entry(trigger).or_default().entry(tier.from) {
+            Entry::Occupied(_) => Err(FeesError::DuplicateTier(trigger, tier.from)),
+            Entry::Vacant(entry) => {
+                entry.insert(tier);
+                Ok(())
+            }
+        }
+    }
+
+    /// Fee of `amount`, rounded to [`AMOUNT_PRECISION`] with banker's rounding.
+    /// Zero if there is no tier for it.
+    pub fn fee(&self, trigger: FeeTrigger, amount: Decimal) -> Result<Decimal, ProcessingError> {
+        let Some(tier) = self
+            .tiers
+            .get(&trigger)
+            .and_then(|tiers| tiers.range(..=amount).next_back())
+            .map(|(_, tier)| tier)
+        else {
+            return Ok(Decimal::ZERO);
+        };
+
+        Ok(amount
+            .checked_mul(tier.percent)
+            .and_then(|value| value.checked_div(Decimal::ONE_HUNDRED))
+            .and_then(|value| value.checked_add(tier.flat))
+            .ok_or(ProcessingError::DecimalOverflow)?
+            .round_dp_with_strategy(AMOUNT_PRECISION, RoundingStrategy::MidpointNearestEven)
+            .normalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{FeeSchedule, FeeTrigger, FeesError};
+
+    #[test]
+    fn test_read() {
+        let input = "type, from, flat, percent\n\
+                     withdrawal, 0, 1,\n\
+                     withdrawal, 1000, , 0.5\n\
+                     chargeback, 0, 15, 1\n";
+        let schedule = FeeSchedule::read(input.as_bytes()).unwrap();
+        assert_eq!(
+            dec!(1),
+            schedule.fee(FeeTrigger::Withdrawal, dec!(999)).unwrap()
+        );
+        assert_eq!(
+            dec!(5),
+            schedule.fee(FeeTrigger::Withdrawal, dec!(1000)).unwrap()
+        );
+        assert_eq!(
+            dec!(16),
+            schedule.fee(FeeTrigger::Chargeback, dec!(100)).unwrap()
+        );
+
+        let read = |input: &str| FeeSchedule::read(input.as_bytes());
+        assert!(matches!(
+            read("type,from,flat,percent\nwithdrawal,0,1,\nwithdrawal,0,2,\n"),
+            Err(FeesError::DuplicateTier(..))
+        ));
+        assert!(matches!(
+            read("type,from,flat,percent\nwithdrawal,0,-1,\n"),
+            Err(FeesError::InvalidTier(..))
+        ));
+        assert!(matches!(
+            read("type,from,flat,percent\nwithdrawal,0,,101\n"),
+            Err(FeesError::InvalidTier(..))
+        ));
+        assert!(matches!(
+            read("type,from,flat,percent\ndispute,0,1,\n"),
+            Err(FeesError::Csv(..))
+        ));
+        // Deposits are free, disputes move the whole deposited amount
+        assert!(matches!(
+            read("type,from,flat,percent\ndeposit,0,1,\n"),
+            Err(FeesError::Csv(..))
+        ));
+    }
+
+    #[test]
+    fn test_fee() {
+        let input = "type,from,flat,percent\nwithdrawal,10,,0.25\n";
+        let schedule = FeeSchedule::read(input.as_bytes()).unwrap();
+        // Below the lowest tier
+        assert_eq!(
+            dec!(0),
+            schedule.fee(FeeTrigger::Withdrawal, dec!(9.99)).unwrap()
+        );
+        // 0.02505, ties to even
+        assert_eq!(
+            dec!(0.025),
+            schedule.fee(FeeTrigger::Withdrawal, dec!(10.02)).unwrap()
+        );
+    }
+}
@@ -0,0 +1,555 @@
+use std::{
+    fmt::{Display, Formatter},
+    io::{BufRead, BufReader, Lines, Read, Write},
+};
+
+use clap::ValueEnum;
+use csv::StringRecord;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    account_manager::account::{TransactionState, TransactionType},
+    fees::FeeRevenue,
+    model::{
+        ClientId, Currency, InputRecord, OutputRecord, Timestamp, TransactionId, TransactionRecord,
+    },
+};
+
+/// Error code of rows which can't be parsed
+pub const PARSE_ERROR_CODE: &str = "parse_error";
+/// Columns of CSV input
+const CSV_HEADERS: [&str; 10] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "operator",
+    "reason",
+    "timestamp",
+    "currency",
+    "to_currency",
+    "to_client",
+];
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum InputFormat {
+    /// CSV with header, `type,client,tx,amount`, optionally followed by
+    /// `operator,reason,timestamp,currency,to_currency,to_client`
+    #[default]
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// CSV with header, `client,available,held,total,locked`,
+    /// `currency` column follows client if any account holds currencies
+    #[default]
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+    /// Single JSON array
+    Json,
+}
+
+#[derive(Debug, Error)]
+pub enum FormatError {
+    #[error("CSV error: `{0}`")]
+    Csv(#[from] csv::Error),
+    #[error("IO error: `{0}`")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error: `{0}`")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Row as it was read from input, before trimming and parsing
+#[derive(Clone, Debug)]
+pub enum RawRow {
+    Csv(StringRecord),
+    Jsonl(String),
+}
+
+/// Single row read by [`RecordReader`], `record` holds an error if row could not be parsed
+#[derive(Debug)]
+pub struct ReadRecord {
+    /// Line number in input, starting from 1
+    pub line: u64,
+    pub raw: RawRow,
+    pub record: Result<InputRecord, FormatError>,
+}
+
+/// Streaming reader of [`InputRecord`]s in given [`InputFormat`].
+/// Outer error means that reading failed (e.g. IO error) and it makes no sense to continue.
+pub enum RecordReader<R: Read> {
+    Csv {
+        reader: csv::Reader<R>,
+        headers: Option<StringRecord>,
+    },
+    Jsonl {
+        lines: Lines<BufReader<R>>,
+        line: u64,
+    },
+}
+
+impl<R: Read> RecordReader<R> {
+    pub fn new(reader: R, format: InputFormat) -> Self {
+        match format {
+            InputFormat::Csv => Self::Csv {
+                reader: csv::ReaderBuilder::new().flexible(true).from_reader(reader),
+                headers: None,
+            },
+            InputFormat::Jsonl => Self::Jsonl {
+                lines: BufReader::new(reader).lines(),
+                line: 0,
+            },
+        }
+    }
+}
+
+impl<R: Read> Iterator for RecordReader<R> {
+    type Item = Result<ReadRecord, FormatError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Csv { reader, headers } => next_csv_record(reader, headers),
+            Self::Jsonl { lines, line } => loop {
+                let raw = match lines.next()? {
+                    Ok(raw) => raw,
+                    Err(error) => return Some(Err(error.into())),
+                };
+                *line += 1;
+                if raw.trim().is_empty() {
+                    continue;
+                }
+
+                return Some(Ok(ReadRecord {
+                    line: *line,
+                    record: serde_json::from_str(&raw).map_err(FormatError::from),
+                    raw: RawRow::Jsonl(raw),
+                }));
+            },
+        }
+    }
+}
+
+fn next_csv_record<R: Read>(
+    reader: &mut csv::Reader<R>,
+    headers: &mut Option<StringRecord>,
+) -> Option<Result<ReadRecord, FormatError>> {
+    let headers = match headers {
+        Some(headers) => headers,
+        None => match reader.headers() {
+            Ok(read_headers) => {
+                let mut read_headers = read_headers.clone();
+                read_headers.trim();
+                headers.insert(read_headers)
+            }
+            Err(error) => return Some(Err(error.into())),
+        },
+    };
+
+    let mut raw = StringRecord::new();
+    match reader.read_record(&mut raw) {
+        Ok(false) => None,
+        Ok(true) => {
+            let mut trimmed = raw.clone();
+            trimmed.trim();
+            Some(Ok(ReadRecord {
+                line: raw.position().map_or(0, csv::Position::line),
+                record: trimmed
+                    .deserialize(Some(headers))
+                    .map_err(FormatError::from),
+                raw: RawRow::Csv(raw),
+            }))
+        }
+        Err(error) if error.is_io_error() => Some(Err(error.into())),
+        Err(error) => Some(Ok(ReadRecord {
+            line: error.position().map_or(0, csv::Position::line),
+            raw: RawRow::Csv(StringRecord::new()),
+            record: Err(error.into()),
+        })),
+    }
+}
+
+/// Parses single CSV row without header, columns are
+/// `type,client,tx,amount[,operator,reason,timestamp,currency,to_currency,to_client]`
+pub fn parse_csv_row(row: &str) -> Result<InputRecord, FormatError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(row.as_bytes());
+    let mut record = StringRecord::new();
+    reader.read_record(&mut record)?;
+
+    Ok(record.deserialize(Some(&StringRecord::from(CSV_HEADERS.as_slice())))?)
+}
+
+/// Checks if CSV row is a header
+pub fn is_csv_header(row: &str) -> bool {
+    row.split(',')
+        .next()
+        .is_some_and(|column| column.trim() == CSV_HEADERS[0])
+}
+
+impl Display for RawRow {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Csv(record) => {
+                for (index, field) in record.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    if field.contains([',', '"', '\n', '\r']) {
+                        write!(f, "\"{}\"", field.replace('"', "\"\""))?;
+                    } else {
+                        write!(f, "{field}")?;
+                    }
+                }
+                Ok(())
+            }
+            Self::Jsonl(line) => write!(f, "{line}"),
+        }
+    }
+}
+
+pub fn write_output(
+    writer: impl Write,
+    format: OutputFormat,
+    records: &[OutputRecord],
+) -> Result<(), FormatError> {
+    match format {
+        OutputFormat::Csv if records.iter().any(|record| record.currency.is_some()) => {
+            let rows: Vec<_> = records.iter().map(OutputCsvRow::from).collect();
+            write_records(writer, format, &rows)
+        }
+        _ => write_records(writer, format, records),
+    }
+}
+
+/// Every CSV row needs the same columns, so currency column is kept even if it's empty
+#[derive(Serialize)]
+struct OutputCsvRow {
+    client: ClientId,
+    currency: Option<Currency>,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credit_limit: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    available_to_spend: Option<Decimal>,
+}
+
+impl From<&OutputRecord> for OutputCsvRow {
+    fn from(record: &OutputRecord) -> Self {
+        Self {
+            client: record.client_id,
+            currency: record.currency,
+            available: record.available,
+            held: record.held,
+            total: record.total,
+            locked: record.locked,
+            credit_limit: record.credit_limit,
+            available_to_spend: record.available_to_spend,
+        }
+    }
+}
+
+/// Writes transactions, in CSV transitions are joined with `>`,
+/// e.g. `valid>dispute>resolved`
+pub fn write_transactions(
+    writer: impl Write,
+    format: OutputFormat,
+    records: &[TransactionRecord],
+) -> Result<(), FormatError> {
+    match format {
+        OutputFormat::Csv => {
+            let rows: Vec<_> = records.iter().map(TransactionCsvRow::from).collect();
+            write_records(writer, format, &rows)
+        }
+        OutputFormat::Jsonl | OutputFormat::Json => write_records(writer, format, records),
+    }
+}
+
+/// CSV can't hold nested sequences, so transitions are flattened to a single column
+#[derive(Serialize)]
+struct TransactionCsvRow {
+    tx: TransactionId,
+    r#type: TransactionType,
+    amount: Decimal,
+    state: TransactionState,
+    transitions: String,
+    operator: Option<String>,
+    reason: Option<String>,
+    timestamp: Option<Timestamp>,
+    currency: Option<Currency>,
+    counterparty: Option<ClientId>,
+}
+
+impl From<&TransactionRecord> for TransactionCsvRow {
+    fn from(record: &TransactionRecord) -> Self {
+        Self {
+            tx: record.transaction_id,
+            r#type: record.r#type,
+            amount: record.amount,
+            state: record.state,
+            transitions: record
+                .transitions
+                .iter()
+                .map(|state| state.to_string())
+                .collect::<Vec<_>>()
+                .join(">"),
+            operator: record.operator.clone(),
+            reason: record.reason.clone(),
+            timestamp: record.timestamp,
+            currency: record.currency,
+            counterparty: record.counterparty,
+        }
+    }
+}
+
+/// Writes fee revenue report, one record per fee type and currency
+pub fn write_fee_revenue(
+    writer: impl Write,
+    format: OutputFormat,
+    revenue: &[FeeRevenue],
+) -> Result<(), FormatError> {
+    write_records(writer, format, revenue)
+}
+
+fn write_records<T: Serialize>(
+    writer: impl Write,
+    format: OutputFormat,
+    records: &[T],
+) -> Result<(), FormatError> {
+    match format {
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            records
+                .iter()
+                .try_for_each(|record| writer.serialize(record))?;
+            writer.flush()?;
+        }
+        OutputFormat::Jsonl => {
+            let mut writer = std::io::BufWriter::new(writer);
+            for record in records {
+                serde_json::to_writer(&mut writer, record)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+        OutputFormat::Json => {
+            let mut writer = std::io::BufWriter::new(writer);
+            serde_json::to_writer_pretty(&mut writer, records)?;
+            writer.write_all(b"\n")?;
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{
+        is_csv_header, parse_csv_row, write_output, write_transactions, InputFormat, OutputFormat,
+        ReadRecord, RecordReader,
+    };
+    use crate::{
+        account_manager::account::{TransactionState, TransactionType},
+        model::{InputRecord, InputRecordType, OutputRecord, TransactionRecord},
+    };
+
+    fn read_all(input: &str, format: InputFormat) -> Vec<ReadRecord> {
+        RecordReader::new(input.as_bytes(), format)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    fn parsed(records: Vec<ReadRecord>) -> Vec<InputRecord> {
+        records
+            .into_iter()
+            .map(|record| record.record.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_read_csv() {
+        let input = "type, client, tx, amount\ndeposit, 1, 2, 3.5\ndispute, 1, 2\n";
+        let records = parsed(read_all(input, InputFormat::Csv));
+
+        assert_eq!(2, records.len());
+        assert_eq!(InputRecordType::Deposit, records[0].r#type);
+        assert_eq!(Some(dec!(3.5)), records[0].amount);
+        assert_eq!(InputRecordType::Dispute, records[1].r#type);
+        assert_eq!(None, records[1].amount);
+    }
+
+    #[test]
+    fn test_read_csv_line_and_raw() {
+        let input = "type,client,tx,amount\ndeposit, 1, 2, 3.5\n\"dep,osit\",1,3,1\n";
+        let records = read_all(input, InputFormat::Csv);
+
+        assert_eq!(2, records[0].line);
+        assert_eq!("deposit, 1, 2, 3.5", records[0].raw.to_string());
+        assert!(records[0].record.is_ok());
+
+        assert_eq!(3, records[1].line);
+        assert_eq!("\"dep,osit\",1,3,1", records[1].raw.to_string());
+        assert!(records[1].record.is_err());
+    }
+
+    #[test]
+    fn test_parse_csv_row() {
+        let record = parse_csv_row(" withdrawal, 1, 2, 3.5 ").unwrap();
+        assert_eq!(InputRecordType::Withdrawal, record.r#type);
+        assert_eq!(1, record.client_id);
+        assert_eq!(2, record.transaction_id);
+        assert_eq!(Some(dec!(3.5)), record.amount);
+
+        assert_eq!(None, parse_csv_row("dispute,1,2").unwrap().amount);
+        assert_eq!(
+            Some(1_700_000_000),
+            parse_csv_row("dispute,1,2,,,,1700000000")
+                .unwrap()
+                .timestamp
+        );
+        assert_eq!(
+            Some("EUR".parse().unwrap()),
+            parse_csv_row("deposit,1,2,3.5,,,,eur").unwrap().currency
+        );
+        assert!(parse_csv_row("deposit,1,2,3.5,,,,EURO").is_err());
+        assert_eq!(
+            Some(7),
+            parse_csv_row("transfer,1,2,3.5,,,,,,7")
+                .unwrap()
+                .to_client_id
+        );
+        assert!(parse_csv_row("dispute,1").is_err());
+
+        assert!(is_csv_header("type, client, tx, amount"));
+        assert!(!is_csv_header("deposit,1,2,3"));
+    }
+
+    #[test]
+    fn test_read_jsonl() {
+        let input = concat!(
+            r#"{"type": "deposit", "client": 1, "tx": 2, "amount": "3.5"}"#,
+            "\n\n",
+            r#"{"type": "withdrawal", "client": 1, "tx": 3, "amount": 0.1}"#,
+            "\n",
+            r#"{"type": "dispute", "client": 1, "tx": 2}"#,
+            "\n",
+        );
+        let read = read_all(input, InputFormat::Jsonl);
+        assert_eq!(
+            vec![1, 3, 4],
+            read.iter().map(|r| r.line).collect::<Vec<_>>()
+        );
+
+        let records = parsed(read);
+        assert_eq!(3, records.len());
+        assert_eq!(Some(dec!(3.5)), records[0].amount);
+        assert_eq!(InputRecordType::Withdrawal, records[1].r#type);
+        assert_eq!(Some(dec!(0.1)), records[1].amount);
+        assert_eq!(2, records[2].transaction_id);
+        assert_eq!(None, records[2].amount);
+    }
+
+    #[test]
+    fn test_read_jsonl_malformed_line() {
+        let input = "{\"type\": \"deposit\"\n";
+        let records = read_all(input, InputFormat::Jsonl);
+
+        assert_eq!(1, records.len());
+        assert_eq!(input.trim_end(), records[0].raw.to_string());
+        assert!(records[0].record.is_err());
+    }
+
+    #[test]
+    fn test_write_output() {
+        let mut records = vec![OutputRecord {
+            client_id: 1,
+            currency: None,
+            available: dec!(1.5),
+            held: dec!(0),
+            total: dec!(1.5),
+            locked: false,
+            credit_limit: None,
+            available_to_spend: None,
+        }];
+        let write = |format| {
+            let mut output = Vec::new();
+            write_output(&mut output, format, &records).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n",
+            write(OutputFormat::Csv)
+        );
+        assert_eq!(
+            "{\"client\":1,\"available\":\"1.5\",\"held\":\"0\",\"total\":\"1.5\",\"locked\":false}\n",
+            write(OutputFormat::Jsonl)
+        );
+        assert!(write(OutputFormat::Json).starts_with('['));
+
+        records.push(OutputRecord {
+            currency: Some("EUR".parse().unwrap()),
+            ..records[0].clone()
+        });
+        let mut output = Vec::new();
+        write_output(&mut output, OutputFormat::Csv, &records).unwrap();
+        assert_eq!(
+            "client,currency,available,held,total,locked
+1,,1.5,0,1.5,false
+1,EUR,1.5,0,1.5,false
+",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_write_transactions() {
+        let records = [TransactionRecord {
+            transaction_id: 3,
+            r#type: TransactionType::Withdrawal,
+            amount: dec!(2.5),
+            state: TransactionState::Resolved,
+            transitions: vec![
+                TransactionState::Valid,
+                TransactionState::Dispute,
+                TransactionState::Resolved,
+            ],
+            operator: None,
+            reason: None,
+            timestamp: Some(1_700_000_000),
+            currency: Some("USD".parse().unwrap()),
+            counterparty: None,
+        }];
+        let write = |format| {
+            let mut output = Vec::new();
+            write_transactions(&mut output, format, &records).unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(
+            "tx,type,amount,state,transitions,operator,reason,timestamp,currency,counterparty\n\
+             3,withdrawal,2.5,resolved,valid>dispute>resolved,,,1700000000,USD,\n",
+            write(OutputFormat::Csv)
+        );
+        assert_eq!(
+            "{\"tx\":3,\"type\":\"withdrawal\",\"amount\":\"2.5\",\"state\":\"resolved\",\
+             \"transitions\":[\"valid\",\"dispute\",\"resolved\"],\"timestamp\":1700000000,\
+             \"currency\":\"USD\"}\n",
+            write(OutputFormat::Jsonl)
+        );
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Read, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    account_manager::{account::ProcessingError, AccountManager},
+    model::InputRecord,
+    snapshot::{Snapshot, SnapshotError},
+};
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Applied,
+    /// Record was rejected with given error code
+    Rejected(String),
+}
+
+/// Single line of journal
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JournalEntry {
+    Record {
+        /// Continues across runs, snapshot remembers last sequence it includes
+        sequence: u64,
+        /// Position of record in input of the run, starting from 1
+        position: u64,
+        record: InputRecord,
+        outcome: Outcome,
+    },
+    /// Run finished, there's nothing to resume
+    RunCompleted,
+}
+
+#[derive(Debug, Error)]
+pub enum JournalError {
+    #[error("IO error: `{0}`")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error: `{0}`")]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Snapshot(#[from] SnapshotError),
+
+    #[error("Corrupted journal entry at line: `{0}`, reason: `{1}`")]
+    Corrupted(u64, serde_json::Error),
+    #[error("Journal sequence gap, expected: `{0}`, actual: `{1}`")]
+    SequenceGap(u64, u64),
+    #[error("Replayed outcome differs from journal, sequence: `{0}`")]
+    OutcomeMismatch(u64),
+}
+
+/// Append-only log of records processed by `AccountManager`.
+/// Every entry is written before accounts are mutated, so state can be rebuilt with [`recover`].
+pub struct Journal<W: Write> {
+    writer: W,
+    last_sequence: u64,
+}
+
+/// State rebuilt from snapshot and journal
+#[derive(Debug)]
+pub struct Recovery {
+    pub account_manager: AccountManager,
+    /// Sequence of last applied entry, new entries continue from it
+    pub last_sequence: u64,
+    /// Input position of last record of interrupted run, `None` if there's nothing to resume
+    pub resume_position: Option<u64>,
+    /// Length of valid part of journal, anything past it is a torn entry
+    pub valid_length: u64,
+}
+
+impl From<Result<(), &ProcessingError>> for Outcome {
+    fn from(result: Result<(), &ProcessingError>) -> Self {
+        match result {
+            Ok(()) => Self::Applied,
+            Err(error) => Self::Rejected(error.code().to_string()),
+        }
+    }
+}
+
+impl Journal<File> {
+    /// Rebuilds state from snapshot and journal file, then opens the journal for appending.
+    /// Torn entry at the end of journal, left by a crash, is cut off.
+    pub fn recover_from_path(
+        path: &Path,
+        snapshot: Option<Snapshot>,
+        configure: impl FnOnce(AccountManager) -> AccountManager,
+    ) -> Result<(Recovery, Self), JournalError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let recovery = recover(snapshot, configure, &file)?;
+        file.set_len(recovery.valid_length)?;
+
+        let last_sequence = recovery.last_sequence;
+        Ok((recovery, Self::new(file, last_sequence)))
+    }
+
+    /// Removes all entries, to be called once they are included in a snapshot
+    pub fn clear(&mut self) -> Result<(), JournalError> {
+        self.writer.set_len(0)?;
+        self.writer.sync_all()?;
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Journal<W> {
+    pub fn new(writer: W, last_sequence: u64) -> Self {
+        Self {
+            writer,
+            last_sequence,
+        }
+    }
+
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    /// Processes record, journal entry with its outcome is written before accounts are mutated.
+    /// Outer error means that entry could not be written and record was not applied.
+    pub fn process_record(
+        &mut self,
+        account_manager: &mut AccountManager,
+        position: u64,
+        record: &InputRecord,
+    ) -> Result<Result<(), ProcessingError>, JournalError> {
+        let sequence = self.last_sequence + 1;
+        let result = account_manager.process_record_with(record, |outcome| {
+            self.append(&JournalEntry::Record {
+                sequence,
+                position,
+                record: record.clone(),
+                outcome: Outcome::from(outcome),
+            })
+        })?;
+        self.last_sequence = sequence;
+
+        Ok(result)
+    }
+
+    pub fn complete_run(&mut self) -> Result<(), JournalError> {
+        self.append(&JournalEntry::RunCompleted)
+    }
+
+    fn append(&mut self, entry: &JournalEntry) -> Result<(), JournalError> {
+        // Whole entry is written at once, so a crash can tear at most the last line
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Rebuilds `AccountManager` from snapshot (if any) and journal entries not included in it.
+/// Entries are replayed with rules set up by `configure`, they have to be the ones entries were journaled with.
+pub fn recover(
+    snapshot: Option<Snapshot>,
+    configure: impl FnOnce(AccountManager) -> AccountManager,
+    journal: impl Read,
+) -> Result<Recovery, JournalError> {
+    let (account_manager, mut last_sequence) = match snapshot {
+        Some(snapshot) => {
+            let journal_sequence = snapshot.journal_sequence;
+            (AccountManager::from_snapshot(snapshot)?, journal_sequence)
+        }
+        None => (AccountManager::new(), 0),
+    };
+    let mut account_manager = configure(account_manager);
+
+    let mut reader = BufReader::new(journal);
+    let mut resume_position = None;
+    let mut valid_length = 0;
+    let mut line_number = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let length = reader.read_line(&mut line)? as u64;
+        if length == 0 {
+            break;
+        }
+        line_number += 1;
+
+        // Entry without line ending is the one being written during a crash, it was never applied
+        if !line.ends_with('\n') {
+            break;
+        }
+        let entry = serde_json::from_str::<JournalEntry>(&line)
+            .map_err(|error| JournalError::Corrupted(line_number, error))?;
+        valid_length += length;
+
+        match entry {
+            JournalEntry::Record {
+                sequence,
+                position,
+                record,
+                outcome,
+            } => {
+                // Already included in snapshot
+                if sequence <= last_sequence {
+                    continue;
+                }
+                if sequence != last_sequence + 1 {
+                    return Err(JournalError::SequenceGap(last_sequence + 1, sequence));
+                }
+
+                let result = account_manager.process_record(&record);
+                if Outcome::from(result.as_ref().map(|_| ())) != outcome {
+                    return Err(JournalError::OutcomeMismatch(sequence));
+                }
+                last_sequence = sequence;
+                resume_position = Some(position);
+            }
+            JournalEntry::RunCompleted => resume_position = None,
+        }
+    }
+
+    Ok(Recovery {
+        account_manager,
+        last_sequence,
+        resume_position,
+        valid_length,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::identity;
+
+    use rust_decimal_macros::dec;
+
+    use super::{recover, Journal, JournalError};
+    use crate::{
+        account_manager::AccountManager,
+        model::{InputRecord, InputRecordType},
+    };
+
+    fn journal_of(records: &[InputRecord]) -> (AccountManager, Vec<u8>) {
+        let mut account_manager = AccountManager::new();
+        let mut journal = Journal::new(Vec::new(), 0);
+        for (index, record) in records.iter().enumerate() {
+            let _ = journal
+                .process_record(&mut account_manager, index as u64 + 1, record)
+                .unwrap();
+        }
+        (account_manager, journal.writer)
+    }
+
+    #[test]
+    fn test_recover() {
+        let (account_manager, journal) = journal_of(&[
+            InputRecord::new(InputRecordType::Deposit, 1, 0, Some(dec!(10.0))),
+            InputRecord::new(InputRecordType::Withdrawal, 1, 1, Some(dec!(50.0))),
+            InputRecord::new(InputRecordType::Dispute, 1, 0, None),
+        ]);
+
+        let recovery = recover(None, identity, journal.as_slice()).unwrap();
+        assert_eq!(
+            account_manager.gather_output(),
+            recovery.account_manager.gather_output()
+        );
+        assert_eq!(3, recovery.last_sequence);
+        assert_eq!(Some(3), recovery.resume_position);
+        assert_eq!(journal.len() as u64, recovery.valid_length);
+    }
+
+    #[test]
+    fn test_recover_from_snapshot() {
+        let (account_manager, journal) = journal_of(&[
+            InputRecord::new(InputRecordType::Deposit, 1, 0, Some(dec!(10.0))),
+            InputRecord::new(InputRecordType::Deposit, 1, 1, Some(dec!(5.0))),
+        ]);
+
+        // Snapshot taken after first entry
+        let (snapshot_manager, _) = journal_of(&[InputRecord::new(
+            InputRecordType::Deposit,
+            1,
+            0,
+            Some(dec!(10.0)),
+        )]);
+        let mut snapshot = snapshot_manager.snapshot();
+        snapshot.journal_sequence = 1;
+
+        let recovery = recover(Some(snapshot), identity, journal.as_slice()).unwrap();
+        assert_eq!(
+            account_manager.gather_output(),
+            recovery.account_manager.gather_output()
+        );
+        assert_eq!(2, recovery.last_sequence);
+    }
+
+    #[test]
+    fn test_recover_torn_entry() {
+        let (_, mut journal) = journal_of(&[InputRecord::new(
+            InputRecordType::Deposit,
+            1,
+            0,
+            Some(dec!(10.0)),
+        )]);
+        let valid_length = journal.len() as u64;
+        journal.extend_from_slice(br#"{"type":"record","sequence":2,"posi"#);
+
+        let recovery = recover(None, identity, journal.as_slice()).unwrap();
+        assert_eq!(1, recovery.last_sequence);
+        assert_eq!(valid_length, recovery.valid_length);
+    }
+
+    #[test]
+    fn test_recover_corrupted_entry() {
+        let journal = b"{\"type\":\"record\"}\n";
+        assert!(matches!(
+            recover(None, identity, journal.as_slice()),
+            Err(JournalError::Corrupted(1, _))
+        ));
+    }
+
+    #[test]
+    fn test_recover_outcome_mismatch() {
+        let (_, journal) = journal_of(&[InputRecord::new(
+            InputRecordType::Deposit,
+            1,
+            0,
+            Some(dec!(10.0)),
+        )]);
+        let journal = String::from_utf8(journal)
+            .unwrap()
+            .replace("\"applied\"", "{\"rejected\":\"account_is_locked\"}");
+
+        assert!(matches!(
+            recover(None, identity, journal.as_bytes()),
+            Err(JournalError::OutcomeMismatch(1))
+        ));
+    }
+
+    #[test]
+    fn test_recover_rejected_outcome_and_completed_run() {
+        let mut account_manager = AccountManager::new();
+        let mut journal = Journal::new(Vec::new(), 0);
+        assert!(journal
+            .process_record(
+                &mut account_manager,
+                1,
+                &InputRecord::new(InputRecordType::Dispute, 1, 0, None)
+            )
+            .unwrap()
+            .is_err());
+        journal.complete_run().unwrap();
+
+        let recovery = recover(None, identity, journal.writer.as_slice()).unwrap();
+        assert_eq!(1, recovery.last_sequence);
+        assert_eq!(None, recovery.resume_position);
+    }
+}
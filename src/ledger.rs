@@ -0,0 +1,826 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{Display, Formatter},
+    io::Write,
+    sync::Arc,
+};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    account_manager::{
+        account::{ProcessingError, TransactionState, TransactionType},
+        dispute_policy::{DisputePolicy, DisputedFunds, HoldWithdrawals},
+        AccountManager,
+    },
+    events::{Event, EventKind, EventSink},
+    format::FormatError,
+    model::{ClientId, Currency, OutputRecord, TransactionId, TransactionRecord},
+};
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum ClientAccount {
+    Available,
+    Held,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum ExternalAccount {
+    /// Money deposited to and withdrawn from the system
+    Bank,
+    /// Money moved by disputes, chargebacks end up here
+    Chargebacks,
+    /// Administrative credits and debits
+    Adjustments,
+    /// Currency conversions, every currency has its own
+    Exchange,
+    /// Clearing account of transfers between clients, zero once both legs are posted
+    Transfers,
+    /// Fee revenue
+    Fees,
+}
+
+/// Account of the ledger, displayed like `7:available` or `external:bank`.
+/// Every currency has its own accounts, e.g. `7:EUR:available`.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum LedgerAccount {
+    Client(ClientId, Option<Currency>, ClientAccount),
+    External(Option<Currency>, ExternalAccount),
+}
+
+impl LedgerAccount {
+    pub fn currency(&self) -> Option<Currency> {
+        match self {
+            Self::Client(_, currency, _) | Self::External(currency, _) => *currency,
+        }
+    }
+}
+
+impl Display for LedgerAccount {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Client(client_id, _, _) => write!(f, "{client_id}:")?,
+            Self::External(_, _) => write!(f, "external:")?,
+        }
+        if let Some(currency) = self.currency() {
+            write!(f, "{currency}:")?;
+        }
+        match self {
+            Self::Client(_, _, ClientAccount::Available) => write!(f, "available"),
+            Self::Client(_, _, ClientAccount::Held) => write!(f, "held"),
+            Self::External(_, ExternalAccount::Bank) => write!(f, "bank"),
+            Self::External(_, ExternalAccount::Chargebacks) => write!(f, "chargebacks"),
+            Self::External(_, ExternalAccount::Adjustments) => write!(f, "adjustments"),
+            Self::External(_, ExternalAccount::Exchange) => write!(f, "exchange"),
+            Self::External(_, ExternalAccount::Transfers) => write!(f, "transfers"),
+            Self::External(_, ExternalAccount::Fees) => write!(f, "fees"),
+        }
+    }
+}
+
+/// Signed amount posted to an account, positive is debit, negative is credit
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Posting {
+    pub account: LedgerAccount,
+    pub amount: Decimal,
+}
+
+/// Postings of a single event, they always sum to zero
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LedgerEntry {
+    pub kind: EventKind,
+    pub client_id: ClientId,
+    pub transaction_id: TransactionId,
+    pub postings: Vec<Posting>,
+}
+
+#[derive(Debug, Error)]
+pub enum LedgerError {
+    #[error("Decimal overflow")]
+    DecimalOverflow,
+    #[error("Ledger is not balanced, accounts sum to: `{0}`")]
+    Unbalanced(Decimal),
+    #[error("Ledger disagrees with account state of client: `{0}`")]
+    ViewMismatch(ClientId),
+    #[error("Disputed transaction is not in the ledger: `{0}`")]
+    TransactionMissing(TransactionId),
+    #[error("Dispute can't be posted: `{0}`")]
+    Dispute(ProcessingError),
+}
+
+/// Double-entry ledger, balances are derived only from balanced postings.
+/// It's fed with events of [`AccountManager`], but postings are built from kind and amount
+/// of the event alone, never from account balances. So [`Ledger::check_view`] proves that
+/// account state matches the money moved by processed records.
+#[derive(Debug)]
+pub struct Ledger {
+    balances: BTreeMap<LedgerAccount, Decimal>,
+    locked: BTreeSet<ClientId>,
+    // Deposits and withdrawals, their disputes move the posted amount
+    disputable: HashMap<TransactionId, Disputable>,
+    dispute_policy: Arc<dyn DisputePolicy>,
+}
+
+/// Change of balance of an account, postings are made of them
+type Change = (LedgerAccount, Decimal);
+
+#[derive(Clone, Copy, Debug)]
+struct Disputable {
+    client_id: ClientId,
+    r#type: TransactionType,
+    /// Positive for withdrawals too
+    amount: Decimal,
+    state: TransactionState,
+    /// Funds moved by the first dispute, later cycles move and revert the same ones
+    funds: Option<DisputedFunds>,
+}
+
+impl Default for Ledger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ledger {
+    /// Ledger with [`HoldWithdrawals`] policy, see [`Ledger::with_dispute_policy`]
+    pub fn new() -> Self {
+        Self {
+            balances: BTreeMap::new(),
+            locked: BTreeSet::new(),
+            disputable: HashMap::new(),
+            dispute_policy: Arc::new(HoldWithdrawals),
+        }
+    }
+
+    /// Policy deciding how disputes of withdrawals are posted, it has to be the same as accounts use
+    pub fn with_dispute_policy(mut self, dispute_policy: Arc<dyn DisputePolicy>) -> Self {
+        self.dispute_policy = dispute_policy;
+        self
+    }
+
+    /// Opens ledger with current balances of all accounts, funded from `external:bank`.
+    /// Deposits and withdrawals of the accounts are kept, so they can be disputed later.
+    pub fn open(account_manager: &AccountManager) -> Result<Self, LedgerError> {
+        let mut ledger =
+            Self::new().with_dispute_policy(Arc::clone(&account_manager.dispute_rules().policy));
+        for record in account_manager.gather_output() {
+            ledger.apply(&[
+                Posting {
+                    account: LedgerAccount::Client(
+                        record.client_id,
+                        record.currency,
+                        ClientAccount::Available,
+                    ),
+                    amount: record.available,
+                },
+                Posting {
+                    account: LedgerAccount::Client(
+                        record.client_id,
+                        record.currency,
+                        ClientAccount::Held,
+                    ),
+                    amount: record.held,
+                },
+                Posting {
+                    account: LedgerAccount::External(record.currency, ExternalAccount::Bank),
+                    amount: -record.total,
+                },
+            ])?;
+            if record.locked {
+                ledger.locked.insert(record.client_id);
+            }
+        }
+        for account in account_manager.accounts() {
+            for transaction in account.transactions() {
+                let funds = account.disputed_funds(transaction.transaction_id);
+                ledger.track(account.client_id(), &transaction, funds);
+            }
+        }
+
+        Ok(ledger)
+    }
+
+    fn track(
+        &mut self,
+        client_id: ClientId,
+        transaction: &TransactionRecord,
+        funds: Option<DisputedFunds>,
+    ) {
+        if matches!(
+            transaction.r#type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) {
+            self.disputable.insert(
+                transaction.transaction_id,
+                Disputable {
+                    client_id,
+                    r#type: transaction.r#type,
+                    amount: transaction.amount,
+                    state: match transaction.state {
+                        state if state.is_disputed() => TransactionState::Dispute,
+                        state => state,
+                    },
+                    funds,
+                },
+            );
+        }
+    }
+
+    /// Posts entry for given event. Postings follow from kind and amount of the event,
+    /// disputes move the amount of disputed transaction between client and `external:chargebacks`.
+    pub fn post(&mut self, event: &Event) -> Result<LedgerEntry, LedgerError> {
+        let client_id = event.client_id;
+        let currency = event.currency;
+        let amount = event.amount;
+        let available = LedgerAccount::Client(client_id, currency, ClientAccount::Available);
+        let external = |account| LedgerAccount::External(currency, account);
+
+        let mut disputed = None;
+        let postings = match event.kind {
+            EventKind::DepositApplied => vec![
+                (available, amount),
+                (external(ExternalAccount::Bank), -amount),
+            ],
+            EventKind::WithdrawalApplied => {
+                vec![
+                    (available, -amount),
+                    (external(ExternalAccount::Bank), amount),
+                ]
+            }
+            EventKind::FundsHeld | EventKind::FundsReleased | EventKind::ChargebackApplied => {
+                let (postings, state, funds) = self.dispute_postings(event)?;
+                disputed = Some((state, funds));
+                postings
+            }
+            EventKind::AdjustmentApplied => vec![
+                (available, amount),
+                (external(ExternalAccount::Adjustments), -amount),
+            ],
+            EventKind::ConversionDebited => vec![
+                (available, -amount),
+                (external(ExternalAccount::Exchange), amount),
+            ],
+            EventKind::ConversionCredited => vec![
+                (available, amount),
+                (external(ExternalAccount::Exchange), -amount),
+            ],
+            EventKind::TransferSent => vec![
+                (available, -amount),
+                (external(ExternalAccount::Transfers), amount),
+            ],
+            EventKind::TransferReceived => vec![
+                (available, amount),
+                (external(ExternalAccount::Transfers), -amount),
+            ],
+            EventKind::FeeCharged => {
+                vec![
+                    (available, -amount),
+                    (external(ExternalAccount::Fees), amount),
+                ]
+            }
+            // Lock status changes move no money, so there are no postings at all
+            EventKind::AccountLocked | EventKind::AccountFrozen | EventKind::AccountUnlocked => {
+                Vec::new()
+            }
+        };
+        let postings: Vec<_> = postings
+            .into_iter()
+            .filter(|(_, amount)| !amount.is_zero())
+            .map(|(account, amount)| Posting { account, amount })
+            .collect();
+        self.apply(&postings)?;
+
+        match event.kind {
+            EventKind::DepositApplied => self.disputable.insert(
+                event.transaction_id,
+                Disputable {
+                    client_id,
+                    r#type: TransactionType::Deposit,
+                    amount,
+                    state: TransactionState::Valid,
+                    funds: None,
+                },
+            ),
+            EventKind::WithdrawalApplied => self.disputable.insert(
+                event.transaction_id,
+                Disputable {
+                    client_id,
+                    r#type: TransactionType::Withdrawal,
+                    amount,
+                    state: TransactionState::Valid,
+                    funds: None,
+                },
+            ),
+            _ => None,
+        };
+        if let (Some((state, funds)), Some(transaction)) =
+            (disputed, self.disputable.get_mut(&event.transaction_id))
+        {
+            transaction.state = state;
+            transaction.funds = Some(funds);
+        }
+        match event.kind {
+            EventKind::AccountLocked | EventKind::AccountFrozen => {
+                self.locked.insert(client_id);
+            }
+            EventKind::AccountUnlocked => {
+                self.locked.remove(&client_id);
+            }
+            _ => {}
+        }
+
+        Ok(LedgerEntry {
+            kind: event.kind,
+            client_id,
+            transaction_id: event.transaction_id,
+            postings,
+        })
+    }
+
+    /// Postings of dispute, resolve or chargeback, along with new state of disputed transaction
+    /// and funds the dispute moved. Resolved transaction disputed again goes through another cycle.
+    fn dispute_postings(
+        &self,
+        event: &Event,
+    ) -> Result<(Vec<Change>, TransactionState, DisputedFunds), LedgerError> {
+        let transaction = self
+            .disputable
+            .get(&event.transaction_id)
+            .filter(|transaction| transaction.client_id == event.client_id)
+            .ok_or(LedgerError::TransactionMissing(event.transaction_id))?;
+        let available_account =
+            LedgerAccount::Client(event.client_id, event.currency, ClientAccount::Available);
+        let held_account =
+            LedgerAccount::Client(event.client_id, event.currency, ClientAccount::Held);
+        let available = self.balance(available_account);
+        let held = self.balance(held_account);
+        let amount = match transaction.r#type {
+            TransactionType::Withdrawal => -transaction.amount,
+            _ => transaction.amount,
+        };
+        let overflow = || LedgerError::DecimalOverflow;
+        let funds = match transaction.funds {
+            Some(funds) => funds,
+            None => DisputedFunds::of(
+                transaction.r#type,
+                amount,
+                available,
+                held,
+                self.dispute_policy.as_ref(),
+            )
+            .map_err(LedgerError::Dispute)?,
+        };
+
+        let redispute = transaction.state == TransactionState::Resolved;
+        let (state, balances) = match event.kind {
+            EventKind::FundsHeld if redispute => (
+                TransactionState::Dispute,
+                funds.redispute(amount, available, held),
+            ),
+            EventKind::FundsHeld => (TransactionState::Dispute, funds.dispute(available, held)),
+            EventKind::FundsReleased => (
+                TransactionState::Resolved,
+                funds.revert(amount, available, held),
+            ),
+            _ => (
+                TransactionState::ChargedBack,
+                funds.revert(amount, available, held),
+            ),
+        };
+        let (new_available, new_held) = balances.map_err(LedgerError::Dispute)?;
+        let available_change = new_available.checked_sub(available).ok_or_else(overflow)?;
+        let held_change = new_held.checked_sub(held).ok_or_else(overflow)?;
+        let counter = -available_change
+            .checked_add(held_change)
+            .ok_or_else(overflow)?;
+        Ok((
+            vec![
+                (available_account, available_change),
+                (held_account, held_change),
+                (
+                    LedgerAccount::External(event.currency, ExternalAccount::Chargebacks),
+                    counter,
+                ),
+            ],
+            state,
+            funds,
+        ))
+    }
+
+    fn apply(&mut self, postings: &[Posting]) -> Result<(), LedgerError> {
+        let sum = postings
+            .iter()
+            .try_fold(Decimal::ZERO, |sum, posting| {
+                sum.checked_add(posting.amount)
+            })
+            .ok_or(LedgerError::DecimalOverflow)?;
+        if !sum.is_zero() {
+            return Err(LedgerError::Unbalanced(sum));
+        }
+
+        // Checked up front, so failed entry leaves no partial postings behind
+        let updated = postings
+            .iter()
+            .map(|posting| {
+                self.balance(posting.account)
+                    .checked_add(posting.amount)
+                    .map(|balance| (posting.account, balance))
+                    .ok_or(LedgerError::DecimalOverflow)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.balances.extend(updated);
+
+        Ok(())
+    }
+
+    pub fn balance(&self, account: LedgerAccount) -> Decimal {
+        self.balances
+            .get(&account)
+            .copied()
+            .unwrap_or(Decimal::ZERO)
+    }
+
+    /// Balances of all accounts, ordered by account
+    pub fn balances(&self) -> impl Iterator<Item = (LedgerAccount, Decimal)> + '_ {
+        self.balances
+            .iter()
+            .map(|(&account, &balance)| (account, balance))
+    }
+
+    /// Checks that accounts of every currency sum to zero
+    pub fn trial_balance(&self) -> Result<(), LedgerError> {
+        let mut sums = BTreeMap::new();
+        for (account, balance) in self.balances() {
+            let sum: &mut Decimal = sums.entry(account.currency()).or_default();
+            *sum = sum
+                .checked_add(balance)
+                .ok_or(LedgerError::DecimalOverflow)?;
+        }
+        match sums.into_values().find(|sum| !sum.is_zero()) {
+            None => Ok(()),
+            Some(sum) => Err(LedgerError::Unbalanced(sum)),
+        }
+    }
+
+    /// Same view of a client as [`crate::Account::to_outputs`], computed from ledger balances
+    pub fn output(&self, client_id: ClientId, currency: Option<Currency>) -> OutputRecord {
+        let available = self.balance(LedgerAccount::Client(
+            client_id,
+            currency,
+            ClientAccount::Available,
+        ));
+        let held = self.balance(LedgerAccount::Client(
+            client_id,
+            currency,
+            ClientAccount::Held,
+        ));
+        OutputRecord {
+            client_id,
+            currency,
+            available,
+            held,
+            total: available + held,
+            locked: self.locked.contains(&client_id),
+            credit_limit: None,
+            available_to_spend: None,
+        }
+    }
+
+    /// Checks that every account of `account_manager` has the same view in the ledger
+    pub fn check_view(&self, account_manager: &AccountManager) -> Result<(), LedgerError> {
+        for record in account_manager.gather_output() {
+            // Credit lines aren't posted, only balances are compared
+            let record = OutputRecord {
+                credit_limit: None,
+                available_to_spend: None,
+                ..record
+            };
+            if self.output(record.client_id, record.currency) != record {
+                return Err(LedgerError::ViewMismatch(record.client_id));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl EventSink for Ledger {
+    type Error = LedgerError;
+
+    fn emit(&mut self, event: &Event) -> Result<(), Self::Error> {
+        self.post(event).map(|_| ())
+    }
+}
+
+/// Writes trial balance as CSV, `account,balance`
+pub fn write_trial_balance(writer: impl Write, ledger: &Ledger) -> Result<(), FormatError> {
+    #[derive(Serialize)]
+    struct Row {
+        account: String,
+        balance: Decimal,
+    }
+
+    let mut writer = csv::Writer::from_writer(writer);
+    for (account, balance) in ledger.balances() {
+        writer.serialize(Row {
+            account: account.to_string(),
+            balance,
+        })?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    use super::{
+        write_trial_balance, ClientAccount, ExternalAccount, Ledger, LedgerAccount, LedgerError,
+    };
+    use crate::{
+        account_manager::{dispute_policy::DisputePolicyKind, AccountManager},
+        fees::{FeeSchedule, FeeTier, FeeTrigger},
+        model::{InputRecord, InputRecordType},
+        rates::ExchangeRates,
+    };
+
+    #[test]
+    fn test_ledger_matches_accounts() {
+        let mut account_manager = AccountManager::new();
+        let mut ledger = Ledger::new();
+        for record in [
+            InputRecord::new(InputRecordType::Deposit, 1, 1, Some(dec!(50.0))),
+            InputRecord::new(InputRecordType::Deposit, 1, 2, Some(dec!(999.0))),
+            InputRecord::new(InputRecordType::Withdrawal, 1, 3, Some(dec!(950.0))),
+            InputRecord::new(InputRecordType::Dispute, 1, 3, None),
+            InputRecord::new(InputRecordType::Resolve, 1, 3, None),
+            InputRecord::new(InputRecordType::Dispute, 1, 2, None),
+            InputRecord::new(InputRecordType::Chargeback, 1, 2, None),
+            InputRecord::new(InputRecordType::Deposit, 2, 4, Some(dec!(7.5))),
+            InputRecord::new(InputRecordType::Dispute, 2, 4, None),
+            // Rejected, posts nothing
+            InputRecord::new(InputRecordType::Withdrawal, 2, 5, Some(dec!(1.0))),
+            InputRecord {
+                currency: Some("EUR".parse().unwrap()),
+                ..InputRecord::new(InputRecordType::Deposit, 2, 6, Some(dec!(3.0)))
+            },
+        ] {
+            let _ = account_manager
+                .process_record_events(&record, &mut ledger)
+                .unwrap();
+            ledger.trial_balance().unwrap();
+        }
+
+        ledger.check_view(&account_manager).unwrap();
+        // Deposits minus withdrawal
+        assert_eq!(
+            dec!(-106.5),
+            ledger.balance(LedgerAccount::External(None, ExternalAccount::Bank))
+        );
+        // Every currency has its own accounts
+        assert_eq!(
+            dec!(-3.0),
+            ledger.balance(LedgerAccount::External(
+                Some("EUR".parse().unwrap()),
+                ExternalAccount::Bank
+            ))
+        );
+        assert_eq!(
+            "2:EUR:available",
+            LedgerAccount::Client(2, Some("EUR".parse().unwrap()), ClientAccount::Available)
+                .to_string()
+        );
+        // Charged back deposit, minus withdrawal refunded by resolved dispute
+        assert_eq!(
+            dec!(999.0) - dec!(950.0),
+            ledger.balance(LedgerAccount::External(None, ExternalAccount::Chargebacks))
+        );
+        assert_eq!(
+            dec!(7.5),
+            ledger.balance(LedgerAccount::Client(2, None, ClientAccount::Held))
+        );
+    }
+
+    #[test]
+    fn test_view_mismatch() {
+        // Account engine credited less than the deposit, entries still balance
+        let mut account_manager = AccountManager::new();
+        let mut events = Vec::new();
+        account_manager
+            .process_record_events(
+                &InputRecord::new(InputRecordType::Deposit, 1, 1, Some(dec!(5.0))),
+                &mut events,
+            )
+            .unwrap()
+            .unwrap();
+        events[0].amount = dec!(10.0);
+
+        let mut ledger = Ledger::new();
+        ledger.post(&events[0]).unwrap();
+        ledger.trial_balance().unwrap();
+        assert!(matches!(
+            ledger.check_view(&account_manager),
+            Err(LedgerError::ViewMismatch(1))
+        ));
+
+        // Balances reported by events aren't used at all
+        let mut account_manager = AccountManager::new();
+        let mut events = Vec::new();
+        for record in [
+            InputRecord::new(InputRecordType::Deposit, 1, 1, Some(dec!(5.0))),
+            InputRecord::new(InputRecordType::Dispute, 1, 1, None),
+        ] {
+            account_manager
+                .process_record_events(&record, &mut events)
+                .unwrap()
+                .unwrap();
+        }
+        events[1].after.held = dec!(1.0);
+        let mut ledger = Ledger::new();
+        for event in &events {
+            ledger.post(event).unwrap();
+        }
+        ledger.check_view(&account_manager).unwrap();
+        assert!(matches!(
+            Ledger::new().post(&events[1]),
+            Err(LedgerError::TransactionMissing(1))
+        ));
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_policy() {
+        let mut account_manager = AccountManager::new()
+            .with_dispute_policy(DisputePolicyKind::ProvisionalCredit.policy());
+        let mut ledger = Ledger::open(&account_manager).unwrap();
+        for record in [
+            InputRecord::new(InputRecordType::Deposit, 1, 1, Some(dec!(10.0))),
+            InputRecord::new(InputRecordType::Withdrawal, 1, 2, Some(dec!(4.0))),
+            InputRecord::new(InputRecordType::Dispute, 1, 2, None),
+            InputRecord::new(InputRecordType::Chargeback, 1, 2, None),
+        ] {
+            account_manager
+                .process_record_events(&record, &mut ledger)
+                .unwrap()
+                .unwrap();
+        }
+
+        ledger.trial_balance().unwrap();
+        ledger.check_view(&account_manager).unwrap();
+        // Withdrawal was credited back when disputed
+        assert_eq!(dec!(10.0), ledger.output(1, None).available);
+        assert_eq!(
+            dec!(-4.0),
+            ledger.balance(LedgerAccount::External(None, ExternalAccount::Chargebacks))
+        );
+        assert!(ledger.output(1, None).locked);
+    }
+
+    #[test]
+    fn test_open() {
+        let mut account_manager = AccountManager::new();
+        account_manager
+            .process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                3,
+                1,
+                Some(dec!(2.0)),
+            ))
+            .unwrap();
+        account_manager
+            .process_record(&InputRecord::new(InputRecordType::Dispute, 3, 1, None))
+            .unwrap();
+
+        let mut ledger = Ledger::open(&account_manager).unwrap();
+        ledger.trial_balance().unwrap();
+        ledger.check_view(&account_manager).unwrap();
+
+        account_manager
+            .process_record_events(
+                &InputRecord::new(InputRecordType::Chargeback, 3, 1, None),
+                &mut ledger,
+            )
+            .unwrap()
+            .unwrap();
+        ledger.trial_balance().unwrap();
+        ledger.check_view(&account_manager).unwrap();
+
+        let mut output = Vec::new();
+        write_trial_balance(&mut output, &ledger).unwrap();
+        assert_eq!(
+            "account,balance\n3:available,0.0\n3:held,0.0\nexternal:bank,-2.0\nexternal:chargebacks,2.0\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_conversion() {
+        let usd = "USD".parse().unwrap();
+        let eur = "EUR".parse().unwrap();
+        let mut rates = ExchangeRates::new(usd);
+        rates.insert(eur, dec!(1.1)).unwrap();
+        let mut account_manager = AccountManager::new().with_exchange_rates(Arc::new(rates));
+        let mut ledger = Ledger::new();
+        for record in [
+            InputRecord {
+                currency: Some(eur),
+                ..InputRecord::new(InputRecordType::Deposit, 1, 1, Some(dec!(10.0)))
+            },
+            InputRecord {
+                currency: Some(eur),
+                to_currency: Some(usd),
+                ..InputRecord::new(InputRecordType::Convert, 1, 2, Some(dec!(3.0)))
+            },
+        ] {
+            account_manager
+                .process_record_events(&record, &mut ledger)
+                .unwrap()
+                .unwrap();
+        }
+
+        ledger.trial_balance().unwrap();
+        ledger.check_view(&account_manager).unwrap();
+        assert_eq!(
+            dec!(3.0),
+            ledger.balance(LedgerAccount::External(
+                Some(eur),
+                ExternalAccount::Exchange
+            ))
+        );
+        assert_eq!(
+            dec!(-3.3),
+            ledger.balance(LedgerAccount::External(
+                Some(usd),
+                ExternalAccount::Exchange
+            ))
+        );
+        assert_eq!(dec!(3.3), ledger.output(1, Some(usd)).available);
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut account_manager = AccountManager::new();
+        let mut ledger = Ledger::new();
+        for record in [
+            InputRecord::new(InputRecordType::Deposit, 1, 1, Some(dec!(10.0))),
+            InputRecord::new(InputRecordType::Deposit, 2, 2, Some(dec!(1.0))),
+            InputRecord {
+                to_client_id: Some(2),
+                ..InputRecord::new(InputRecordType::Transfer, 1, 3, Some(dec!(4.0)))
+            },
+        ] {
+            account_manager
+                .process_record_events(&record, &mut ledger)
+                .unwrap()
+                .unwrap();
+        }
+
+        ledger.trial_balance().unwrap();
+        ledger.check_view(&account_manager).unwrap();
+        // Clearing account is empty once both legs are posted
+        assert_eq!(
+            Decimal::ZERO,
+            ledger.balance(LedgerAccount::External(None, ExternalAccount::Transfers))
+        );
+        assert_eq!(dec!(5.0), ledger.output(2, None).available);
+    }
+
+    #[test]
+    fn test_fees() {
+        let mut fee_schedule = FeeSchedule::new();
+        fee_schedule
+            .insert(
+                FeeTrigger::Withdrawal,
+                FeeTier {
+                    from: Decimal::ZERO,
+                    flat: dec!(0.5),
+                    percent: dec!(1),
+                },
+            )
+            .unwrap();
+        let mut account_manager = AccountManager::new().with_fee_schedule(Arc::new(fee_schedule));
+        let mut ledger = Ledger::new();
+        for record in [
+            InputRecord::new(InputRecordType::Deposit, 1, 1, Some(dec!(10.0))),
+            InputRecord::new(InputRecordType::Withdrawal, 1, 2, Some(dec!(5.0))),
+        ] {
+            account_manager
+                .process_record_events(&record, &mut ledger)
+                .unwrap()
+                .unwrap();
+        }
+
+        ledger.trial_balance().unwrap();
+        ledger.check_view(&account_manager).unwrap();
+        assert_eq!(
+            dec!(0.55),
+            ledger.balance(LedgerAccount::External(None, ExternalAccount::Fees))
+        );
+    }
+}
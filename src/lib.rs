@@ -0,0 +1,54 @@
+//! Transaction processing engine.
+//!
+//! Library part of the transaction processor, the CLI in `main.rs` is a thin wrapper around it.
+//! Records are fed one by one into [`AccountManager`], which keeps the state of every client
+//! account and can produce a summary of all accounts as [`OutputRecord`]s.
+
+pub mod account_manager;
+pub mod credit;
+pub mod events;
+pub mod fees;
+pub mod format;
+pub mod journal;
+pub mod ledger;
+pub mod model;
+pub mod rates;
+pub mod rejected;
+pub mod server;
+pub mod snapshot;
+pub mod source;
+pub mod validation;
+
+pub use crate::{
+    account_manager::{
+        account::{Account, ProcessingError, TransactionState, TransactionType},
+        dispute_policy::{
+            DisallowWithdrawalDisputes, DisputePolicy, DisputePolicyKind, DisputeRules,
+            DisputedFunds, HoldWithdrawals, ProvisionalCredit, MAX_DISPUTE_CYCLES,
+        },
+        sharded::{Rejected, ShardedAccountManager, ShardedError, MAX_WORKERS},
+        AccountManager, OutputOrder,
+    },
+    credit::{CreditLimits, CreditLimitsError},
+    events::{Balances, Event, EventKind, EventSink, EventWriter},
+    fees::{FeeRevenue, FeeSchedule, FeeTier, FeeTrigger, FeesError},
+    format::{
+        is_csv_header, parse_csv_row, write_fee_revenue, write_output, write_transactions,
+        FormatError, InputFormat, OutputFormat, RawRow, ReadRecord, RecordReader, PARSE_ERROR_CODE,
+    },
+    journal::{recover, Journal, JournalEntry, JournalError, Outcome, Recovery},
+    ledger::{
+        write_trial_balance, ClientAccount, ExternalAccount, Ledger, LedgerAccount, LedgerEntry,
+        LedgerError, Posting,
+    },
+    model::{
+        ClientId, Currency, CurrencyError, InputRecord, InputRecordType, OutputRecord,
+        TransactionId, TransactionRecord,
+    },
+    rates::{ExchangeRates, RatesError},
+    rejected::{RejectedFormat, RejectedRecord, RejectedWriter},
+    server::SharedEngine,
+    snapshot::{Snapshot, SnapshotError, SNAPSHOT_VERSION},
+    source::{InputSource, SourceError},
+    validation::{read_operators, PrecisionPolicy, RecordValidator},
+};
@@ -1,47 +1,1039 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashSet,
+    fmt::{Display, Formatter},
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+};
 
-use clap::Parser;
-use csv::Trim;
+use clap::{Parser, Subcommand, ValueEnum};
+use thiserror::Error;
+use tokio::net::TcpListener;
+use transaction_processor::{
+    read_operators,
+    server::{http, tcp},
+    write_fee_revenue, write_output, write_transactions, write_trial_balance, AccountManager,
+    ClientId, CreditLimits, CreditLimitsError, Currency, DisputePolicyKind, DisputeRules, Event,
+    EventSink, EventWriter, ExchangeRates, FeeSchedule, FeesError, FormatError, InputFormat,
+    InputRecord, InputSource, Journal, JournalError, Ledger, LedgerError, OutputFormat,
+    OutputOrder, OutputRecord, PrecisionPolicy, ProcessingError, RatesError, RawRow, ReadRecord,
+    RecordReader, RecordValidator, RejectedFormat, RejectedRecord, RejectedWriter,
+    ShardedAccountManager, ShardedError, SharedEngine, Snapshot, SnapshotError, SourceError,
+    MAX_DISPUTE_CYCLES, PARSE_ERROR_CODE,
+};
 
-use crate::account_manager::AccountManager;
+/// Exit code used when run finished, but some records were rejected
+const EXIT_CODE_REJECTED: u8 = 2;
 
-mod account_manager;
-mod model;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum ParseErrorPolicy {
+    /// Stop processing, no output is written
+    Abort,
+    /// Skip the row
+    Skip,
+    /// Skip the row and write it to rejected records file
+    Report,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum ProcessingErrorPolicy {
+    /// Skip the record, it is written to rejected records file
+    Skip,
+    /// Stop processing, no output is written
+    Abort,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum Backend {
+    /// Balances are kept directly on accounts
+    Accounts,
+    /// Every change is posted as balanced entries to a double-entry ledger, output is computed from it
+    Ledger,
+}
 
 #[derive(Debug, Parser)]
-#[command(version, long_about = None)]
+#[command(
+    version,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(short, long, default_value_t = false, help = "Log errors to stderr")]
     log_errors: bool,
-    /// Path of transaction file
-    path: PathBuf,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = PrecisionPolicy::Reject,
+        help = "What to do with amounts having more than 4 decimal places"
+    )]
+    precision_policy: PrecisionPolicy,
+    #[arg(
+        long,
+        help = "File with ids of operators allowed to submit administrative records, one per line"
+    )]
+    operators: Option<PathBuf>,
+    #[command(flatten)]
+    disputes: DisputeArgs,
+    #[command(flatten)]
+    rates: RatesArgs,
+    #[command(flatten)]
+    fees: FeeArgs,
+    #[command(flatten)]
+    credit: CreditArgs,
+    #[arg(
+        long,
+        requires = "rates",
+        help = "Write every account valued in base currency to given file, in output format"
+    )]
+    valuation_out: Option<PathBuf>,
+    #[arg(
+        long,
+        requires = "fees",
+        help = "Write fees charged from all accounts, per type and currency, to given file, in output format"
+    )]
+    fee_report_out: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputOrder::Client,
+        help = "Order of output records"
+    )]
+    order: OutputOrder,
+    #[arg(long, value_enum, default_value_t = InputFormat::Csv, help = "Format of transaction file")]
+    input_format: InputFormat,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Csv, help = "Format of output")]
+    output_format: OutputFormat,
+    #[arg(long, help = "Write rejected records to given file")]
+    rejected_out: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = RejectedFormat::Csv,
+        help = "Format of rejected records file"
+    )]
+    rejected_format: RejectedFormat,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ParseErrorPolicy::Abort,
+        help = "What to do with rows which can't be parsed"
+    )]
+    on_parse_error: ParseErrorPolicy,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ProcessingErrorPolicy::Skip,
+        help = "What to do with records which can't be processed"
+    )]
+    on_processing_error: ProcessingErrorPolicy,
+    #[arg(
+        long,
+        help = "Load engine state from given snapshot file before processing"
+    )]
+    state_in: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Save engine state to given snapshot file after processing"
+    )]
+    state_out: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Journal file, interrupted run is recovered from it and resumed where it left off"
+    )]
+    journal: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Write event of every balance change to given file, as JSON Lines"
+    )]
+    events_out: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Backend::Accounts,
+        help = "How balances are kept"
+    )]
+    backend: Backend,
+    #[arg(
+        long,
+        help = "Write trial balance of ledger to given file, requires `--backend ledger`"
+    )]
+    trial_balance_out: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = 1,
+        help = "Number of worker threads, accounts are partitioned between them by client id"
+    )]
+    workers: usize,
+    /// Paths or glob patterns of transaction files, processed in order, `-` reads from stdin
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Accept records over network, all connections share the same accounts
+    Serve(ServeArgs),
+    /// Print transactions of a client with their state transitions
+    History(HistoryArgs),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum ServeProtocol {
+    /// CSV rows over plain TCP, one response line per row
+    Tcp,
+    /// HTTP/JSON API
+    Http,
+}
+
+#[derive(Debug, clap::Args)]
+struct DisputeArgs {
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = DisputePolicyKind::Hold,
+        help = "How disputes of withdrawals move funds"
+    )]
+    withdrawal_dispute_policy: DisputePolicyKind,
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(1..=i64::from(MAX_DISPUTE_CYCLES)),
+        help = "How many times transaction can be disputed, resolved transaction can be disputed again \
+                as second presentment and then arbitration"
+    )]
+    max_dispute_cycles: u8,
+    #[arg(
+        long,
+        value_name = "DAYS",
+        help = "Reject disputes which come later than given number of days after transaction, \
+                records need `timestamp`"
+    )]
+    dispute_window: Option<u64>,
+    #[arg(
+        long,
+        value_name = "DAYS",
+        help = "Reject chargebacks which come later than given number of days after dispute, \
+                late resolves are accepted, records need `timestamp`"
+    )]
+    resolution_deadline: Option<u64>,
+    #[arg(
+        long,
+        requires = "resolution_deadline",
+        help = "Resolve disputes past resolution deadline, once a record with later timestamp comes"
+    )]
+    auto_resolve: bool,
+}
+
+impl DisputeArgs {
+    fn rules(&self) -> DisputeRules {
+        DisputeRules {
+            policy: self.withdrawal_dispute_policy.policy(),
+            max_cycles: self.max_dispute_cycles,
+            dispute_window: self.dispute_window.map(days_to_seconds),
+            resolution_deadline: self.resolution_deadline.map(days_to_seconds),
+            auto_resolve: self.auto_resolve,
+        }
+    }
+}
+
+fn days_to_seconds(days: u64) -> u64 {
+    days.saturating_mul(SECONDS_PER_DAY)
+}
+
+#[derive(Debug, clap::Args)]
+struct RatesArgs {
+    #[arg(
+        long,
+        requires = "base_currency",
+        help = "CSV file with exchange rates used by conversions, `currency,rate`, \
+                rate is the value of one unit in base currency"
+    )]
+    rates: Option<PathBuf>,
+    #[arg(
+        long,
+        requires = "rates",
+        help = "Currency exchange rates are given in"
+    )]
+    base_currency: Option<Currency>,
+}
+
+impl RatesArgs {
+    fn exchange_rates(&self) -> Result<Option<Arc<ExchangeRates>>, RunError> {
+        let (Some(path), Some(base_currency)) = (&self.rates, self.base_currency) else {
+            return Ok(None);
+        };
+
+        Ok(Some(Arc::new(ExchangeRates::read(
+            File::open(path)?,
+            base_currency,
+        )?)))
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct FeeArgs {
+    #[arg(
+        long,
+        help = "CSV file with fee schedule, `type,from,flat,percent`, \
+                fees are charged on withdrawals and chargebacks"
+    )]
+    fees: Option<PathBuf>,
+}
+
+impl FeeArgs {
+    fn fee_schedule(&self) -> Result<Option<Arc<FeeSchedule>>, RunError> {
+        let Some(path) = &self.fees else {
+            return Ok(None);
+        };
+
+        Ok(Some(Arc::new(FeeSchedule::read(File::open(path)?)?)))
+    }
+}
+
+#[derive(Debug, clap::Args)]
+struct CreditArgs {
+    #[arg(
+        long,
+        help = "CSV file with credit lines of clients, `client,limit[,currency]`, \
+                debits can take available funds below zero down to minus the limit"
+    )]
+    credit_limits: Option<PathBuf>,
+}
+
+impl CreditArgs {
+    fn credit_limits(&self) -> Result<Option<Arc<CreditLimits>>, RunError> {
+        let Some(path) = &self.credit_limits else {
+            return Ok(None);
+        };
+
+        Ok(Some(Arc::new(CreditLimits::read(File::open(path)?)?)))
+    }
+}
+
+/// Applies rules given on command line, they aren't a part of snapshot
+fn configure(
+    account_manager: AccountManager,
+    disputes: &DisputeArgs,
+    exchange_rates: Option<&Arc<ExchangeRates>>,
+    fee_schedule: Option<&Arc<FeeSchedule>>,
+    credit_limits: Option<&Arc<CreditLimits>>,
+    operators: &Arc<HashSet<String>>,
+) -> AccountManager {
+    let mut account_manager = account_manager
+        .with_dispute_rules(disputes.rules())
+        .with_operators(Arc::clone(operators));
+    if let Some(exchange_rates) = exchange_rates {
+        account_manager = account_manager.with_exchange_rates(Arc::clone(exchange_rates));
+    }
+    if let Some(fee_schedule) = fee_schedule {
+        account_manager = account_manager.with_fee_schedule(Arc::clone(fee_schedule));
+    }
+    if let Some(credit_limits) = credit_limits {
+        account_manager = account_manager.with_credit_limits(Arc::clone(credit_limits));
+    }
+    account_manager
+}
+
+#[derive(Debug, clap::Args)]
+struct ServeArgs {
+    #[arg(long, default_value = "127.0.0.1:7878", help = "Address to listen on")]
+    listen: SocketAddr,
+    #[arg(long, value_enum, default_value_t = ServeProtocol::Tcp, help = "Protocol of server")]
+    protocol: ServeProtocol,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = PrecisionPolicy::Reject,
+        help = "What to do with amounts having more than 4 decimal places"
+    )]
+    precision_policy: PrecisionPolicy,
+    #[arg(
+        long,
+        help = "File with ids of operators allowed to submit administrative records, one per line"
+    )]
+    operators: Option<PathBuf>,
+    #[command(flatten)]
+    disputes: DisputeArgs,
+    #[command(flatten)]
+    rates: RatesArgs,
+    #[command(flatten)]
+    fees: FeeArgs,
+    #[command(flatten)]
+    credit: CreditArgs,
+    #[arg(long, help = "Load engine state from given snapshot file on start")]
+    state_in: Option<PathBuf>,
+    #[arg(long, help = "Save engine state to given snapshot file on Ctrl+C")]
+    state_out: Option<PathBuf>,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+#[derive(Debug, clap::Args)]
+struct HistoryArgs {
+    #[arg(long, help = "Client whose transactions are printed")]
+    client: ClientId,
+    #[arg(long, help = "Load engine state from given snapshot file")]
+    state_in: Option<PathBuf>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = PrecisionPolicy::Reject,
+        help = "What to do with amounts having more than 4 decimal places"
+    )]
+    precision_policy: PrecisionPolicy,
+    #[arg(
+        long,
+        help = "File with ids of operators allowed to submit administrative records, one per line"
+    )]
+    operators: Option<PathBuf>,
+    #[command(flatten)]
+    disputes: DisputeArgs,
+    #[command(flatten)]
+    rates: RatesArgs,
+    #[command(flatten)]
+    fees: FeeArgs,
+    #[command(flatten)]
+    credit: CreditArgs,
+    #[arg(long, value_enum, default_value_t = InputFormat::Csv, help = "Format of transaction file")]
+    input_format: InputFormat,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Csv, help = "Format of output")]
+    output_format: OutputFormat,
+    /// Transaction files processed after loading the snapshot, rejected records are skipped
+    #[arg(required_unless_present = "state_in")]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, Error)]
+enum RunError {
+    #[error("Parse error at `{0}:{1}`: {2}")]
+    Parse(InputSource, u64, FormatError),
+    #[error("Processing error at `{0}:{1}`: {2}")]
+    Processing(InputSource, u64, ProcessingError),
+    #[error("`{0}` is not supported with multiple workers")]
+    UnsupportedWithWorkers(&'static str),
+    #[error("`{0}` is not supported with `--journal`")]
+    UnsupportedWithJournal(&'static str),
+    #[error("`{0}` requires `--backend ledger`")]
+    RequiresLedger(&'static str),
+    #[error("Client not found: `{0}`")]
+    ClientMissing(ClientId),
+
+    #[error(transparent)]
+    Format(#[from] FormatError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Journal(#[from] JournalError),
+    #[error(transparent)]
+    Ledger(#[from] LedgerError),
+    #[error(transparent)]
+    CreditLimits(#[from] CreditLimitsError),
+    #[error(transparent)]
+    Fees(#[from] FeesError),
+    #[error(transparent)]
+    Rates(#[from] RatesError),
+    #[error("Valuation error: {0}")]
+    Valuation(ProcessingError),
+    #[error(transparent)]
+    Sharded(#[from] ShardedError),
+    #[error(transparent)]
+    Snapshot(#[from] SnapshotError),
+    #[error(transparent)]
+    Source(#[from] SourceError),
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    read: u64,
+    parse_errors: u64,
+    processing_errors: u64,
+}
+
+impl Summary {
+    fn rejected(&self) -> u64 {
+        self.parse_errors + self.processing_errors
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Records read: {}, rejected: {} (parse errors: {}, processing errors: {})",
+            self.read,
+            self.rejected(),
+            self.parse_errors,
+            self.processing_errors
+        )
+    }
+}
+
+fn main() -> ExitCode {
     let args = Args::parse();
+    if let Some(command) = &args.command {
+        let result = match command {
+            Command::Serve(args) => serve(args),
+            Command::History(args) => history(args),
+        };
+        return match result {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(error) => {
+                eprintln!("Error: {error}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let mut summary = Summary::default();
+    let result = run(&args, &mut summary);
+    if let Err(error) = &result {
+        eprintln!("Error: {error}");
+    }
+    if result.is_err() || summary.rejected() > 0 {
+        eprintln!("{summary}");
+    }
+
+    match result {
+        Ok(()) if summary.rejected() > 0 => ExitCode::from(EXIT_CODE_REJECTED),
+        Ok(()) => ExitCode::SUCCESS,
+        Err(_) => ExitCode::FAILURE,
+    }
+}
+
+fn run(args: &Args, summary: &mut Summary) -> Result<(), RunError> {
+    let sources = InputSource::resolve(&args.paths)?;
+    let mut engine = Engine::new(args)?;
+    let mut reporter = Reporter::new(args, &sources, engine.sharded.is_some())?;
 
-    let mut reader = csv::ReaderBuilder::new()
-        .flexible(true)
-        .trim(Trim::All)
-        .from_path(args.path)?;
+    let result = process_sources(args, &sources, &mut engine, &mut reporter, summary);
+    let result = result.and_then(|()| engine.finish(args, &mut reporter, summary));
 
-    let mut account_manager = AccountManager::new();
+    // Rejected records are flushed even if run was aborted, they are needed to investigate it
+    reporter.flush()?;
+    result?;
 
-    for result in reader.deserialize() {
-        let record: model::InputRecord = result?;
-        if let Err(error) = account_manager.process_record(&record) {
-            if args.log_errors {
-                eprintln!("Error processing record: `{record:?}`, reason: `{error}`)`");
+    let mut output = engine.account_manager.gather_output_ordered(args.order);
+    if let Some(ledger) = &engine.ledger {
+        for record in &mut output {
+            // Ledger has balances only, credit view is computed from them
+            let balances = ledger.output(record.client_id, record.currency);
+            *record = OutputRecord {
+                available_to_spend: record
+                    .credit_limit
+                    .map(|credit_limit| balances.available + credit_limit),
+                credit_limit: record.credit_limit,
+                ..balances
+            };
+        }
+    }
+    write_output(std::io::stdout(), args.output_format, &output)?;
+
+    if let (Some(path), Some(exchange_rates)) =
+        (&args.valuation_out, engine.account_manager.exchange_rates())
+    {
+        let valuation = engine
+            .account_manager
+            .gather_valuation(exchange_rates)
+            .map_err(RunError::Valuation)?;
+        write_output(File::create(path)?, args.output_format, &valuation)?;
+    }
+    if let Some(path) = &args.fee_report_out {
+        write_fee_revenue(
+            File::create(path)?,
+            args.output_format,
+            &engine.account_manager.gather_fee_revenue(),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn serve(args: &ServeArgs) -> Result<(), RunError> {
+    let account_manager = configure(
+        match &args.state_in {
+            Some(path) => AccountManager::from_snapshot(Snapshot::read_from_path(path)?)?,
+            None => AccountManager::new(),
+        },
+        &args.disputes,
+        args.rates.exchange_rates()?.as_ref(),
+        args.fees.fee_schedule()?.as_ref(),
+        args.credit.credit_limits()?.as_ref(),
+        &operators(args.operators.as_deref())?,
+    );
+    let engine = Arc::new(SharedEngine::new(
+        account_manager,
+        RecordValidator::new(args.precision_policy),
+    ));
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let listener = TcpListener::bind(args.listen).await?;
+        eprintln!("Listening on `{}`", listener.local_addr()?);
+        let server = match args.protocol {
+            ServeProtocol::Tcp => {
+                tokio::spawn(tcp::serve(listener, Arc::clone(&engine), |error| {
+                    eprintln!("{error}")
+                }))
             }
+            ServeProtocol::Http => tokio::spawn(http::serve(listener, Arc::clone(&engine))),
+        };
+        tokio::select! {
+            result = server => result?,
+            result = tokio::signal::ctrl_c() => result,
         }
+    })?;
+
+    if let Some(path) = &args.state_out {
+        engine.read(AccountManager::snapshot).write_to_path(path)?;
     }
 
-    let mut writer = csv::Writer::from_writer(std::io::stdout());
-    account_manager
-        .gather_output()
-        .into_iter()
-        .try_for_each(|record| writer.serialize(record))?;
-    writer.flush()?;
+    Ok(())
+}
+
+/// Without operators file nobody can submit administrative records
+fn operators(path: Option<&Path>) -> Result<Arc<HashSet<String>>, RunError> {
+    Ok(Arc::new(match path {
+        Some(path) => read_operators(BufReader::new(File::open(path)?))?,
+        None => HashSet::new(),
+    }))
+}
+
+fn history(args: &HistoryArgs) -> Result<(), RunError> {
+    let mut account_manager = configure(
+        match &args.state_in {
+            Some(path) => AccountManager::from_snapshot(Snapshot::read_from_path(path)?)?,
+            None => AccountManager::new(),
+        },
+        &args.disputes,
+        args.rates.exchange_rates()?.as_ref(),
+        args.fees.fee_schedule()?.as_ref(),
+        args.credit.credit_limits()?.as_ref(),
+        &operators(args.operators.as_deref())?,
+    );
+
+    let validator = RecordValidator::new(args.precision_policy);
+    for source in InputSource::resolve(&args.paths)? {
+        for result in RecordReader::new(source.open()?, args.input_format) {
+            if let Ok(record) = result?.record {
+                // Rejected records don't change any transaction, so they are not part of history
+                let _ = validator
+                    .validate(record)
+                    .and_then(|record| account_manager.process_record(&record));
+            }
+        }
+    }
+
+    let transactions = account_manager
+        .transactions(args.client)
+        .ok_or(RunError::ClientMissing(args.client))?;
+    write_transactions(std::io::stdout(), args.output_format, &transactions)?;
+
+    Ok(())
+}
+
+fn process_sources(
+    args: &Args,
+    sources: &[InputSource],
+    engine: &mut Engine,
+    reporter: &mut Reporter,
+    summary: &mut Summary,
+) -> Result<(), RunError> {
+    let validator = RecordValidator::new(args.precision_policy);
+    let mut position = 0;
+
+    for (source_index, source) in sources.iter().enumerate() {
+        for result in RecordReader::new(source.open()?, args.input_format) {
+            let ReadRecord { line, raw, record } = result?;
+            position += 1;
+            if engine
+                .resume_position
+                .is_some_and(|resume_position| position <= resume_position)
+            {
+                continue;
+            }
+            summary.read += 1;
+            let row = Row {
+                position,
+                source: source_index,
+                line,
+                raw,
+            };
+
+            let record = match record {
+                Ok(record) => record,
+                Err(error) => {
+                    summary.parse_errors += 1;
+                    if args.log_errors {
+                        eprintln!("Error parsing row: `{}`, reason: `{error}`", row.raw);
+                    }
+                    match args.on_parse_error {
+                        ParseErrorPolicy::Abort => {
+                            return Err(RunError::Parse(source.clone(), line, error));
+                        }
+                        ParseErrorPolicy::Skip => {}
+                        ParseErrorPolicy::Report => {
+                            reporter.report(row, PARSE_ERROR_CODE, &error)?
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            let rejected = match validator.validate(record) {
+                Ok(record) => engine.process_record(record, row)?,
+                Err(error) => Some((row, error)),
+            };
+            if let Some((row, error)) = rejected {
+                reject_record(args, reporter, summary, row, &error)?;
+                if args.on_processing_error == ProcessingErrorPolicy::Abort {
+                    return Err(RunError::Processing(source.clone(), line, error));
+                }
+            }
+        }
+    }
 
     Ok(())
 }
+
+fn reject_record(
+    args: &Args,
+    reporter: &mut Reporter,
+    summary: &mut Summary,
+    row: Row,
+    error: &ProcessingError,
+) -> Result<(), RunError> {
+    summary.processing_errors += 1;
+    if args.log_errors {
+        eprintln!("Error processing record: `{}`, reason: `{error}`", row.raw);
+    }
+    reporter.report(row, error.code(), error)
+}
+
+/// Where a record comes from, used to report it when it gets rejected
+#[derive(Debug)]
+struct Row {
+    /// Position in input of the run, starting from 1
+    position: u64,
+    /// Index of source
+    source: usize,
+    line: u64,
+    raw: RawRow,
+}
+
+/// Writes rejected records, with multiple workers they are collected and written in input order
+/// once processing is finished
+struct Reporter {
+    writer: Option<RejectedWriter<File>>,
+    sources: Vec<InputSource>,
+    deferred: Option<Vec<(Row, &'static str, String)>>,
+}
+
+impl Reporter {
+    fn new(args: &Args, sources: &[InputSource], deferred: bool) -> Result<Self, RunError> {
+        let writer = args
+            .rejected_out
+            .as_ref()
+            .map(|path| {
+                File::create(path).map(|file| RejectedWriter::new(file, args.rejected_format))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            deferred: (deferred && writer.is_some()).then(Vec::new),
+            writer,
+            sources: sources.to_vec(),
+        })
+    }
+
+    fn report(
+        &mut self,
+        row: Row,
+        code: &'static str,
+        error: &impl std::error::Error,
+    ) -> Result<(), RunError> {
+        match (&mut self.deferred, &mut self.writer) {
+            (Some(deferred), _) => deferred.push((row, code, error.to_string())),
+            (None, Some(writer)) => {
+                writer.write(&rejected_record(
+                    &self.sources,
+                    &row,
+                    code,
+                    error.to_string(),
+                ))?;
+            }
+            (None, None) => {}
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), RunError> {
+        let Some(writer) = &mut self.writer else {
+            return Ok(());
+        };
+
+        if let Some(mut deferred) = self.deferred.take() {
+            deferred.sort_by_key(|(row, _, _)| row.position);
+            for (row, code, message) in deferred {
+                writer.write(&rejected_record(&self.sources, &row, code, message))?;
+            }
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+fn rejected_record(
+    sources: &[InputSource],
+    row: &Row,
+    code: &'static str,
+    message: String,
+) -> RejectedRecord {
+    RejectedRecord {
+        source: sources
+            .get(row.source)
+            .map(ToString::to_string)
+            .unwrap_or_default(),
+        line: row.line,
+        raw: row.raw.to_string(),
+        code,
+        message,
+    }
+}
+
+/// `AccountManager` along with optional journal, or its sharded counterpart
+struct Engine {
+    account_manager: AccountManager,
+    journal: Option<Journal<File>>,
+    events: Option<EventWriter<File>>,
+    ledger: Option<Ledger>,
+    /// Used with multiple workers, `account_manager` holds merged state once it's finished
+    sharded: Option<ShardedAccountManager<Row>>,
+    /// Records up to this input position were processed by interrupted run
+    resume_position: Option<u64>,
+    /// Journal sequence included in `state_in`, carried over when journal is not used
+    journal_sequence: u64,
+}
+
+impl Engine {
+    fn new(args: &Args) -> Result<Self, RunError> {
+        let snapshot = args
+            .state_in
+            .as_deref()
+            .map(Snapshot::read_from_path)
+            .transpose()?;
+        let journal_sequence = snapshot
+            .as_ref()
+            .map_or(0, |snapshot| snapshot.journal_sequence);
+
+        if args.workers > 1 {
+            if args.journal.is_some() {
+                return Err(RunError::UnsupportedWithWorkers("--journal"));
+            }
+            if args.on_processing_error == ProcessingErrorPolicy::Abort {
+                return Err(RunError::UnsupportedWithWorkers(
+                    "--on-processing-error abort",
+                ));
+            }
+            if args.events_out.is_some() {
+                return Err(RunError::UnsupportedWithWorkers("--events-out"));
+            }
+            if args.backend == Backend::Ledger {
+                return Err(RunError::UnsupportedWithWorkers("--backend ledger"));
+            }
+            // Workers see only timestamps of their own records, so disputes would be resolved at different times
+            if args.disputes.auto_resolve {
+                return Err(RunError::UnsupportedWithWorkers("--auto-resolve"));
+            }
+        }
+        // Records recovered from journal would have no events
+        if args.journal.is_some() {
+            if args.events_out.is_some() {
+                return Err(RunError::UnsupportedWithJournal("--events-out"));
+            }
+            if args.backend == Backend::Ledger {
+                return Err(RunError::UnsupportedWithJournal("--backend ledger"));
+            }
+        }
+        if args.trial_balance_out.is_some() && args.backend != Backend::Ledger {
+            return Err(RunError::RequiresLedger("--trial-balance-out"));
+        }
+        let events = args
+            .events_out
+            .as_ref()
+            .map(File::create)
+            .transpose()?
+            .map(EventWriter::new);
+        let exchange_rates = args.rates.exchange_rates()?;
+        let fee_schedule = args.fees.fee_schedule()?;
+        let credit_limits = args.credit.credit_limits()?;
+        let operators = operators(args.operators.as_deref())?;
+
+        let (account_manager, journal, resume_position) = match &args.journal {
+            Some(path) => {
+                let (recovery, journal) =
+                    Journal::recover_from_path(path, snapshot, |account_manager| {
+                        configure(
+                            account_manager,
+                            &args.disputes,
+                            exchange_rates.as_ref(),
+                            fee_schedule.as_ref(),
+                            credit_limits.as_ref(),
+                            &operators,
+                        )
+                    })?;
+                if let Some(position) = recovery.resume_position {
+                    eprintln!("Resuming interrupted run after record: `{position}`");
+                }
+                (
+                    recovery.account_manager,
+                    Some(journal),
+                    recovery.resume_position,
+                )
+            }
+            None => (
+                configure(
+                    snapshot
+                        .map(AccountManager::from_snapshot)
+                        .transpose()?
+                        .unwrap_or_default(),
+                    &args.disputes,
+                    exchange_rates.as_ref(),
+                    fee_schedule.as_ref(),
+                    credit_limits.as_ref(),
+                    &operators,
+                ),
+                None,
+                None,
+            ),
+        };
+
+        let ledger = match args.backend {
+            Backend::Accounts => None,
+            Backend::Ledger => Some(Ledger::open(&account_manager)?),
+        };
+
+        let (account_manager, sharded) = if args.workers > 1 {
+            (
+                AccountManager::new(),
+                Some(ShardedAccountManager::new(account_manager, args.workers)?),
+            )
+        } else {
+            (account_manager, None)
+        };
+
+        Ok(Self {
+            account_manager,
+            journal,
+            events,
+            ledger,
+            sharded,
+            resume_position,
+            journal_sequence,
+        })
+    }
+
+    /// Returns record back along with the error if it was rejected right away,
+    /// with multiple workers rejected records are known once processing is finished
+    fn process_record(
+        &mut self,
+        record: InputRecord,
+        row: Row,
+    ) -> Result<Option<(Row, ProcessingError)>, RunError> {
+        if let Some(sharded) = &mut self.sharded {
+            sharded.process_record(record, row)?;
+            return Ok(None);
+        }
+
+        let result = match &mut self.journal {
+            Some(journal) => {
+                journal.process_record(&mut self.account_manager, row.position, &record)?
+            }
+            None if self.events.is_some() || self.ledger.is_some() => {
+                let mut sinks = Sinks {
+                    events: self.events.as_mut(),
+                    ledger: self.ledger.as_mut(),
+                };
+                self.account_manager
+                    .process_record_events(&record, &mut sinks)?
+            }
+            None => self.account_manager.process_record(&record),
+        };
+
+        Ok(result.err().map(|error| (row, error)))
+    }
+
+    /// Merges sharded state and saves snapshot,
+    /// journal is cleared once its entries are included in the snapshot
+    fn finish(
+        &mut self,
+        args: &Args,
+        reporter: &mut Reporter,
+        summary: &mut Summary,
+    ) -> Result<(), RunError> {
+        if let Some(sharded) = self.sharded.take() {
+            let (account_manager, rejected) = sharded.finish()?;
+            self.account_manager = account_manager;
+            for rejected in rejected {
+                reject_record(args, reporter, summary, rejected.context, &rejected.error)?;
+            }
+        }
+
+        if let Some(events) = &mut self.events {
+            events.flush()?;
+        }
+        if let Some(ledger) = &self.ledger {
+            ledger.trial_balance()?;
+            ledger.check_view(&self.account_manager)?;
+            if let Some(path) = &args.trial_balance_out {
+                write_trial_balance(File::create(path)?, ledger)?;
+            }
+        }
+
+        let journal_sequence = self
+            .journal
+            .as_ref()
+            .map_or(self.journal_sequence, Journal::last_sequence);
+
+        if let Some(path) = &args.state_out {
+            let mut snapshot = self.account_manager.snapshot();
+            snapshot.journal_sequence = journal_sequence;
+            snapshot.write_to_path(path)?;
+            if let Some(journal) = &mut self.journal {
+                journal.clear()?;
+            }
+        } else if let Some(journal) = &mut self.journal {
+            journal.complete_run()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Event sinks of sequential engine
+struct Sinks<'a> {
+    events: Option<&'a mut EventWriter<File>>,
+    ledger: Option<&'a mut Ledger>,
+}
+
+impl EventSink for Sinks<'_> {
+    type Error = RunError;
+
+    fn emit(&mut self, event: &Event) -> Result<(), Self::Error> {
+        if let Some(ledger) = &mut self.ledger {
+            ledger.post(event)?;
+        }
+        if let Some(events) = &mut self.events {
+            events.emit(event)?;
+        }
+
+        Ok(())
+    }
+}
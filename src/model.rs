@@ -1,12 +1,66 @@
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::account_manager::account::{TransactionState, TransactionType};
 
 pub type ClientId = u16;
 pub type TransactionId = u32;
+/// Unix time, in seconds
+pub type Timestamp = u64;
+
+/// Maximum number of decimal places of amounts
+pub const AMOUNT_PRECISION: u32 = 4;
+
+/// Currency code, three ASCII letters like `EUR`, always uppercase
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Currency([u8; 3]);
+
+#[derive(Debug, Error)]
+#[error("Invalid currency code: `{0}`")]
+pub struct CurrencyError(String);
+
+impl FromStr for Currency {
+    type Err = CurrencyError;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        match <[u8; 3]>::try_from(code.as_bytes()) {
+            Ok(bytes) if bytes.iter().all(u8::is_ascii_alphabetic) => {
+                Ok(Self(bytes.map(|byte| byte.to_ascii_uppercase())))
+            }
+            _ => Err(CurrencyError(code.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for Currency {
+    type Error = CurrencyError;
+
+    fn try_from(code: String) -> Result<Self, Self::Error> {
+        code.parse()
+    }
+}
+
+impl From<Currency> for String {
+    fn from(currency: Currency) -> Self {
+        currency.to_string()
+    }
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // Only ASCII letters are accepted
+        f.write_str(std::str::from_utf8(&self.0).unwrap_or_default())
+    }
+}
 
-// Allowing dead code for now, as debug print output is used
-#[allow(dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct InputRecord {
     pub r#type: InputRecordType,
     #[serde(rename = "client")]
@@ -17,9 +71,69 @@ pub struct InputRecord {
     // Decimal used here, floats are not safe for calculating money
     #[serde(default)]
     pub amount: Option<Decimal>,
+
+    /// Required by administrative records
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operator: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    /// Time of the record, dispute time windows are enforced only when it's known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    /// Records without currency use balances with no unit, like before currencies were supported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    /// Currency `amount` is converted to, used only by conversions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_currency: Option<Currency>,
+    /// Client receiving `amount`, used only by transfers
+    #[serde(rename = "to_client", default, skip_serializing_if = "Option::is_none")]
+    pub to_client_id: Option<ClientId>,
+}
+
+impl InputRecord {
+    /// Record without optional columns, they can be added with struct update syntax
+    pub fn new(
+        r#type: InputRecordType,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        amount: Option<Decimal>,
+    ) -> Self {
+        Self {
+            r#type,
+            client_id,
+            transaction_id,
+            amount,
+            operator: None,
+            reason: None,
+            timestamp: None,
+            currency: None,
+            to_currency: None,
+            to_client_id: None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl InputRecord {
+    /// Administrative record with a reason, submitted by `operator`
+    pub(crate) fn admin(
+        r#type: InputRecordType,
+        client_id: ClientId,
+        transaction_id: TransactionId,
+        amount: Option<Decimal>,
+        operator: &str,
+    ) -> Self {
+        Self {
+            operator: Some(operator.to_string()),
+            reason: Some("remediation".to_string()),
+            ..Self::new(r#type, client_id, transaction_id, amount)
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum InputRecordType {
     Deposit,
@@ -27,14 +141,71 @@ pub enum InputRecordType {
     Dispute,
     Resolve,
     Chargeback,
+    /// Administrative credit (positive amount) or debit (negative amount), works on locked accounts too
+    Adjust,
+    /// Administrative lock of account
+    Freeze,
+    /// Administrative unlock of account, e.g. after chargeback
+    Unlock,
+    /// Conversion of `amount` from `currency` to `to_currency` using exchange rates, can't be disputed
+    Convert,
+    /// Move of `amount` to existing account of `to_client`, can't be disputed
+    Transfer,
 }
 
-#[derive(Debug, Serialize)]
+impl InputRecordType {
+    /// Record creates a new transaction, its id has to be unique
+    pub fn creates_transaction(self) -> bool {
+        !matches!(self, Self::Dispute | Self::Resolve | Self::Chargeback)
+    }
+
+    /// Record requires an authorized operator
+    pub fn is_admin(self) -> bool {
+        matches!(self, Self::Adjust | Self::Freeze | Self::Unlock)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct OutputRecord {
     #[serde(rename = "client")]
     pub client_id: ClientId,
+    /// Accounts get one record per currency
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
     pub available: Decimal,
     pub held: Decimal,
     pub total: Decimal,
     pub locked: bool,
+    /// Credit line of the account, set only when credit limits are configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credit_limit: Option<Decimal>,
+    /// Available funds including credit line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_to_spend: Option<Decimal>,
+}
+
+/// Transaction of a single account, as returned by [`crate::Account::transactions`]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct TransactionRecord {
+    #[serde(rename = "tx")]
+    pub transaction_id: TransactionId,
+    pub r#type: TransactionType,
+    /// Amount as given in input record, positive for withdrawals too
+    pub amount: Decimal,
+    pub state: TransactionState,
+    /// All states transaction went through, starting with [`TransactionState::Valid`]
+    /// and ending with current one
+    pub transitions: Vec<TransactionState>,
+    /// Operator of administrative transactions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    /// Other client of a transfer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub counterparty: Option<ClientId>,
 }
@@ -0,0 +1,212 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Read,
+};
+
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    account_manager::account::ProcessingError,
+    model::{Currency, AMOUNT_PRECISION},
+};
+
+/// Exchange rates against base currency, rate of a currency is the value of its one unit in base currency
+#[derive(Clone, Debug)]
+pub struct ExchangeRates {
+    base: Currency,
+    rates: BTreeMap<Currency, Decimal>,
+}
+
+#[derive(Debug, Error)]
+pub enum RatesError {
+    #[error("CSV error: `{0}`")]
+    Csv(#[from] csv::Error),
+    #[error("Rate of base currency has to be `1`, got: `{0}`")]
+    BaseRateNotOne(Decimal),
+    #[error("Duplicate rate of currency: `{0}`")]
+    DuplicateRate(Currency),
+    #[error("Rate has to be positive, currency: `{0}`, rate: `{1}`")]
+    RateNotPositive(Currency, Decimal),
+}
+
+impl ExchangeRates {
+    /// Rates with just the base currency
+    pub fn new(base: Currency) -> Self {
+        Self {
+            base,
+            rates: BTreeMap::from([(base, Decimal::ONE)]),
+        }
+    }
+
+    /// Reads CSV with header, `currency,rate`. Base currency can be left out, its rate is always `1`.
+    pub fn read(reader: impl Read, base: Currency) -> Result<Self, RatesError> {
+        #[derive(Deserialize)]
+        struct Row {
+            currency: Currency,
+            rate: Decimal,
+        }
+
+        let mut rates = Self::new(base);
+        let mut seen = BTreeSet::new();
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        for row in reader.deserialize() {
+            let Row { currency, rate } = row?;
+            if !seen.insert(currency) {
+                return Err(RatesError::DuplicateRate(currency));
+            }
+            rates.insert(currency, rate)?;
+        }
+
+        Ok(rates)
+    }
+
+    pub fn insert(&mut self, currency: Currency, rate: Decimal) -> Result<(), RatesError> {
+        if rate <= Decimal::ZERO {
+            return Err(RatesError::RateNotPositive(currency, rate));
+        }
+        if currency == self.base && rate != Decimal::ONE {
+            return Err(RatesError::BaseRateNotOne(rate));
+        }
+        self.rates.insert(currency, rate);
+
+        Ok(())
+    }
+
+    pub fn base(&self) -> Currency {
+        self.base
+    }
+
+    pub fn rate(&self, currency: Currency) -> Option<Decimal> {
+        self.rates.get(&currency).copied()
+    }
+
+    /// Converts `amount` between currencies. Result is rounded towards zero to [`AMOUNT_PRECISION`],
+    /// so conversion never credits more than the debited amount is worth.
+    pub fn convert(
+        &self,
+        amount: Decimal,
+        from: Currency,
+        to: Currency,
+    ) -> Result<Decimal, ProcessingError> {
+        let from_rate = self.rate(from).ok_or(ProcessingError::RateMissing(from))?;
+        let to_rate = self.rate(to).ok_or(ProcessingError::RateMissing(to))?;
+        let converted = amount
+            .checked_mul(from_rate)
+            .and_then(|value| value.checked_div(to_rate))
+            .ok_or(ProcessingError::DecimalOverflow)?
+            .round_dp_with_strategy(AMOUNT_PRECISION, RoundingStrategy::ToZero)
+            .normalize();
+        if converted <= Decimal::ZERO {
+            return Err(ProcessingError::AmountNotPositive(converted));
+        }
+
+        Ok(converted)
+    }
+
+    /// Value of `amount` in base currency, rounded to [`AMOUNT_PRECISION`] with banker's rounding.
+    /// Amounts without currency are considered to be in base currency.
+    pub fn value(
+        &self,
+        amount: Decimal,
+        currency: Option<Currency>,
+    ) -> Result<Decimal, ProcessingError> {
+        let rate = match currency {
+            Some(currency) => self
+                .rate(currency)
+                .ok_or(ProcessingError::RateMissing(currency))?,
+            None => Decimal::ONE,
+        };
+
+        Ok(amount
+            .checked_mul(rate)
+            .ok_or(ProcessingError::DecimalOverflow)?
+            .round_dp_with_strategy(AMOUNT_PRECISION, RoundingStrategy::MidpointNearestEven)
+            .normalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{ExchangeRates, RatesError};
+    use crate::{account_manager::account::ProcessingError, model::Currency};
+
+    fn currency(code: &str) -> Currency {
+        code.parse().unwrap()
+    }
+
+    #[test]
+    fn test_read() {
+        let input = "currency, rate\nEUR, 1.08\nJPY, 0.0067\nUSD, 1\n";
+        let rates = ExchangeRates::read(input.as_bytes(), currency("USD")).unwrap();
+        assert_eq!(Some(dec!(1.08)), rates.rate(currency("EUR")));
+        assert_eq!(Some(dec!(1)), rates.rate(currency("USD")));
+        assert_eq!(None, rates.rate(currency("GBP")));
+
+        let read = |input: &str| ExchangeRates::read(input.as_bytes(), currency("USD"));
+        assert!(matches!(
+            read("currency,rate\nEUR,1\nEUR,2\n"),
+            Err(RatesError::DuplicateRate(..))
+        ));
+        assert!(matches!(
+            read("currency,rate\nEUR,0\n"),
+            Err(RatesError::RateNotPositive(..))
+        ));
+        assert!(matches!(
+            read("currency,rate\nUSD,2\n"),
+            Err(RatesError::BaseRateNotOne(..))
+        ));
+        assert!(matches!(
+            read("currency,rate\nEURO,1\n"),
+            Err(RatesError::Csv(..))
+        ));
+    }
+
+    #[test]
+    fn test_convert() {
+        let mut rates = ExchangeRates::new(currency("USD"));
+        rates.insert(currency("EUR"), dec!(1.08)).unwrap();
+        rates.insert(currency("JPY"), dec!(0.0067)).unwrap();
+
+        assert_eq!(
+            dec!(10.8),
+            rates
+                .convert(dec!(10), currency("EUR"), currency("USD"))
+                .unwrap()
+        );
+        // 1611.9402985..., rounded towards zero
+        assert_eq!(
+            dec!(1611.9402),
+            rates
+                .convert(dec!(10), currency("EUR"), currency("JPY"))
+                .unwrap()
+        );
+        assert!(matches!(
+            rates.convert(dec!(0.0001), currency("JPY"), currency("EUR")),
+            Err(ProcessingError::AmountNotPositive(_))
+        ));
+        assert!(matches!(
+            rates.convert(dec!(1), currency("EUR"), currency("GBP")),
+            Err(ProcessingError::RateMissing(_))
+        ));
+    }
+
+    #[test]
+    fn test_value() {
+        let mut rates = ExchangeRates::new(currency("USD"));
+        rates.insert(currency("JPY"), dec!(0.0067)).unwrap();
+
+        assert_eq!(dec!(2.5), rates.value(dec!(2.5), None).unwrap());
+        // 0.00335, ties to even
+        assert_eq!(
+            dec!(0.0034),
+            rates.value(dec!(0.5), Some(currency("JPY"))).unwrap()
+        );
+        assert!(rates.value(dec!(1), Some(currency("GBP"))).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use std::io::{BufWriter, Write};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::format::FormatError;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum RejectedFormat {
+    /// CSV with header, `source,line,raw,code,message`
+    #[default]
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+/// Row which was not applied, along with the reason
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct RejectedRecord {
+    /// File the row comes from, `<stdin>` for standard input
+    pub source: String,
+    pub line: u64,
+    pub raw: String,
+    /// Machine-readable error code, e.g. `account_is_locked`
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Streaming writer of [`RejectedRecord`]s
+pub enum RejectedWriter<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    Jsonl(BufWriter<W>),
+}
+
+impl<W: Write> RejectedWriter<W> {
+    pub fn new(writer: W, format: RejectedFormat) -> Self {
+        match format {
+            RejectedFormat::Csv => Self::Csv(Box::new(csv::Writer::from_writer(writer))),
+            RejectedFormat::Jsonl => Self::Jsonl(BufWriter::new(writer)),
+        }
+    }
+
+    pub fn write(&mut self, record: &RejectedRecord) -> Result<(), FormatError> {
+        match self {
+            Self::Csv(writer) => writer.serialize(record)?,
+            Self::Jsonl(writer) => {
+                serde_json::to_writer(&mut *writer, record)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), FormatError> {
+        match self {
+            Self::Csv(writer) => writer.flush()?,
+            Self::Jsonl(writer) => writer.flush()?,
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RejectedFormat, RejectedRecord, RejectedWriter};
+
+    fn rejected() -> RejectedRecord {
+        RejectedRecord {
+            source: "input.csv".to_string(),
+            line: 3,
+            raw: "deposit,1,2,-5".to_string(),
+            code: "amount_not_positive",
+            message: "Amount has to be positive: `-5`".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let mut output = Vec::new();
+        let mut writer = RejectedWriter::new(&mut output, RejectedFormat::Csv);
+        writer.write(&rejected()).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        assert_eq!(
+            "source,line,raw,code,message\n\
+             input.csv,3,\"deposit,1,2,-5\",amount_not_positive,Amount has to be positive: `-5`\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_write_jsonl() {
+        let mut output = Vec::new();
+        let mut writer = RejectedWriter::new(&mut output, RejectedFormat::Jsonl);
+        writer.write(&rejected()).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        assert_eq!(
+            "{\"source\":\"input.csv\",\"line\":3,\"raw\":\"deposit,1,2,-5\",\
+             \"code\":\"amount_not_positive\",\"message\":\"Amount has to be positive: `-5`\"}\n",
+            String::from_utf8(output).unwrap()
+        );
+    }
+}
@@ -0,0 +1,474 @@
+//! HTTP/JSON API:
+//! - `POST /transactions` - processes single record or array of records
+//! - `GET /accounts` - all accounts, ordered by client id
+//! - `GET /accounts/{client}` - single account, balances of currencies are nested in `currencies`
+//! - `GET /accounts/{client}/transactions` - transactions of single account
+
+use std::{io, sync::Arc};
+
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection},
+        Path, State,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+use crate::{
+    account_manager::{account::ProcessingError, AccountManager},
+    format::PARSE_ERROR_CODE,
+    model::{ClientId, Currency, InputRecord, OutputRecord, TransactionRecord},
+    server::SharedEngine,
+};
+
+/// Error code of requests for account which doesn't exist
+pub const ACCOUNT_MISSING_CODE: &str = "account_missing";
+
+pub async fn serve(listener: TcpListener, engine: Arc<SharedEngine>) -> io::Result<()> {
+    axum::serve(listener, router(engine)).await
+}
+
+pub fn router(engine: Arc<SharedEngine>) -> Router {
+    Router::new()
+        .route("/transactions", post(post_transactions))
+        .route("/accounts", get(get_accounts))
+        .route("/accounts/{client}", get(get_account))
+        .route("/accounts/{client}/transactions", get(get_transactions))
+        .with_state(engine)
+}
+
+/// Body of error responses
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    pub code: String,
+    pub message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &str, message: impl ToString) -> Self {
+        Self {
+            status,
+            code: code.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<ProcessingError> for ApiError {
+    fn from(error: ProcessingError) -> Self {
+        Self::new(status_code(&error), error.code(), error)
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::new(rejection.status(), PARSE_ERROR_CODE, rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        Self::new(rejection.status(), PARSE_ERROR_CODE, rejection.body_text())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+/// Status code of response to record rejected with given error
+pub fn status_code(error: &ProcessingError) -> StatusCode {
+    match error {
+        ProcessingError::AccountIsLocked | ProcessingError::DestinationAccountLocked(_) => {
+            StatusCode::LOCKED
+        }
+        ProcessingError::AmountMissing
+        | ProcessingError::AmountNotPositive(_)
+        | ProcessingError::AmountTooPrecise(_)
+        | ProcessingError::AmountUnexpected(_)
+        | ProcessingError::AmountZero
+        | ProcessingError::ConversionToSameCurrency(_)
+        | ProcessingError::CurrencyMissing
+        | ProcessingError::DestinationMissing
+        | ProcessingError::OperatorMissing
+        | ProcessingError::ReasonMissing
+        | ProcessingError::TransferToSameClient => StatusCode::BAD_REQUEST,
+        ProcessingError::ClientMismatch(_, _, _) | ProcessingError::OperatorNotAuthorized(_) => {
+            StatusCode::FORBIDDEN
+        }
+        ProcessingError::AccountNotLocked
+        | ProcessingError::DisputeCycleLimitReached(_)
+        | ProcessingError::DisputeWindowExpired(_)
+        | ProcessingError::ResolutionDeadlineExceeded(_)
+        | ProcessingError::TransactionAlreadyExists(_)
+        | ProcessingError::TransactionNotDisputable(_)
+        | ProcessingError::TransactionWrongState(_, _) => StatusCode::CONFLICT,
+        ProcessingError::DestinationAccountMissing(_) | ProcessingError::TransactionMissing(_) => {
+            StatusCode::NOT_FOUND
+        }
+        ProcessingError::CurrencyMismatch(_)
+        | ProcessingError::DecimalOverflow
+        | ProcessingError::RateMissing(_)
+        | ProcessingError::WithdrawalNotEnoughMoneyAvailable(_, _) => {
+            StatusCode::UNPROCESSABLE_ENTITY
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TransactionsRequest {
+    Batch(Vec<InputRecord>),
+    Single(InputRecord),
+}
+
+/// Outcome of a single record of a batch, records of a batch are processed independently
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum BatchOutcome {
+    Applied,
+    Rejected { code: String, message: String },
+}
+
+/// Single record responds with account it was applied to, batch with outcome of each record
+async fn post_transactions(
+    State(engine): State<Arc<SharedEngine>>,
+    request: Result<Json<TransactionsRequest>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(request) = request?;
+    match request {
+        TransactionsRequest::Single(record) => {
+            let client_id = record.client_id;
+            engine.process_record(record)?;
+            Ok(Json(account_output(&engine, client_id)?).into_response())
+        }
+        TransactionsRequest::Batch(records) => {
+            let outcomes: Vec<_> = records
+                .into_iter()
+                .map(|record| match engine.process_record(record) {
+                    Ok(()) => BatchOutcome::Applied,
+                    Err(error) => BatchOutcome::Rejected {
+                        code: error.code().to_string(),
+                        message: error.to_string(),
+                    },
+                })
+                .collect();
+            Ok(Json(outcomes).into_response())
+        }
+    }
+}
+
+async fn get_accounts(State(engine): State<Arc<SharedEngine>>) -> Json<Vec<AccountResponse>> {
+    Json(engine.read(|account_manager| {
+        account_manager
+            .accounts()
+            .filter_map(|account| account_response(account_manager, account.client_id()))
+            .collect()
+    }))
+}
+
+async fn get_account(
+    State(engine): State<Arc<SharedEngine>>,
+    client_id: Result<Path<ClientId>, PathRejection>,
+) -> Result<Json<AccountResponse>, ApiError> {
+    let Path(client_id) = client_id?;
+    Ok(Json(account_output(&engine, client_id)?))
+}
+
+async fn get_transactions(
+    State(engine): State<Arc<SharedEngine>>,
+    client_id: Result<Path<ClientId>, PathRejection>,
+) -> Result<Json<Vec<TransactionRecord>>, ApiError> {
+    let Path(client_id) = client_id?;
+    engine
+        .read(|account_manager| account_manager.transactions(client_id))
+        .map(Json)
+        .ok_or_else(|| account_missing(client_id))
+}
+
+/// Account with balances without currency at top level, so it keeps the same shape
+/// as before currencies. Balances of currencies are nested, left out if there are none.
+#[derive(Debug, Serialize)]
+struct AccountResponse {
+    #[serde(flatten)]
+    balances: OutputRecord,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    currencies: Vec<CurrencyBalances>,
+}
+
+#[derive(Debug, Serialize)]
+struct CurrencyBalances {
+    currency: Currency,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credit_limit: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    available_to_spend: Option<Decimal>,
+}
+
+fn account_output(engine: &SharedEngine, client_id: ClientId) -> Result<AccountResponse, ApiError> {
+    engine
+        .read(|account_manager| account_response(account_manager, client_id))
+        .ok_or_else(|| account_missing(client_id))
+}
+
+fn account_response(
+    account_manager: &AccountManager,
+    client_id: ClientId,
+) -> Option<AccountResponse> {
+    let mut rows = account_manager.account_output(client_id)?;
+    let balances = match rows.first() {
+        Some(row) if row.currency.is_none() => rows.remove(0),
+        // Account has funds in currencies only
+        _ => {
+            let credit_limit = account_manager
+                .credit_limits()
+                .map(|credit_limits| credit_limits.limit(client_id, None));
+            OutputRecord {
+                client_id,
+                currency: None,
+                available: Decimal::ZERO,
+                held: Decimal::ZERO,
+                total: Decimal::ZERO,
+                locked: account_manager.account(client_id)?.balances().locked,
+                credit_limit,
+                available_to_spend: credit_limit,
+            }
+        }
+    };
+    let currencies = rows
+        .into_iter()
+        .filter_map(|row| {
+            Some(CurrencyBalances {
+                currency: row.currency?,
+                available: row.available,
+                held: row.held,
+                total: row.total,
+                credit_limit: row.credit_limit,
+                available_to_spend: row.available_to_spend,
+            })
+        })
+        .collect();
+
+    Some(AccountResponse {
+        balances,
+        currencies,
+    })
+}
+
+fn account_missing(client_id: ClientId) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        ACCOUNT_MISSING_CODE,
+        format!("Account missing: `{client_id}`"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        body::{to_bytes, Body},
+        http::{Method, Request, StatusCode},
+        Router,
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::router;
+    use crate::{account_manager::AccountManager, server::SharedEngine, RecordValidator};
+
+    fn test_router() -> Router {
+        router(Arc::new(SharedEngine::new(
+            AccountManager::new(),
+            RecordValidator::default(),
+        )))
+    }
+
+    async fn request(
+        router: &Router,
+        method: Method,
+        uri: &str,
+        body: Value,
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_post_single_and_get() {
+        let router = test_router();
+
+        let (status, body) = request(
+            &router,
+            Method::POST,
+            "/transactions",
+            json!({"type": "deposit", "client": 1, "tx": 1, "amount": "10.5"}),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            json!({"client": 1, "available": "10.5", "held": "0", "total": "10.5", "locked": false}),
+            body
+        );
+
+        let (status, body) = request(&router, Method::GET, "/accounts/1", Value::Null).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("10.5", body["available"]);
+
+        let (status, body) = request(&router, Method::GET, "/accounts", Value::Null).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(1, body.as_array().unwrap().len());
+
+        let (status, body) = request(
+            &router,
+            Method::GET,
+            "/accounts/1/transactions",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            json!([{
+                "tx": 1,
+                "type": "deposit",
+                "amount": "10.5",
+                "state": "valid",
+                "transitions": ["valid"],
+            }]),
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post_batch() {
+        let router = test_router();
+
+        let (status, body) = request(
+            &router,
+            Method::POST,
+            "/transactions",
+            json!([
+                {"type": "deposit", "client": 1, "tx": 1, "amount": "10"},
+                {"type": "withdrawal", "client": 1, "tx": 2, "amount": "20"},
+                {"type": "dispute", "client": 1, "tx": 1},
+                {"type": "deposit", "client": 1, "tx": 3, "amount": "5", "currency": "EUR"},
+                {"type": "dispute", "client": 1, "tx": 3, "currency": "USD"},
+            ]),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("applied", body[0]["status"]);
+        assert_eq!("rejected", body[1]["status"]);
+        assert_eq!("withdrawal_not_enough_money_available", body[1]["code"]);
+        assert_eq!("applied", body[2]["status"]);
+        assert_eq!("applied", body[3]["status"]);
+        assert_eq!("currency_mismatch", body[4]["code"]);
+
+        let (_, body) = request(&router, Method::GET, "/accounts/1", Value::Null).await;
+        assert_eq!("10", body["held"]);
+        assert_eq!(
+            json!([{"currency": "EUR", "available": "5", "held": "0", "total": "5"}]),
+            body["currencies"]
+        );
+
+        // Account with currencies only keeps the same shape
+        request(
+            &router,
+            Method::POST,
+            "/transactions",
+            json!({"type": "deposit", "client": 2, "tx": 4, "amount": "1", "currency": "USD"}),
+        )
+        .await;
+        let (_, body) = request(&router, Method::GET, "/accounts", Value::Null).await;
+        assert_eq!(2, body.as_array().unwrap().len());
+        assert_eq!(
+            json!({
+                "client": 2,
+                "available": "0",
+                "held": "0",
+                "total": "0",
+                "locked": false,
+                "currencies": [{"currency": "USD", "available": "1", "held": "0", "total": "1"}],
+            }),
+            body[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let router = test_router();
+
+        for (method, uri, body, expected_status, expected_code) in [
+            (
+                Method::POST,
+                "/transactions",
+                json!({"type": "dispute", "client": 1, "tx": 1}),
+                StatusCode::NOT_FOUND,
+                "transaction_missing",
+            ),
+            (
+                Method::POST,
+                "/transactions",
+                json!({"type": "deposit", "client": 1, "tx": 1, "amount": "-1"}),
+                StatusCode::BAD_REQUEST,
+                "amount_not_positive",
+            ),
+            (
+                Method::POST,
+                "/transactions",
+                json!({"type": "teleport"}),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "parse_error",
+            ),
+            (
+                Method::GET,
+                "/accounts/7",
+                Value::Null,
+                StatusCode::NOT_FOUND,
+                "account_missing",
+            ),
+            (
+                Method::GET,
+                "/accounts/abc",
+                Value::Null,
+                StatusCode::BAD_REQUEST,
+                "parse_error",
+            ),
+            (
+                Method::GET,
+                "/accounts/7/transactions",
+                Value::Null,
+                StatusCode::NOT_FOUND,
+                "account_missing",
+            ),
+        ] {
+            let (status, body) = request(&router, method, uri, body).await;
+            assert_eq!(expected_status, status, "{uri}");
+            assert_eq!(expected_code, body["code"], "{uri}");
+            assert!(body["message"].is_string());
+        }
+    }
+}
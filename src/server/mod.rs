@@ -0,0 +1,44 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::{
+    account_manager::{account::ProcessingError, AccountManager},
+    model::InputRecord,
+    validation::RecordValidator,
+};
+
+pub mod http;
+pub mod tcp;
+
+/// `AccountManager` shared between connections, records are validated before processing
+#[derive(Debug)]
+pub struct SharedEngine {
+    account_manager: Mutex<AccountManager>,
+    validator: RecordValidator,
+}
+
+impl SharedEngine {
+    pub fn new(account_manager: AccountManager, validator: RecordValidator) -> Self {
+        Self {
+            account_manager: Mutex::new(account_manager),
+            validator,
+        }
+    }
+
+    pub fn process_record(&self, record: InputRecord) -> Result<(), ProcessingError> {
+        let record = self.validator.validate(record)?;
+        self.lock().process_record(&record)
+    }
+
+    /// Runs `f` with current state, records are not processed in the meantime
+    pub fn read<T>(&self, f: impl FnOnce(&AccountManager) -> T) -> T {
+        f(&self.lock())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, AccountManager> {
+        // Processing either fully succeeds or leaves account untouched, so state is valid
+        // even if other thread panicked while holding the lock
+        self.account_manager
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,268 @@
+//! Line based TCP protocol. Client sends CSV rows
+//! (`type,client,tx,amount[,operator,reason,timestamp,currency,to_currency,to_client]`, header is optional),
+//! server responds to each of them with `ok` or `error,<code>,<message>`.
+//! Lines longer than [`MAX_LINE_LENGTH`] are skipped and answered with `line_too_long` error.
+//! `output` command responds with CSV output of all accounts, terminated with an empty line.
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    format::{is_csv_header, parse_csv_row, write_output, OutputFormat, PARSE_ERROR_CODE},
+    server::SharedEngine,
+};
+
+/// Command requesting current output of all accounts
+pub const OUTPUT_COMMAND: &str = "output";
+/// Longest accepted line in bytes, without line terminator
+pub const MAX_LINE_LENGTH: usize = 4096;
+pub const LINE_TOO_LONG_CODE: &str = "line_too_long";
+// Failed accept is retried after a while, e.g. running out of file descriptors takes time to recover
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Errors which don't stop the server, they are passed to `on_error` hook of [`serve`]
+#[derive(Debug, Error)]
+pub enum ConnectionError {
+    #[error("Accepting connection failed: `{0}`")]
+    Accept(io::Error),
+    #[error("Connection `{0}` failed: `{1}`")]
+    Connection(SocketAddr, io::Error),
+}
+
+/// Accepts connections forever, each of them is handled in a separate task
+pub async fn serve(
+    listener: TcpListener,
+    engine: Arc<SharedEngine>,
+    on_error: impl Fn(ConnectionError) + Send + Sync + 'static,
+) -> io::Result<()> {
+    let on_error = Arc::new(on_error);
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                on_error(ConnectionError::Accept(error));
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let engine = Arc::clone(&engine);
+        let on_error = Arc::clone(&on_error);
+        tokio::spawn(async move {
+            if let Err(error) = handle_connection(stream, &engine).await {
+                on_error(ConnectionError::Connection(address, error));
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: TcpStream, engine: &SharedEngine) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        // Line terminator fits in the limit, so longer lines are cut
+        let read = (&mut reader)
+            .take(MAX_LINE_LENGTH as u64 + 1)
+            .read_line(&mut line)
+            .await?;
+        if read == 0 {
+            break;
+        }
+
+        let response = match read > MAX_LINE_LENGTH && !line.ends_with('\n') {
+            true => {
+                skip_line(&mut reader).await?;
+                error_response(
+                    LINE_TOO_LONG_CODE,
+                    &format!("Line longer than `{MAX_LINE_LENGTH}` bytes"),
+                )?
+            }
+            false => respond(line.trim(), engine)?,
+        };
+        writer.write_all(&response).await?;
+        // Responses are flushed once all rows received so far are handled
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+
+    writer.flush().await
+}
+
+/// Drops rest of current line without buffering it
+async fn skip_line(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<()> {
+    loop {
+        let buffer = reader.fill_buf().await?;
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        match buffer.iter().position(|&byte| byte == b'\n') {
+            Some(end) => {
+                reader.consume(end + 1);
+                return Ok(());
+            }
+            None => {
+                let length = buffer.len();
+                reader.consume(length);
+            }
+        }
+    }
+}
+
+fn respond(line: &str, engine: &SharedEngine) -> io::Result<Vec<u8>> {
+    if line.is_empty() || is_csv_header(line) {
+        return Ok(Vec::new());
+    }
+
+    if line.eq_ignore_ascii_case(OUTPUT_COMMAND) {
+        let mut response = Vec::new();
+        let output = engine.read(|account_manager| account_manager.gather_output());
+        write_output(&mut response, OutputFormat::Csv, &output).map_err(io::Error::other)?;
+        response.push(b'\n');
+        return Ok(response);
+    }
+
+    let result = parse_csv_row(line)
+        .map_err(|error| (PARSE_ERROR_CODE, error.to_string()))
+        .and_then(|record| {
+            engine
+                .process_record(record)
+                .map_err(|error| (error.code(), error.to_string()))
+        });
+
+    match result {
+        Ok(()) => Ok(b"ok\n".to_vec()),
+        Err((code, message)) => error_response(code, &message),
+    }
+}
+
+fn error_response(code: &str, message: &str) -> io::Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(["error", code, message])?;
+    writer.into_inner().map_err(|error| error.into_error())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{serve, MAX_LINE_LENGTH};
+    use crate::{account_manager::AccountManager, server::SharedEngine, RecordValidator};
+
+    async fn start_server() -> (Arc<SharedEngine>, std::net::SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let engine = Arc::new(SharedEngine::new(
+            AccountManager::new(),
+            RecordValidator::default(),
+        ));
+        tokio::spawn(serve(listener, Arc::clone(&engine), |_| {}));
+
+        (engine, address)
+    }
+
+    async fn read_lines(reader: &mut BufReader<TcpStream>, count: usize) -> Vec<String> {
+        let mut lines = Vec::new();
+        for _ in 0..count {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            lines.push(line.trim_end().to_string());
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn test_stream_records_and_output() {
+        let (_, address) = start_server().await;
+        let mut client = BufReader::new(TcpStream::connect(address).await.unwrap());
+
+        client
+            .get_mut()
+            .write_all(
+                b"type,client,tx,amount\n\
+                  deposit,1,1,10\n\
+                  withdrawal,1,2,15\n\
+                  bogus\n\
+                  dispute,1,1\n\
+                  output\n",
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                "ok",
+                "error,withdrawal_not_enough_money_available,\
+                 \"Withdrawal: not enough money available, available: `10`, requested: `15`\"",
+            ],
+            read_lines(&mut client, 2).await
+        );
+        assert!(read_lines(&mut client, 1).await[0].starts_with("error,parse_error,"));
+        assert_eq!(
+            vec![
+                "ok",
+                "client,available,held,total,locked",
+                "1,0,10,10,false",
+                "",
+            ],
+            read_lines(&mut client, 4).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connections_share_state() {
+        let (engine, address) = start_server().await;
+
+        let mut first = BufReader::new(TcpStream::connect(address).await.unwrap());
+        let mut second = BufReader::new(TcpStream::connect(address).await.unwrap());
+        first.get_mut().write_all(b"deposit,1,1,5\n").await.unwrap();
+        assert_eq!(vec!["ok"], read_lines(&mut first, 1).await);
+        second
+            .get_mut()
+            .write_all(b"deposit,2,1,5\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            vec!["error,transaction_already_exists,Transaction already exists: `1`"],
+            read_lines(&mut second, 1).await
+        );
+
+        assert_eq!(
+            1,
+            engine.read(|account_manager| account_manager.gather_output().len())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_line_too_long() {
+        let (_, address) = start_server().await;
+        let mut client = BufReader::new(TcpStream::connect(address).await.unwrap());
+
+        let mut request = format!("deposit,1,1,1{}\n", "0".repeat(MAX_LINE_LENGTH));
+        request.push_str("deposit,1,2,10\n");
+        client
+            .get_mut()
+            .write_all(request.as_bytes())
+            .await
+            .unwrap();
+
+        // Rest of the long line is skipped, connection keeps working
+        assert_eq!(
+            vec!["error,line_too_long,Line longer than `4096` bytes", "ok"],
+            read_lines(&mut client, 2).await
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    account_manager::account::Account,
+    model::{ClientId, TransactionId},
+};
+
+/// Version of snapshot format, bumped whenever state older readers would silently drop is added:
+/// - `1` - balances and transactions
+/// - `2` - per-currency balances, along with state transitions, administrative notes and timestamps
+///   of transactions
+/// - `3` - charged fees and counterparties of transfers
+/// - `4` - funds moved by disputes
+///
+/// Older snapshots are still read, state they don't have is left empty.
+pub const SNAPSHOT_VERSION: u32 = 4;
+
+/// Complete state of `AccountManager`, accounts are kept in order of first appearance
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub version: u32,
+    /// Sequence of last journal entry included in this snapshot
+    #[serde(default)]
+    pub journal_sequence: u64,
+    pub accounts: Vec<Account>,
+}
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("IO error: `{0}`")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error: `{0}`")]
+    Json(#[from] serde_json::Error),
+    #[error("Unsupported snapshot version: `{0}`, expected at most: `{SNAPSHOT_VERSION}`")]
+    UnsupportedVersion(u32),
+
+    #[error("Duplicate client in snapshot: `{0}`")]
+    DuplicateClient(ClientId),
+    #[error("Duplicate transaction in snapshot: `{0}`")]
+    DuplicateTransaction(TransactionId),
+}
+
+impl Snapshot {
+    pub fn new(accounts: Vec<Account>) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            journal_sequence: 0,
+            accounts,
+        }
+    }
+
+    pub fn read(reader: impl Read) -> Result<Self, SnapshotError> {
+        let snapshot: Self = serde_json::from_reader(reader)?;
+        if !(1..=SNAPSHOT_VERSION).contains(&snapshot.version) {
+            return Err(SnapshotError::UnsupportedVersion(snapshot.version));
+        }
+
+        Ok(snapshot)
+    }
+
+    pub fn write(&self, writer: impl Write) -> Result<(), SnapshotError> {
+        let mut writer = BufWriter::new(writer);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    pub fn read_from_path(path: &Path) -> Result<Self, SnapshotError> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Writes snapshot to temporary file first and renames it, so an existing snapshot
+    /// is never left half-written
+    pub fn write_to_path(&self, path: &Path) -> Result<(), SnapshotError> {
+        let mut temporary_path = path.as_os_str().to_owned();
+        temporary_path.push(".tmp");
+
+        let file = File::create(&temporary_path)?;
+        self.write(&file)?;
+        file.sync_all()?;
+        std::fs::rename(&temporary_path, path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{Snapshot, SnapshotError};
+    use crate::{
+        account_manager::{account::ProcessingError, AccountManager, OutputOrder},
+        model::{InputRecord, InputRecordType},
+    };
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut account_manager = AccountManager::new();
+        for input in [
+            InputRecord::new(InputRecordType::Deposit, 2, 0, Some(dec!(10.0))),
+            InputRecord::new(InputRecordType::Deposit, 1, 1, Some(dec!(20.0))),
+            InputRecord::new(InputRecordType::Dispute, 1, 1, None),
+            InputRecord::new(InputRecordType::Deposit, 3, 2, Some(dec!(5.0))),
+            InputRecord::new(InputRecordType::Dispute, 3, 2, None),
+            InputRecord::new(InputRecordType::Chargeback, 3, 2, None),
+        ] {
+            account_manager.process_record(&input).unwrap();
+        }
+
+        let mut serialized = Vec::new();
+        account_manager.snapshot().write(&mut serialized).unwrap();
+        let mut restored =
+            AccountManager::from_snapshot(Snapshot::read(serialized.as_slice()).unwrap()).unwrap();
+
+        assert_eq!(account_manager.gather_output(), restored.gather_output());
+        assert_eq!(
+            account_manager.gather_output_ordered(OutputOrder::Insertion),
+            restored.gather_output_ordered(OutputOrder::Insertion)
+        );
+
+        // Disputes and global transaction index carry over
+        restored
+            .process_record(&InputRecord::new(InputRecordType::Resolve, 1, 1, None))
+            .unwrap();
+        assert!(matches!(
+            restored.process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                2,
+                1,
+                Some(dec!(1.0))
+            )),
+            Err(ProcessingError::TransactionAlreadyExists(1))
+        ));
+        assert!(matches!(
+            restored.process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                3,
+                3,
+                Some(dec!(1.0))
+            )),
+            Err(ProcessingError::AccountIsLocked)
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_unsupported_version() {
+        let serialized = r#"{"version": 999, "accounts": []}"#;
+        assert!(matches!(
+            Snapshot::read(serialized.as_bytes()),
+            Err(SnapshotError::UnsupportedVersion(999))
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_older_version() {
+        let serialized = r#"{"version": 1, "accounts": [{
+            "client_id": 1,
+            "transactions": {"1": {"state": "valid", "amount": "2.5", "type": "deposit"}},
+            "available": "2.5",
+            "held": "0",
+            "locked": false
+        }]}"#;
+        let restored =
+            AccountManager::from_snapshot(Snapshot::read(serialized.as_bytes()).unwrap()).unwrap();
+        assert_eq!(dec!(2.5), restored.gather_output()[0].available);
+        assert_eq!(None, restored.transactions(1).unwrap()[0].currency);
+    }
+
+    #[test]
+    fn test_snapshot_duplicate_transaction() {
+        let mut first = AccountManager::new();
+        first
+            .process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                1,
+                0,
+                Some(dec!(1.0)),
+            ))
+            .unwrap();
+        let mut second = AccountManager::new();
+        second
+            .process_record(&InputRecord::new(
+                InputRecordType::Deposit,
+                2,
+                0,
+                Some(dec!(1.0)),
+            ))
+            .unwrap();
+
+        let mut accounts = first.snapshot().accounts;
+        accounts.extend(second.snapshot().accounts);
+        assert!(matches!(
+            AccountManager::from_snapshot(Snapshot::new(accounts)),
+            Err(SnapshotError::DuplicateTransaction(0))
+        ));
+    }
+}
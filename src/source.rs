@@ -0,0 +1,137 @@
+use std::{
+    fmt::{Display, Formatter},
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+/// Path meaning standard input
+pub const STDIN_PATH: &str = "-";
+
+/// Source of transactions, either a file or standard input
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InputSource {
+    Stdin,
+    File(PathBuf),
+}
+
+#[derive(Debug, Error)]
+pub enum SourceError {
+    #[error("Invalid glob pattern: `{0}`")]
+    GlobPattern(#[from] glob::PatternError),
+    #[error("Glob error: `{0}`")]
+    Glob(#[from] glob::GlobError),
+    #[error("No files matching: `{0}`")]
+    NoMatches(String),
+    #[error("Could not open `{0}`: `{1}`")]
+    Open(InputSource, std::io::Error),
+}
+
+impl InputSource {
+    /// Turns paths into sources, keeping their order. `-` means stdin, paths which do not exist
+    /// and contain glob characters are expanded into matching files, sorted alphabetically.
+    pub fn resolve(paths: &[PathBuf]) -> Result<Vec<Self>, SourceError> {
+        let mut sources = Vec::new();
+        for path in paths {
+            if path.as_os_str() == STDIN_PATH {
+                sources.push(Self::Stdin);
+            } else if is_glob(path) {
+                let pattern = path.to_string_lossy();
+                let matches = glob::glob(&pattern)?
+                    .map(|entry| entry.map(Self::File))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if matches.is_empty() {
+                    return Err(SourceError::NoMatches(pattern.into_owned()));
+                }
+                sources.extend(matches);
+            } else {
+                sources.push(Self::File(path.clone()));
+            }
+        }
+
+        Ok(sources)
+    }
+
+    pub fn open(&self) -> Result<Box<dyn Read>, SourceError> {
+        match self {
+            Self::Stdin => Ok(Box::new(std::io::stdin().lock())),
+            Self::File(path) => File::open(path)
+                .map(|file| Box::new(file) as Box<dyn Read>)
+                .map_err(|error| SourceError::Open(self.clone(), error)),
+        }
+    }
+}
+
+impl Display for InputSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stdin => write!(f, "<stdin>"),
+            Self::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+fn is_glob(path: &Path) -> bool {
+    !path.exists() && path.to_string_lossy().contains(['*', '?', '['])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{InputSource, SourceError};
+
+    #[test]
+    fn test_resolve_keeps_order() {
+        let sources = InputSource::resolve(&[
+            PathBuf::from("b.csv"),
+            PathBuf::from("-"),
+            PathBuf::from("a.csv"),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            vec![
+                InputSource::File(PathBuf::from("b.csv")),
+                InputSource::Stdin,
+                InputSource::File(PathBuf::from("a.csv")),
+            ],
+            sources
+        );
+    }
+
+    #[test]
+    fn test_resolve_glob() {
+        let sources = InputSource::resolve(&[PathBuf::from(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/ex*.csv"
+        ))])
+        .unwrap();
+
+        assert_eq!(
+            vec![InputSource::File(PathBuf::from(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/example.csv"
+            )))],
+            sources
+        );
+    }
+
+    #[test]
+    fn test_resolve_glob_no_matches() {
+        assert!(matches!(
+            InputSource::resolve(&[PathBuf::from("does-not-exist-*.csv")]),
+            Err(SourceError::NoMatches(_))
+        ));
+    }
+
+    #[test]
+    fn test_open_missing_file() {
+        assert!(matches!(
+            InputSource::File(PathBuf::from("does-not-exist.csv")).open(),
+            Err(SourceError::Open(_, _))
+        ));
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    collections::HashSet,
+    io::{self, BufRead},
+};
+
+use clap::ValueEnum;
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::{
+    account_manager::account::ProcessingError,
+    model::{InputRecord, InputRecordType, AMOUNT_PRECISION},
+};
+
+/// What to do with amounts having more than [`AMOUNT_PRECISION`] decimal places
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, ValueEnum)]
+pub enum PrecisionPolicy {
+    /// Reject the record
+    #[default]
+    Reject,
+    /// Round to nearest, ties to even (banker's rounding)
+    Round,
+    /// Drop excess decimal places
+    Truncate,
+}
+
+/// Sanity checks of records, done before they reach `AccountManager`.
+/// Operators of administrative records are checked by `AccountManager` itself.
+#[derive(Clone, Debug, Default)]
+pub struct RecordValidator {
+    precision_policy: PrecisionPolicy,
+}
+
+impl RecordValidator {
+    pub fn new(precision_policy: PrecisionPolicy) -> Self {
+        Self { precision_policy }
+    }
+
+    /// Validates record, amount might get rounded depending on [`PrecisionPolicy`]
+    pub fn validate(&self, mut record: InputRecord) -> Result<InputRecord, ProcessingError> {
+        match record.r#type {
+            InputRecordType::Deposit
+            | InputRecordType::Withdrawal
+            | InputRecordType::Convert
+            | InputRecordType::Transfer => {
+                if let Some(amount) = record.amount {
+                    record.amount = Some(self.validate_amount(amount)?);
+                }
+            }
+            InputRecordType::Dispute | InputRecordType::Resolve | InputRecordType::Chargeback => {
+                if let Some(amount) = record.amount {
+                    return Err(ProcessingError::AmountUnexpected(amount));
+                }
+            }
+            InputRecordType::Adjust => {
+                if let Some(amount) = record.amount {
+                    if amount.is_zero() {
+                        return Err(ProcessingError::AmountZero);
+                    }
+                    // Sign tells credit from debit, precision is checked on absolute value
+                    let validated = self.validate_amount(amount.abs())?;
+                    record.amount = Some(match amount.is_sign_negative() {
+                        true => -validated,
+                        false => validated,
+                    });
+                }
+            }
+            InputRecordType::Freeze | InputRecordType::Unlock => {
+                if let Some(amount) = record.amount {
+                    return Err(ProcessingError::AmountUnexpected(amount));
+                }
+            }
+        }
+
+        Ok(record)
+    }
+
+    fn validate_amount(&self, amount: Decimal) -> Result<Decimal, ProcessingError> {
+        if amount <= Decimal::ZERO {
+            return Err(ProcessingError::AmountNotPositive(amount));
+        }
+
+        let amount = amount.normalize();
+        if amount.scale() <= AMOUNT_PRECISION {
+            return Ok(amount);
+        }
+
+        let rounded = match self.precision_policy {
+            PrecisionPolicy::Reject => return Err(ProcessingError::AmountTooPrecise(amount)),
+            PrecisionPolicy::Round => amount
+                .round_dp_with_strategy(AMOUNT_PRECISION, RoundingStrategy::MidpointNearestEven),
+            PrecisionPolicy::Truncate => {
+                amount.round_dp_with_strategy(AMOUNT_PRECISION, RoundingStrategy::ToZero)
+            }
+        };
+        if rounded <= Decimal::ZERO {
+            return Err(ProcessingError::AmountNotPositive(rounded));
+        }
+
+        Ok(rounded)
+    }
+}
+
+/// Reads operator ids, one per line, empty lines and lines starting with `#` are skipped
+pub fn read_operators(reader: impl BufRead) -> io::Result<HashSet<String>> {
+    let mut operators = HashSet::new();
+    for line in reader.lines() {
+        let line = line?;
+        let operator = line.trim();
+        if !operator.is_empty() && !operator.starts_with('#') {
+            operators.insert(operator.to_string());
+        }
+    }
+
+    Ok(operators)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::{read_operators, PrecisionPolicy, RecordValidator};
+    use crate::{
+        account_manager::account::ProcessingError,
+        model::{InputRecord, InputRecordType},
+    };
+
+    #[test]
+    fn test_validate_success() {
+        let validator = RecordValidator::default();
+        for r#type in [
+            InputRecordType::Deposit,
+            InputRecordType::Withdrawal,
+            InputRecordType::Convert,
+            InputRecordType::Transfer,
+        ] {
+            let validated = validator
+                .validate(InputRecord::new(r#type, 0, 0, Some(dec!(1.2345))))
+                .unwrap();
+            assert_eq!(Some(dec!(1.2345)), validated.amount);
+        }
+        for r#type in [
+            InputRecordType::Dispute,
+            InputRecordType::Resolve,
+            InputRecordType::Chargeback,
+        ] {
+            validator
+                .validate(InputRecord::new(r#type, 0, 0, None))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_validate_missing_amount_is_passed_through() {
+        let validated = RecordValidator::default()
+            .validate(InputRecord::new(InputRecordType::Deposit, 0, 0, None))
+            .unwrap();
+        assert_eq!(None, validated.amount);
+    }
+
+    #[test]
+    fn test_validate_fail_not_positive() {
+        let validator = RecordValidator::default();
+        for amount in [dec!(0.0), dec!(-100.0)] {
+            for r#type in [
+                InputRecordType::Deposit,
+                InputRecordType::Withdrawal,
+                InputRecordType::Convert,
+                InputRecordType::Transfer,
+            ] {
+                assert!(matches!(
+                    validator.validate(InputRecord::new(r#type, 0, 0, Some(amount))),
+                    Err(ProcessingError::AmountNotPositive(_))
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate_fail_unexpected_amount() {
+        let validator = RecordValidator::default();
+        for r#type in [
+            InputRecordType::Dispute,
+            InputRecordType::Resolve,
+            InputRecordType::Chargeback,
+        ] {
+            assert!(matches!(
+                validator.validate(InputRecord::new(r#type, 0, 0, Some(dec!(1.0)))),
+                Err(ProcessingError::AmountUnexpected(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_validate_precision_reject() {
+        let validator = RecordValidator::new(PrecisionPolicy::Reject);
+        assert!(matches!(
+            validator.validate(InputRecord::new(
+                InputRecordType::Deposit,
+                0,
+                0,
+                Some(dec!(1.00005))
+            )),
+            Err(ProcessingError::AmountTooPrecise(_))
+        ));
+
+        // Trailing zeros do not count as precision
+        let validated = validator
+            .validate(InputRecord::new(
+                InputRecordType::Deposit,
+                0,
+                0,
+                Some(dec!(1.000000)),
+            ))
+            .unwrap();
+        assert_eq!(Some(dec!(1)), validated.amount);
+    }
+
+    #[test]
+    fn test_validate_precision_round() {
+        let validator = RecordValidator::new(PrecisionPolicy::Round);
+        let validated = validator
+            .validate(InputRecord::new(
+                InputRecordType::Deposit,
+                0,
+                0,
+                Some(dec!(1.00005)),
+            ))
+            .unwrap();
+        assert_eq!(Some(dec!(1.0000)), validated.amount);
+
+        let validated = validator
+            .validate(InputRecord::new(
+                InputRecordType::Deposit,
+                0,
+                0,
+                Some(dec!(1.00015)),
+            ))
+            .unwrap();
+        assert_eq!(Some(dec!(1.0002)), validated.amount);
+
+        assert!(matches!(
+            validator.validate(InputRecord::new(
+                InputRecordType::Deposit,
+                0,
+                0,
+                Some(dec!(0.00004))
+            )),
+            Err(ProcessingError::AmountNotPositive(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_precision_truncate() {
+        let validator = RecordValidator::new(PrecisionPolicy::Truncate);
+        let validated = validator
+            .validate(InputRecord::new(
+                InputRecordType::Withdrawal,
+                0,
+                0,
+                Some(dec!(1.99999)),
+            ))
+            .unwrap();
+        assert_eq!(Some(dec!(1.9999)), validated.amount);
+    }
+
+    #[test]
+    fn test_validate_admin() {
+        let validator = RecordValidator::default();
+
+        for r#type in [InputRecordType::Freeze, InputRecordType::Unlock] {
+            validator
+                .validate(InputRecord::admin(r#type, 0, 0, None, "alice"))
+                .unwrap();
+            assert!(matches!(
+                validator.validate(InputRecord::admin(r#type, 0, 0, Some(dec!(1.0)), "alice")),
+                Err(ProcessingError::AmountUnexpected(_))
+            ));
+        }
+
+        let adjust = |amount| {
+            validator.validate(InputRecord::admin(
+                InputRecordType::Adjust,
+                0,
+                0,
+                Some(amount),
+                "alice",
+            ))
+        };
+        assert_eq!(Some(dec!(-1.5)), adjust(dec!(-1.50)).unwrap().amount);
+        assert_eq!(Some(dec!(2)), adjust(dec!(2)).unwrap().amount);
+        assert!(matches!(adjust(dec!(0)), Err(ProcessingError::AmountZero)));
+        assert!(matches!(
+            adjust(dec!(-0.00001)),
+            Err(ProcessingError::AmountTooPrecise(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_operators() {
+        let operators = read_operators("alice\n\n# risk team\n bob \n".as_bytes()).unwrap();
+        assert_eq!(2, operators.len());
+        assert!(operators.contains("alice"));
+        assert!(operators.contains("bob"));
+    }
+}
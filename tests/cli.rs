@@ -0,0 +1,156 @@
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+const INPUT: &str = "type,client,tx,amount\n\
+                     deposit,1,1,10\n\
+                     bogus,1,2,5\n\
+                     withdrawal,1,3,50\n\
+                     deposit,2,4,1\n";
+
+/// Runs the binary in a fresh directory containing `input.csv`, which is removed afterwards
+fn run(name: &str, input: &str, args: &[&str]) -> (Output, Option<String>) {
+    let directory: PathBuf = std::env::temp_dir().join(format!(
+        "transaction-processor-{name}-{}",
+        std::process::id()
+    ));
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(directory.join("input.csv"), input).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_transaction-processor"))
+        .current_dir(&directory)
+        .args(args)
+        .arg("input.csv")
+        .output()
+        .unwrap();
+    let rejected = fs::read_to_string(directory.join("rejected.csv")).ok();
+    fs::remove_dir_all(&directory).unwrap();
+
+    (output, rejected)
+}
+
+/// `(line, raw, code)` of rejected records
+fn rejected_records(rejected: &str) -> Vec<(u64, String, String)> {
+    csv::Reader::from_reader(rejected.as_bytes())
+        .records()
+        .map(|record| {
+            let record = record.unwrap();
+            assert_eq!("input.csv", &record[0]);
+            (
+                record[1].parse().unwrap(),
+                record[2].to_string(),
+                record[3].to_string(),
+            )
+        })
+        .collect()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+#[test]
+fn test_success() {
+    for workers in ["1", "2"] {
+        let (output, _) = run(
+            "success",
+            "type,client,tx,amount\ndeposit,1,1,10\n",
+            &["--workers", workers],
+        );
+        assert_eq!(Some(0), output.status.code());
+        assert_eq!(
+            "client,available,held,total,locked\n1,10,0,10,false\n",
+            stdout(&output)
+        );
+        // Summary is printed only if something was rejected
+        assert_eq!("", stderr(&output));
+    }
+}
+
+#[test]
+fn test_parse_error_abort() {
+    let (output, rejected) = run("parse-abort", INPUT, &["--rejected-out", "rejected.csv"]);
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!("", stdout(&output));
+    assert!(stderr(&output).starts_with("Error: Parse error at `input.csv:3`"));
+    assert!(stderr(&output)
+        .ends_with("Records read: 2, rejected: 1 (parse errors: 1, processing errors: 0)\n"));
+    // Parse errors aren't reported unless asked for
+    assert_eq!(Some(String::new()), rejected);
+}
+
+#[test]
+fn test_parse_error_skip_and_report() {
+    for workers in ["1", "2"] {
+        for (policy, parse_errors) in [("skip", 0), ("report", 1)] {
+            let (output, rejected) = run(
+                &format!("parse-{policy}-{workers}"),
+                INPUT,
+                &[
+                    "--on-parse-error",
+                    policy,
+                    "--rejected-out",
+                    "rejected.csv",
+                    "--workers",
+                    workers,
+                ],
+            );
+            // Rejected records make the run exit with code 2, output is still written
+            assert_eq!(Some(2), output.status.code());
+            assert_eq!(
+                "client,available,held,total,locked\n1,10,0,10,false\n2,1,0,1,false\n",
+                stdout(&output)
+            );
+            assert_eq!(
+                "Records read: 4, rejected: 2 (parse errors: 1, processing errors: 1)\n",
+                stderr(&output)
+            );
+
+            let mut expected = vec![(3, "bogus,1,2,5".to_string(), "parse_error".to_string())];
+            expected.truncate(parse_errors);
+            expected.push((
+                4,
+                "withdrawal,1,3,50".to_string(),
+                "withdrawal_not_enough_money_available".to_string(),
+            ));
+            assert_eq!(expected, rejected_records(&rejected.unwrap()));
+        }
+    }
+}
+
+#[test]
+fn test_processing_error_abort() {
+    let (output, rejected) = run(
+        "processing-abort",
+        INPUT,
+        &[
+            "--on-parse-error",
+            "skip",
+            "--on-processing-error",
+            "abort",
+            "--rejected-out",
+            "rejected.csv",
+        ],
+    );
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!("", stdout(&output));
+    assert!(stderr(&output).starts_with("Error: Processing error at `input.csv:4`"));
+    // Records after the failing one are not read
+    assert!(stderr(&output)
+        .ends_with("Records read: 3, rejected: 2 (parse errors: 1, processing errors: 1)\n"));
+    // Record which aborted the run is reported
+    assert_eq!(
+        vec![(
+            4,
+            "withdrawal,1,3,50".to_string(),
+            "withdrawal_not_enough_money_available".to_string()
+        )],
+        rejected_records(&rejected.unwrap())
+    );
+}
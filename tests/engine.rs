@@ -0,0 +1,32 @@
+use csv::Trim;
+use rust_decimal_macros::dec;
+use transaction_processor::{AccountManager, InputRecord, OutputRecord};
+
+#[test]
+fn test_example_file() {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(Trim::All)
+        .from_path(concat!(env!("CARGO_MANIFEST_DIR"), "/example.csv"))
+        .unwrap();
+
+    let mut account_manager = AccountManager::new();
+    for result in reader.deserialize() {
+        let record: InputRecord = result.unwrap();
+        let _ = account_manager.process_record(&record);
+    }
+
+    assert_eq!(
+        vec![OutputRecord {
+            client_id: 123,
+            currency: None,
+            available: dec!(50.0),
+            held: dec!(0.0),
+            total: dec!(50.0),
+            locked: true,
+            credit_limit: None,
+            available_to_spend: None,
+        }],
+        account_manager.gather_output()
+    );
+}